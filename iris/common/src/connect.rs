@@ -1,61 +1,100 @@
+//! # Connection manager
+//! An event-driven networking core. A single reactor thread waits on every socket at once
+//! (using epoll via mio), frames incoming bytes into messages, and flushes each client's
+//! outbound queue as its socket becomes writable.
+//!
+//! Each session's `ConnectionEvents` are called on the reactor thread as well, so a session which
+//! blocks stalls every connection. Work which may take a while belongs on another thread,
+//! which can queue its output through a `ConnectionSender`. A session which cannot go on until
+//! that work is done waits for it: its messages are left unread until the work wakes it.
+
+use log::{info, warn};
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token, Waker};
 use std::{
+    collections::{HashMap, VecDeque},
     error::Error,
     fmt::{Debug, Display},
-    io::{Read, Write},
-    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    io::{ErrorKind, Read, Write},
+    net::{IpAddr, Shutdown, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
-pub struct ConnectionManager {
-    listener: TcpListener,
-}
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+const FIRST_CONNECTION: usize = 2;
 
-impl ConnectionManager {
-    pub fn launch(address: impl Into<IpAddr>, port: u16) -> Self {
-        let address = address.into();
-        let listener = TcpListener::bind((address, port))
-            .unwrap_or_else(|_| panic!("failed to bind to {address}:{port}"));
+/// A serialized message. The same buffer is shared by every queue it is delivered to,
+/// so a message sent to many clients is only serialized and allocated once.
+pub type SharedMessage = Arc<[u8]>;
 
-        Self { listener }
-    }
+/// The handler for a single connection's events, driven by the `ConnectionManager`.
+pub trait ConnectionEvents {
+    /// Called with each message as it arrives, or with the error which ended or interrupted it.
+    /// This runs on the reactor thread, and must not block.
+    fn on_message(&mut self, message: Result<String, ConnectionError>);
 
-    pub fn accept_new_connection(&mut self) -> (ConnectionRead, ConnectionWrite) {
-        loop {
-            match self.listener.accept() {
-                Ok((socket, addr)) => {
-                    let (socket_read, socket_write) = (
-                        match socket.try_clone() {
-                            Ok(socket) => socket,
-                            Err(err) => {
-                                eprintln!("[WARN] Failed to clone socket: {err}");
-                                continue;
-                            }
-                        },
-                        socket,
-                    );
-
-                    return (
-                        ConnectionRead::from_socket(socket_read, addr),
-                        ConnectionWrite::from_socket(socket_write, addr),
-                    );
-                }
-                Err(err) => {
-                    eprintln!("[WARN] failed to connect to client: {err}");
-                }
-            }
-        }
+    /// Once this returns true, no more messages are delivered and the connection is closed.
+    fn has_quit(&self) -> bool;
+
+    /// While this returns true, no more messages are delivered, and they wait in order to be delivered
+    /// once the session has been woken with `ConnectionSender::wake` and no longer waits.
+    fn is_waiting(&self) -> bool {
+        false
     }
+
+    /// Called on the reactor thread after `ConnectionSender::wake`, before any more messages are delivered.
+    fn on_wake(&mut self) {}
+}
+
+pub struct ConnectionManager {
+    poll: Poll,
+    listener: TcpListener,
+    notifier: Arc<Notifier>,
+    send_queue_limit: usize,
+    quit_flush_timeout: Duration,
+}
+
+/// Lets threads other than the reactor know that a connection has output waiting,
+/// or that its session should be woken.
+struct Notifier {
+    waker: Waker,
+    pending: Mutex<Vec<Token>>,
+    woken: Mutex<Vec<Token>>,
 }
 
-pub struct ConnectionRead {
+struct Connection<S> {
     socket: TcpStream,
-    socket_addr: SocketAddr,
     buffer: Box<[u8; 512]>,
     buflen: usize,
+    queue: Arc<SendQueue>,
+    session: S,
+    /// When the connection is dropped if its output still has not been flushed, once its session has quit
+    flush_deadline: Option<Instant>,
 }
 
-pub struct ConnectionWrite {
-    socket: TcpStream,
+/// A handle for queueing messages to a client without blocking on its socket.
+/// The queue is flushed by the reactor whenever the client's socket can take more data.
+#[derive(Clone)]
+pub struct ConnectionSender {
+    queue: Arc<SendQueue>,
+}
+
+struct SendQueue {
+    token: Token,
     socket_addr: SocketAddr,
+    limit: usize,
+    notifier: Arc<Notifier>,
+    state: Mutex<SendQueueState>,
+}
+
+struct SendQueueState {
+    messages: VecDeque<SharedMessage>,
+    queued_bytes: usize,
+    front_offset: usize,
+    closed: bool,
+    close_reason: Option<String>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -74,16 +113,226 @@ impl Display for ConnectionError {
 
 impl Error for ConnectionError {}
 
-impl ConnectionRead {
-    fn from_socket(socket: TcpStream, socket_addr: SocketAddr) -> Self {
+impl ConnectionManager {
+    /// Binds to the address, with each client's outbound queue holding at most `send_queue_limit` bytes.
+    /// A client which has quit is given `quit_flush_timeout` to take the rest of its output before it is dropped.
+    pub fn launch(
+        address: impl Into<IpAddr>,
+        port: u16,
+        send_queue_limit: usize,
+        quit_flush_timeout: Duration,
+    ) -> Self {
+        let address = address.into();
+        let mut listener = TcpListener::bind(SocketAddr::new(address, port))
+            .unwrap_or_else(|_| panic!("failed to bind to {address}:{port}"));
+
+        let poll = Poll::new().expect("failed to create poll");
+        poll.registry()
+            .register(&mut listener, LISTENER, Interest::READABLE)
+            .expect("failed to register listener");
+        let waker = Waker::new(poll.registry(), WAKER).expect("failed to create waker");
+
         Self {
-            socket,
-            socket_addr,
-            buffer: Box::from([0; 512]),
-            buflen: 0,
+            poll,
+            listener,
+            notifier: Arc::new(Notifier {
+                waker,
+                pending: Mutex::new(vec![]),
+                woken: Mutex::new(vec![]),
+            }),
+            send_queue_limit,
+            quit_flush_timeout,
         }
     }
 
+    /// Runs the reactor forever, creating a session with `new_session` for every client which connects.
+    pub fn serve<S, F>(mut self, mut new_session: F)
+    where
+        S: ConnectionEvents,
+        F: FnMut(ConnectionSender) -> S,
+    {
+        let mut connections: HashMap<Token, Connection<S>> = HashMap::new();
+        let mut events = Events::with_capacity(1024);
+        let mut next_token = FIRST_CONNECTION;
+        // The connections waiting to be flushed after their sessions quit, soonest deadline first
+        let mut quitting: VecDeque<(Instant, Token)> = VecDeque::new();
+
+        loop {
+            let timeout = quitting
+                .front()
+                .map(|(deadline, _)| deadline.saturating_duration_since(Instant::now()));
+            if let Err(err) = self.poll.poll(&mut events, timeout) {
+                if err.kind() != ErrorKind::Interrupted {
+                    warn!("Failed to poll for events: {err}");
+                }
+                continue;
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept_new_connections(
+                        &mut connections,
+                        &mut next_token,
+                        &mut new_session,
+                    ),
+                    // Pending output is flushed below, after every batch of events
+                    WAKER => {}
+                    token => {
+                        if let Some(connection) = connections.get_mut(&token) {
+                            if event.is_readable() || event.is_read_closed() {
+                                connection.receive();
+                            }
+                            connection.flush();
+                        }
+
+                        self.close_if_finished(&mut connections, &mut quitting, token);
+                    }
+                }
+            }
+
+            let woken = std::mem::take(&mut *self.notifier.woken.lock().unwrap());
+            for token in woken {
+                if let Some(connection) = connections.get_mut(&token) {
+                    connection.session.on_wake();
+                    // Anything which arrived while the session waited is delivered now
+                    connection.receive();
+                    connection.flush();
+                }
+
+                self.close_if_finished(&mut connections, &mut quitting, token);
+            }
+
+            let pending = std::mem::take(&mut *self.notifier.pending.lock().unwrap());
+            for token in pending {
+                if let Some(connection) = connections.get_mut(&token) {
+                    connection.flush();
+                }
+
+                self.close_if_finished(&mut connections, &mut quitting, token);
+            }
+
+            while let Some(&(deadline, token)) = quitting.front() {
+                if deadline > Instant::now() {
+                    break;
+                }
+
+                quitting.pop_front();
+                self.close_if_finished(&mut connections, &mut quitting, token);
+            }
+        }
+    }
+
+    fn accept_new_connections<S, F>(
+        &self,
+        connections: &mut HashMap<Token, Connection<S>>,
+        next_token: &mut usize,
+        new_session: &mut F,
+    ) where
+        S: ConnectionEvents,
+        F: FnMut(ConnectionSender) -> S,
+    {
+        loop {
+            let (mut socket, socket_addr) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    warn!("Failed to connect to client: {err}");
+                    return;
+                }
+            };
+
+            let token = Token(*next_token);
+            *next_token += 1;
+
+            if let Err(err) = self.poll.registry().register(
+                &mut socket,
+                token,
+                Interest::READABLE | Interest::WRITABLE,
+            ) {
+                warn!("Failed to register client {socket_addr}: {err}");
+                continue;
+            }
+
+            info!("New connection from {socket_addr}");
+            let queue = Arc::new(SendQueue {
+                token,
+                socket_addr,
+                limit: self.send_queue_limit,
+                notifier: self.notifier.clone(),
+                state: Mutex::new(SendQueueState {
+                    messages: VecDeque::new(),
+                    queued_bytes: 0,
+                    front_offset: 0,
+                    closed: false,
+                    close_reason: None,
+                }),
+            });
+            let session = new_session(ConnectionSender {
+                queue: queue.clone(),
+            });
+
+            connections.insert(
+                token,
+                Connection {
+                    socket,
+                    buffer: Box::from([0; 512]),
+                    buflen: 0,
+                    queue,
+                    session,
+                    flush_deadline: None,
+                },
+            );
+        }
+    }
+
+    /// Drops the connection once its session has quit and its remaining output has been flushed,
+    /// or once its queue has been closed out from under it.
+    /// A client which stops reading after it quits is dropped anyway once `quit_flush_timeout` is up,
+    /// with whatever output it has not taken.
+    fn close_if_finished<S: ConnectionEvents>(
+        &self,
+        connections: &mut HashMap<Token, Connection<S>>,
+        quitting: &mut VecDeque<(Instant, Token)>,
+        token: Token,
+    ) {
+        let finished = match connections.get_mut(&token) {
+            Some(connection) => {
+                let state = connection.queue.state.lock().unwrap();
+                if state.closed || !connection.session.has_quit() {
+                    state.closed
+                } else if state.messages.is_empty() {
+                    true
+                } else {
+                    let deadline = *connection.flush_deadline.get_or_insert_with(|| {
+                        let deadline = Instant::now() + self.quit_flush_timeout;
+                        quitting.push_back((deadline, token));
+                        deadline
+                    });
+                    let overdue = Instant::now() >= deadline;
+                    if overdue {
+                        warn!(
+                            "Dropping {}, which quit without taking the rest of its output",
+                            connection.queue.socket_addr
+                        );
+                    }
+
+                    overdue
+                }
+            }
+            None => false,
+        };
+
+        if finished {
+            if let Some(mut connection) = connections.remove(&token) {
+                connection.queue.state.lock().unwrap().closed = true;
+                let _ = self.poll.registry().deregister(&mut connection.socket);
+                let _ = connection.socket.shutdown(Shutdown::Both);
+            }
+        }
+    }
+}
+
+impl<S: ConnectionEvents> Connection<S> {
     fn buffer_crlf(&self) -> Option<usize> {
         self.buffer[..self.buflen]
             .windows(2)
@@ -92,69 +341,176 @@ impl ConnectionRead {
             .map(|(index, _)| index)
     }
 
-    pub fn read_message(&mut self) -> Result<String, ConnectionError> {
-        use std::io::ErrorKind;
+    /// Reads everything available from the socket, delivering each complete message to the session.
+    /// A waiting session's messages are left in the buffer and the socket, for once it is woken.
+    fn receive(&mut self) {
+        loop {
+            self.deliver_messages();
+            if self.session.has_quit() || self.session.is_waiting() {
+                return;
+            }
 
-        if self.buffer_crlf().is_none() {
-            let n_bytes = loop {
-                break match self.socket.read(&mut self.buffer[self.buflen..]) {
-                    Ok(0) => return Err(ConnectionError::ConnectionClosed),
-                    Ok(n_bytes) => n_bytes,
-                    Err(err) => {
-                        match err.kind() {
-                            // Retry `read` if interrupted...
-                            ErrorKind::Interrupted => continue,
-                            _ => return Err(ConnectionError::ConnectionLost),
-                        }
+            match self.socket.read(&mut self.buffer[self.buflen..]) {
+                Ok(0) => {
+                    self.session.on_message(Err(self.queue.close_error()));
+                    return;
+                }
+                Ok(n_bytes) => self.buflen += n_bytes,
+                Err(err) => match err.kind() {
+                    ErrorKind::WouldBlock => return,
+                    // Retry `read` if interrupted...
+                    ErrorKind::Interrupted => continue,
+                    _ => {
+                        self.session
+                            .on_message(Err(ConnectionError::ConnectionLost));
+                        return;
                     }
-                };
+                },
+            }
+        }
+    }
+
+    fn deliver_messages(&mut self) {
+        while !self.session.has_quit() && !self.session.is_waiting() {
+            let end = match self.buffer_crlf() {
+                Some(end) => end,
+                None if self.buflen == self.buffer.len() => {
+                    // Clear out their data...
+                    self.buflen = 0;
+                    self.session
+                        .on_message(Err(ConnectionError::MessageTooLong));
+                    return;
+                }
+                None => return,
             };
 
-            self.buflen += n_bytes;
-        }
+            let bytes = Vec::from(&self.buffer[0..end]);
 
-        let end = self.buffer_crlf().ok_or_else(|| {
-            // Clear out their data...
-            self.buflen = 0;
-            ConnectionError::MessageTooLong
-        })?;
+            // end + '\r' + '\n'
+            let after_crlf = end + 2;
 
-        let bytes = Vec::from(&self.buffer[0..end]);
+            self.buffer.copy_within(after_crlf..self.buflen, 0);
+            self.buflen -= after_crlf;
 
-        // end + '\r' + '\n'
-        let after_crlf = end + 2;
+            let message = String::from_utf8(bytes).map_err(|_| ConnectionError::MessageInvalidUtf8);
+            self.session.on_message(message);
+        }
+    }
 
-        self.buffer.copy_within(after_crlf..self.buflen, 0);
-        self.buflen -= after_crlf;
+    /// Writes as much queued output as the socket will take without blocking.
+    fn flush(&mut self) {
+        let mut state = self.queue.state.lock().unwrap();
+        if state.closed {
+            if !self.session.has_quit() {
+                drop(state);
+                self.session.on_message(Err(self.queue.close_error()));
+            }
+            return;
+        }
 
-        let message = String::from_utf8(bytes).map_err(|_| ConnectionError::MessageInvalidUtf8)?;
+        while let Some(message) = state.messages.front() {
+            let message_len = message.len();
+            match self.socket.write(&message[state.front_offset..]) {
+                Ok(n_bytes) => {
+                    state.front_offset += n_bytes;
+                    if state.front_offset == message_len {
+                        state.messages.pop_front();
+                        state.queued_bytes -= message_len;
+                        state.front_offset = 0;
+                    }
+                }
+                Err(err) => match err.kind() {
+                    // The reactor will be told once the socket is writable again
+                    ErrorKind::WouldBlock => return,
+                    ErrorKind::Interrupted => continue,
+                    _ => {
+                        state.closed = true;
+                        state.messages.clear();
+                        state.queued_bytes = 0;
+                        return;
+                    }
+                },
+            }
+        }
+    }
+}
 
-        Ok(message)
+impl SendQueue {
+    fn notify(&self) {
+        self.notifier.pending.lock().unwrap().push(self.token);
+        if let Err(err) = self.notifier.waker.wake() {
+            warn!("Failed to wake the reactor: {err}");
+        }
     }
 
-    pub fn id(&self) -> String {
-        self.socket_addr.to_string()
+    fn close_error(&self) -> ConnectionError {
+        match self.state.lock().unwrap().close_reason {
+            Some(_) => ConnectionError::ConnectionLost,
+            None => ConnectionError::ConnectionClosed,
+        }
     }
 }
 
-impl ConnectionWrite {
-    fn from_socket(socket: TcpStream, socket_addr: SocketAddr) -> Self {
-        Self {
-            socket,
-            socket_addr,
-        }
+impl ConnectionSender {
+    /// Queues a message for the client without blocking.
+    /// If the client's queue overflows, they are disconnected and the message is dropped.
+    pub fn write_message(&self, message: &str) -> Result<(), ConnectionError> {
+        self.write_shared(&SharedMessage::from(message.as_bytes()))
     }
 
-    pub fn write_message(&mut self, message: &str) -> Result<(), ConnectionError> {
-        self.socket
-            .write_all(message.as_bytes())
-            .map_err(|_| ConnectionError::ConnectionClosed)?;
-        let _ = self.socket.flush();
+    /// Queues an already serialized message for the client, sharing its buffer rather than copying it.
+    pub fn write_shared(&self, message: &SharedMessage) -> Result<(), ConnectionError> {
+        let queue = &self.queue;
+        let mut state = queue.state.lock().unwrap();
+        if state.closed {
+            return Err(ConnectionError::ConnectionClosed);
+        }
+
+        if state.queued_bytes + message.len() > queue.limit {
+            warn!("SendQ exceeded for {}, disconnecting", queue.socket_addr);
+
+            state.closed = true;
+            state.close_reason = Some("SendQ exceeded".to_string());
+            state.messages.clear();
+            state.queued_bytes = 0;
+            drop(state);
+
+            queue.notify();
+            return Ok(());
+        }
+
+        let was_empty = state.messages.is_empty();
+        state.queued_bytes += message.len();
+        state.messages.push_back(message.clone());
+        drop(state);
+
+        if was_empty {
+            queue.notify();
+        }
 
         Ok(())
     }
 
+    /// Has the reactor call the session's `on_wake`, and deliver any messages it held back while it waited.
+    pub fn wake(&self) {
+        let notifier = &self.queue.notifier;
+        notifier.woken.lock().unwrap().push(self.queue.token);
+        if let Err(err) = notifier.waker.wake() {
+            warn!("Failed to wake the reactor: {err}");
+        }
+    }
+
+    /// The reason the server closed this connection, if it did so.
+    pub fn close_reason(&self) -> Option<String> {
+        self.queue.state.lock().unwrap().close_reason.clone()
+    }
+
     pub fn id(&self) -> String {
-        self.socket_addr.to_string()
+        self.queue.socket_addr.to_string()
+    }
+
+    /// The address the client connected from.
+    pub fn peer_ip(&self) -> IpAddr {
+        self.queue.socket_addr.ip()
     }
 }
@@ -1,4 +1,6 @@
 pub mod connect;
 pub mod irc_client;
+// `sabi_trait` expands to code which newer compilers warn about
+#[allow(non_local_definitions, repr_transparent_non_zst_fields)]
 pub mod plugin;
 pub mod types;
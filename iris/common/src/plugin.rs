@@ -1,28 +1,346 @@
 use abi_stable::{
+    abi_stability::abi_checking::exported_check_layout_compatibility,
     declare_root_module_statics,
-    library::{LibraryError, RootModule},
-    package_version_strings,
+    library::{lib_header_from_path, LibraryError, RootModule},
+    sabi_trait,
     sabi_types::VersionStrings,
-    std_types::{ROption, RResult, RString, RVec},
+    std_types::{RArc, RHashMap, ROption, RResult, RString, RVec},
     StableAbi,
 };
 
-use crate::types::{Channel, Nick, PluginMsg, PluginName, PluginReply, Target};
+use anyhow::anyhow;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+use crate::types::{
+    Channel, Invocation, JoinMsg, Nick, NickMsg, PartMsg, PluginMsg, PluginName, PluginReply,
+    PrivMsg, QuitMsg, Target,
+};
+
+/// The version of the plugin API this server and its plugins are built against.
+/// Each version adds fields to `PluginMod`, so a plugin built against any version up to this one loads:
+/// 1. `init`, `pl_name` and `handler`
+/// 2. `on_join`, `on_part`, `on_quit`, `on_privmsg` and `on_nick`
+/// 3. `intercept`
+/// 4. `verbs` and `triggers`
+/// 5. `handler_with_context`
+/// 6. `deinit`
+/// 7. `version`, `description`, `usage` and `author`
+/// 8. `init_with_config`
+/// 9. `api_version`
+pub const PLUGIN_API_VERSION: u32 = 9;
+
+/// Reports the plugin API version a plugin was built against, for its `api_version` field.
+/// This crate is built into each plugin, so this is the plugin's version rather than the server's.
+pub extern "C" fn plugin_api_version() -> u32 {
+    PLUGIN_API_VERSION
+}
+
+/// Runs the body of one of a plugin's functions, turning a panic into an `RErr` with the panic's message.
+/// A panic must never unwind out of a plugin: `sabi_extern_fn` aborts the whole server when one does,
+/// and the server cannot catch it from its side. So a plugin which may panic should wrap the body
+/// of each function returning an `RResult` in this, and make sure the rest, such as hooks, cannot panic.
+/// A plugin built with `panic = "abort"` takes the server down whatever it does.
+pub fn catch_panics<T>(body: impl FnOnce() -> RResult<T, RString>) -> RResult<T, RString> {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown cause");
+        RResult::RErr(format!("panicked: {message}").into())
+    })
+}
 
 #[repr(C)]
 #[derive(StableAbi)]
 #[sabi(kind(Prefix(prefix_ref = "PluginMod_Ref")))]
 #[sabi(missing_field(panic))]
 pub struct PluginMod {
-    pub init: extern "C" fn(),
+    pub init: extern "C" fn(host: PluginHostRef),
     pub pl_name: extern "C" fn() -> RPluginName,
     pub handler: extern "C" fn(
+        host: PluginHostRef,
         sender: RNick,
         real_name: RString,
         message: RPluginMsg,
     ) -> RResult<ROption<RPluginReply>, RString>,
+
+    // Event hooks, each called after the server has handled a message of its kind from `sender`.
+    // A plugin leaves a hook as `None` to ignore that kind of event.
+    // Every field from here on is optional: a plugin built before a field existed still loads
+    // (see `load_plugin`), and is treated as not having that hook. New fields only go at the end.
+    #[sabi(missing_field(option))]
+    pub on_join: Option<extern "C" fn(host: PluginHostRef, sender: RNick, message: RJoinMsg)>,
+    #[sabi(missing_field(option))]
+    pub on_part: Option<extern "C" fn(host: PluginHostRef, sender: RNick, message: RPartMsg)>,
+    #[sabi(missing_field(option))]
+    pub on_quit: Option<extern "C" fn(host: PluginHostRef, sender: RNick, message: RQuitMsg)>,
+    #[sabi(missing_field(option))]
+    pub on_privmsg: Option<extern "C" fn(host: PluginHostRef, sender: RNick, message: RPrivMsg)>,
+    /// Called with the user's previous nick as the sender
+    #[sabi(missing_field(option))]
+    pub on_nick: Option<extern "C" fn(host: PluginHostRef, sender: RNick, message: RNickMsg)>,
+
+    /// Called before the server handles a message which may be intercepted,
+    /// deciding whether it goes ahead, goes ahead changed, or is dropped.
+    /// Interceptors run one after the other, each seeing the message as the one before left it,
+    /// while the sender's message waits, so they should return quickly: one which takes longer than
+    /// the plugin time limit lets the message through as it is, and counts as a failure.
+    /// Line breaks are taken out of a `Modify`'s text and a `Deny`'s reason, and a `Deny`'s numeric must have three digits.
+    #[sabi(missing_field(option))]
+    pub intercept: Option<
+        extern "C" fn(host: PluginHostRef, sender: RNick, message: RInterceptedMsg) -> RVerdict,
+    >,
+
+    /// Top-level commands, such as `REMIND`, which call `handler` as if sent with `PLUGIN`.
+    /// Verbs are upper case, and may not clash with a built in command or another plugin's verb.
+    #[sabi(missing_field(option))]
+    pub verbs: Option<extern "C" fn() -> RVec<RString>>,
+    /// Words, such as `!remind`, which call `handler` when a channel message starts with one.
+    /// The rest of the message is passed as the arguments; the message is still delivered to the channel.
+    #[sabi(missing_field(option))]
+    pub triggers: Option<extern "C" fn() -> RVec<RString>>,
+
+    /// Called instead of `handler` when present, with everything known about the call,
+    /// such as the channel it was made from, rather than just the sender and their real name.
+    #[sabi(missing_field(option))]
+    pub handler_with_context: Option<
+        extern "C" fn(
+            host: PluginHostRef,
+            context: RInvocation,
+            message: RPluginMsg,
+        ) -> RResult<ROption<RPluginReply>, RString>,
+    >,
+
+    /// Called once the plugin is unloaded, after every call to it has finished,
+    /// so it can let go of anything it set up in `init`. It is not called again afterwards.
+    #[sabi(missing_field(option))]
+    pub deinit: Option<extern "C" fn(host: PluginHostRef)>,
+
+    /// The plugin's version, such as `1.2.0`. This and the three fields after it are what
+    /// `PLUGIN /help` and `VERSION` say about the plugin, and each is asked for once, when the plugin is loaded.
+    #[sabi(missing_field(option))]
+    pub version: Option<extern "C" fn() -> RString>,
+    /// A sentence on what the plugin does
+    #[sabi(missing_field(option))]
+    pub description: Option<extern "C" fn() -> RString>,
+    /// How to call the plugin, such as `REMIND {seconds} :message`
+    #[sabi(missing_field(option))]
+    pub usage: Option<extern "C" fn() -> RString>,
+    /// Who wrote the plugin
+    #[sabi(missing_field(option))]
+    pub author: Option<extern "C" fn() -> RString>,
+    /// Called instead of `init` when present, with the settings the server was given for the plugin
+    /// (`--plugin-setting /name:key=value`). Returning an error stops the plugin from loading.
+    #[sabi(missing_field(option))]
+    pub init_with_config: Option<
+        extern "C" fn(
+            host: PluginHostRef,
+            config: RHashMap<RString, RString>,
+        ) -> RResult<(), RString>,
+    >,
+    /// The plugin API version the plugin was built against, which should be `plugin_api_version`.
+    /// A plugin built against a newer version than the server's is not loaded.
+    #[sabi(missing_field(option))]
+    pub api_version: Option<extern "C" fn() -> u32>,
+}
+
+/// One of the optional fields of `PluginMod`, as a loaded plugin has it.
+pub struct OptionalField {
+    pub name: &'static str,
+    /// The plugin API version which added the field
+    pub since: u32,
+    /// Whether the plugin fills the field in, or `None` if it was built before the field was added
+    pub provided: Option<bool>,
+}
+
+impl PluginMod_Ref {
+    /// Every optional field, in order.
+    pub fn optional_fields(self) -> Vec<OptionalField> {
+        macro_rules! field {
+            ($name:ident, $since:expr) => {
+                OptionalField {
+                    name: stringify!($name),
+                    since: $since,
+                    provided: self.$name().map(|field| field.is_some()),
+                }
+            };
+        }
+
+        vec![
+            field!(on_join, 2),
+            field!(on_part, 2),
+            field!(on_quit, 2),
+            field!(on_privmsg, 2),
+            field!(on_nick, 2),
+            field!(intercept, 3),
+            field!(verbs, 4),
+            field!(triggers, 4),
+            field!(handler_with_context, 5),
+            field!(deinit, 6),
+            field!(version, 7),
+            field!(description, 7),
+            field!(usage, 7),
+            field!(author, 7),
+            field!(init_with_config, 8),
+            field!(api_version, 9),
+        ]
+    }
+
+    /// The plugin API version the plugin was built against.
+    /// For a plugin which does not say, this is the newest version whose fields it has.
+    pub fn built_against(self) -> u32 {
+        if let Some(api_version) = self.api_version().flatten() {
+            return api_version();
+        }
+
+        self.optional_fields()
+            .iter()
+            .filter(|field| field.provided.is_some())
+            .map(|field| field.since)
+            .max()
+            .unwrap_or(1)
+    }
+}
+
+/// The plugin's handle on the server, handed to `init` and to every `handler` call.
+/// A plugin may keep it for as long as it is loaded, so that it can speak unprompted.
+#[sabi_trait]
+pub trait PluginHost: Send + Sync + Clone {
+    /// Delivers `reply` once `delay_ms` milliseconds have passed,
+    /// and then again every `interval_ms` milliseconds if an interval is given.
+    fn schedule(
+        &self,
+        delay_ms: u64,
+        interval_ms: ROption<u64>,
+        reply: RPluginReply,
+    ) -> RTimerHandle;
+
+    /// Stops a delivery the plugin scheduled, returning whether it was still pending.
+    /// A handle from any other plugin is left alone.
+    fn cancel(&self, handle: RTimerHandle) -> bool;
+
+    /// Sends a message to a user or channel straight away, as a private message from the plugin's service,
+    /// which is put on the server first if it has not joined a channel yet.
+    /// Each line of `message` is delivered as a message of its own.
+    fn send(&self, target: RTarget, message: RString) -> RResult<(), RString>;
+
+    /// Joins a channel as a service, under the plugin's name without its leading `/`.
+    /// Private messages sent to the service are handed to the plugin's handler,
+    /// with their text split into arguments like a command's.
+    fn join(&self, channel: RChannel) -> RResult<(), RString>;
+
+    /// Leaves a channel previously joined with `join`.
+    #[sabi(last_prefix_field)]
+    fn part(&self, channel: RChannel) -> RResult<(), RString>;
+
+    // Queries about the server's state. Each returns a copy taken when it was asked,
+    // so no lock is held while the plugin looks at the answer.
+
+    /// Every channel which has at least one member.
+    fn channels(&self) -> RVec<RChannelInfo>;
+
+    /// The channel, if it has at least one member.
+    fn channel(&self, channel: RChannel) -> ROption<RChannelInfo>;
+
+    /// The nicks of everyone in the channel, which is empty if the channel has no members.
+    fn members(&self, channel: RChannel) -> RVec<RNick>;
+
+    /// The user, if they are online.
+    fn user(&self, nick: RNick) -> ROption<RUserInfo>;
+
+    // The plugin's own persistent store, which no other plugin can see and which outlives
+    // the plugin being unloaded or the server restarting. Keys are 1 to 100 bytes long.
+
+    /// The value stored under `key`, if there is one.
+    fn store_get(&self, key: RString) -> RResult<ROption<RString>, RString>;
+
+    /// Stores `value` under `key`, replacing any value already there.
+    /// The write either happens completely or not at all. It fails if the value is too long,
+    /// or if the key is new and the plugin already keeps as many keys as the server allows.
+    fn store_set(&self, key: RString, value: RString) -> RResult<(), RString>;
+
+    /// Removes `key`, returning whether it was there.
+    fn store_remove(&self, key: RString) -> RResult<bool, RString>;
+
+    /// Every stored key starting with `prefix`, in order.
+    fn store_keys(&self, prefix: RString) -> RResult<RVec<RString>, RString>;
+
+    /// As `schedule`, but fails once the plugin has as many timers pending as the server allows.
+    /// `schedule` schedules nothing by then, and hands back a handle of 0, which is never pending.
+    fn try_schedule(
+        &self,
+        delay_ms: u64,
+        interval_ms: ROption<u64>,
+        reply: RPluginReply,
+    ) -> RResult<RTimerHandle, RString>;
+}
+
+pub type PluginHostRef = PluginHost_TO<'static, RArc<()>>;
+
+/// Everything known about a call to `handler_with_context`, beyond the message itself.
+#[repr(C)]
+#[derive(StableAbi)]
+pub struct RInvocation {
+    pub sender: RNick,
+    pub real_name: RString,
+    /// The sender as `nick!username@host`
+    pub hostmask: RString,
+    /// Where the plugin was called from: the channel for a trigger, otherwise the sender,
+    /// so replying to the origin answers the user wherever they asked
+    pub origin: RTarget,
+    /// The operator name the sender has authenticated as, if any
+    pub account: ROption<RString>,
+    /// When the call was made, in milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    pub server: RServerInfo,
+}
+
+/// The server a plugin is running in.
+#[repr(C)]
+#[derive(StableAbi, Clone)]
+pub struct RServerInfo {
+    pub name: RString,
+    pub version: RString,
 }
 
+impl RInvocation {
+    pub fn new(invocation: Invocation, server: RServerInfo) -> Self {
+        RInvocation {
+            sender: invocation.sender.into(),
+            real_name: invocation.real_name.into(),
+            hostmask: invocation.hostmask.into(),
+            origin: invocation.origin.into(),
+            account: invocation.account.map(RString::from).into(),
+            timestamp_ms: invocation.timestamp_ms,
+            server,
+        }
+    }
+}
+
+/// A channel, as seen by `PluginHost::channels` and `PluginHost::channel`.
+#[repr(C)]
+#[derive(StableAbi)]
+pub struct RChannelInfo {
+    pub channel: RChannel,
+    pub member_count: u64,
+}
+
+/// An online user, as seen by `PluginHost::user`.
+#[repr(C)]
+#[derive(StableAbi)]
+pub struct RUserInfo {
+    pub nick: RNick,
+    pub channels: RVec<RChannel>,
+    pub away_message: ROption<RString>,
+}
+
+/// Identifies a delivery scheduled through `PluginHost::schedule`.
+#[repr(C)]
+#[derive(StableAbi, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RTimerHandle(pub u64);
+
 #[repr(C)]
 #[derive(StableAbi)]
 pub struct RNick(pub RString);
@@ -50,6 +368,15 @@ pub enum RTarget {
     RUser(RNick),
 }
 
+impl From<Target> for RTarget {
+    fn from(target: Target) -> Self {
+        match target {
+            Target::Channel(channel) => RTarget::RChannel(channel.into()),
+            Target::User(user) => RTarget::RUser(user.into()),
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(StableAbi)]
 pub struct RPluginName(pub RString);
@@ -69,13 +396,8 @@ pub struct RPluginReply {
 
 impl From<PluginReply> for RPluginReply {
     fn from(repl: PluginReply) -> Self {
-        let target = match repl.target {
-            Target::Channel(channel) => RTarget::RChannel(channel.into()),
-            Target::User(user) => RTarget::RUser(user.into()),
-        };
-
         RPluginReply {
-            target,
+            target: repl.target.into(),
             message: repl.message.into(),
         }
     }
@@ -97,13 +419,252 @@ impl From<PluginMsg> for RPluginMsg {
     }
 }
 
+#[repr(C)]
+#[derive(StableAbi)]
+pub struct RJoinMsg {
+    pub channel: RChannel,
+}
+
+impl From<JoinMsg> for RJoinMsg {
+    fn from(msg: JoinMsg) -> Self {
+        RJoinMsg {
+            channel: msg.channel.into(),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(StableAbi)]
+pub struct RPartMsg {
+    pub channel: RChannel,
+}
+
+impl From<PartMsg> for RPartMsg {
+    fn from(msg: PartMsg) -> Self {
+        RPartMsg {
+            channel: msg.channel.into(),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(StableAbi)]
+pub struct RQuitMsg {
+    pub message: ROption<RString>,
+}
+
+impl From<QuitMsg> for RQuitMsg {
+    fn from(msg: QuitMsg) -> Self {
+        RQuitMsg {
+            message: msg.message.map(RString::from).into(),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(StableAbi)]
+pub struct RPrivMsg {
+    pub target: RTarget,
+    pub message: RString,
+}
+
+impl From<PrivMsg> for RPrivMsg {
+    fn from(msg: PrivMsg) -> Self {
+        RPrivMsg {
+            target: msg.target.into(),
+            message: msg.message.into(),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(StableAbi)]
+pub struct RNickMsg {
+    pub nick: RNick,
+}
+
+impl From<NickMsg> for RNickMsg {
+    fn from(msg: NickMsg) -> Self {
+        RNickMsg {
+            nick: msg.nick.into(),
+        }
+    }
+}
+
+/// A message which interceptors may change or drop.
+#[repr(u8)]
+#[derive(StableAbi)]
+pub enum RInterceptedMsg {
+    PrivMsg(RPrivMsg),
+    Join(RJoinMsg),
+}
+
+/// An interceptor's decision on a message.
+#[repr(u8)]
+#[derive(StableAbi)]
+pub enum RVerdict {
+    /// Let the message through as it is
+    Allow,
+    /// Let the message through with new text: a PRIVMSG's message, or the channel a JOIN is for
+    Modify(RString),
+    /// Drop the message, replying to the sender with a numeric
+    Deny(RDenial),
+}
+
+#[repr(C)]
+#[derive(StableAbi)]
+pub struct RDenial {
+    pub numeric: u16,
+    pub reason: RString,
+}
+
+/// Loads the plugin at `path`.
+/// As well as plugins built against this version of `PluginMod`, this accepts plugins built
+/// before some of its optional fields were added, which simply report those fields as missing.
+/// A plugin built against a newer plugin API than the server's is turned away, as it may rely on
+/// parts of `PluginHost` the server does not have.
+pub fn load_plugin(path: &Path) -> anyhow::Result<PluginMod_Ref> {
+    let header = lib_header_from_path(path)?;
+
+    let plugin = match header.init_root_module::<PluginMod_Ref>() {
+        Err(LibraryError::AbiInstability(err)) => {
+            // `abi_stable` only accepts a library which is at least as new as its loader,
+            // so an older plugin is checked the other way around: the server must be able to
+            // stand in for the version of `PluginMod` the plugin was built against.
+            let plugin_layout = header.layout().ok_or(LibraryError::AbiInstability(err))?;
+            exported_check_layout_compatibility(
+                plugin_layout,
+                <PluginMod_Ref as StableAbi>::LAYOUT,
+            )
+            .into_result()
+            .map_err(|err| {
+                anyhow!(
+                    "The plugin is not compatible with plugin API {PLUGIN_API_VERSION}: {}",
+                    LibraryError::AbiInstability(err.to_formatted_error())
+                )
+            })?;
+
+            // Safety: the plugin's layout has just been checked to be a prefix of ours
+            unsafe { header.init_root_module_with_unchecked_layout::<PluginMod_Ref>() }?
+        }
+        plugin => plugin?,
+    };
+
+    check_built_against(plugin)?;
+    Ok(plugin)
+}
+
+/// Turns away a plugin built against a newer plugin API than the server's.
+fn check_built_against(plugin: PluginMod_Ref) -> anyhow::Result<()> {
+    let built_against = plugin.built_against();
+    if built_against > PLUGIN_API_VERSION {
+        return Err(anyhow!(
+            "The plugin was built against plugin API {built_against}, \
+             but this server only supports up to {PLUGIN_API_VERSION}"
+        ));
+    }
+
+    Ok(())
+}
+
 impl RootModule for PluginMod_Ref {
     declare_root_module_statics! {PluginMod_Ref}
     const BASE_NAME: &'static str = "iris";
     const NAME: &'static str = "iris";
-    const VERSION_STRINGS: VersionStrings = package_version_strings!();
+    // Fixed rather than following this crate's version, as which plugins are compatible is down to
+    // their layout and `PLUGIN_API_VERSION`, and a new version of this crate would turn them all away
+    const VERSION_STRINGS: VersionStrings = VersionStrings::new("0.1.0");
 
     fn initialization(self) -> Result<Self, LibraryError> {
         Ok(self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use abi_stable::prefix_type::PrefixTypeTrait;
+
+    extern "C" fn init(_: PluginHostRef) {}
+
+    extern "C" fn pl_name() -> RPluginName {
+        RPluginName("/test".into())
+    }
+
+    extern "C" fn handler(
+        _: PluginHostRef,
+        _: RNick,
+        _: RString,
+        _: RPluginMsg,
+    ) -> RResult<ROption<RPluginReply>, RString> {
+        RResult::ROk(ROption::RNone)
+    }
+
+    extern "C" fn from_the_future() -> u32 {
+        PLUGIN_API_VERSION + 1
+    }
+
+    /// A plugin built against this crate, which says it was built against `api_version`.
+    fn plugin(api_version: Option<extern "C" fn() -> u32>) -> PluginMod_Ref {
+        PluginMod {
+            init,
+            pl_name,
+            handler,
+            on_join: None,
+            on_part: None,
+            on_quit: None,
+            on_privmsg: None,
+            on_nick: None,
+            intercept: None,
+            verbs: None,
+            triggers: None,
+            handler_with_context: None,
+            deinit: None,
+            version: None,
+            description: None,
+            usage: None,
+            author: None,
+            init_with_config: None,
+            api_version,
+        }
+        .leak_into_prefix()
+    }
+
+    #[test]
+    fn test_plugins_built_against_a_newer_api_are_turned_away() {
+        assert!(check_built_against(plugin(Some(plugin_api_version))).is_ok());
+
+        let err = check_built_against(plugin(Some(from_the_future))).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "The plugin was built against plugin API {}, but this server only supports up to {PLUGIN_API_VERSION}",
+                PLUGIN_API_VERSION + 1
+            )
+        );
+    }
+
+    #[test]
+    fn test_plugins_which_do_not_say_are_taken_to_be_built_against_their_newest_field() {
+        // Every field is there, even if left empty, so the plugin has the layout of this version
+        let plugin = plugin(None);
+        assert_eq!(plugin.built_against(), PLUGIN_API_VERSION);
+        assert!(check_built_against(plugin).is_ok());
+
+        assert!(plugin
+            .optional_fields()
+            .iter()
+            .all(|field| field.provided == Some(false)));
+    }
+
+    #[test]
+    fn test_panics_are_caught_as_errors() {
+        assert!(matches!(
+            catch_panics(|| RResult::<u8, RString>::ROk(1)),
+            RResult::ROk(1)
+        ));
+
+        let panicked = catch_panics(|| -> RResult<u8, RString> { panic!("oops") });
+        assert!(matches!(panicked, RResult::RErr(err) if err == "panicked: oops"));
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use crate::plugin::{RChannel, RNick, RPluginMsg, RPluginName, RPluginReply, RTarget};
 
 /// All relevant IRC errors are listed here.
@@ -14,6 +16,8 @@ pub enum ErrorType {
     NeedMoreParams = 461,
     NoSuchNick = 401,
     NoSuchChannel = 403,
+    PasswdMismatch = 464,
+    NoPrivileges = 481,
     PluginException = 998,
     NoSuchPlugin = 999,
 }
@@ -22,6 +26,11 @@ pub enum ErrorType {
 /// the server should be listed as from this name.
 pub const SERVER_NAME: &str = "iris-server";
 
+/// The maximum length of a single line on the wire, including the trailing CRLF.
+pub const MAX_LINE_LENGTH: usize = 512;
+
+const CRLF: &str = "\r\n";
+
 impl std::fmt::Display for ErrorType {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         match *self {
@@ -53,6 +62,15 @@ impl std::fmt::Display for ErrorType {
             ErrorType::NoSuchChannel => {
                 write!(fmt, ":{SERVER_NAME} 403 :No such channel")
             }
+            ErrorType::PasswdMismatch => {
+                write!(fmt, ":{SERVER_NAME} 464 :Password incorrect")
+            }
+            ErrorType::NoPrivileges => {
+                write!(
+                    fmt,
+                    ":{SERVER_NAME} 481 :Permission Denied- You're not an IRC operator"
+                )
+            }
             ErrorType::NickCollision => {
                 write!(fmt, ":{SERVER_NAME} 436 :Nickname collision")
             }
@@ -130,7 +148,6 @@ impl TryFrom<String> for Nick {
     type Error = ErrorType;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
-        let value = value;
         if (1..10).contains(&value.len())
             && value.is_ascii()
             && value.chars().next().unwrap_or('!').is_alphabetic()
@@ -187,7 +204,7 @@ impl std::fmt::Display for Channel {
     }
 }
 
-/// An IRC plugin name.
+/// An IRC plugin name: a '/' followed by 1 to 18 letters or digits.
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Ord, PartialOrd)]
 pub struct PluginName(pub String);
 
@@ -195,7 +212,7 @@ impl TryFrom<String> for PluginName {
     type Error = ErrorType;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
-        if (1..20).contains(&value.len())
+        if (2..20).contains(&value.len())
             && value.chars().next().unwrap_or('!') == '/'
             && value.is_ascii()
             && value[1..].chars().all(char::is_alphanumeric)
@@ -283,6 +300,7 @@ impl TryFrom<Vec<String>> for PartMsg {
 // For example: `USER ignored ignored ignored :Thomas Kunc\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UserMsg {
+    pub username: String,
     pub real_name: String,
 }
 
@@ -290,11 +308,13 @@ impl TryFrom<Vec<String>> for UserMsg {
     type Error = ErrorType;
 
     fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
-        value
-            .into_iter()
-            .nth(4)
-            .ok_or(ErrorType::NeedMoreParams)
-            .map(|real_name| UserMsg { real_name })
+        match value.as_slice() {
+            [_, username, _, _, real_name, ..] => Ok(UserMsg {
+                username: username.clone(),
+                real_name: real_name.clone(),
+            }),
+            _ => Err(ErrorType::NeedMoreParams),
+        }
     }
 }
 
@@ -306,6 +326,21 @@ pub struct PluginMsg {
     pub args: Vec<String>,
 }
 
+impl PluginMsg {
+    /// A call to the plugin with the arguments in `text`, which is split the same way as a command.
+    pub fn with_text(plugin_name: PluginName, text: &str) -> PluginMsg {
+        let args = match text.trim_start() {
+            "" => vec![],
+            text => split_command(text)
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+        };
+
+        PluginMsg { plugin_name, args }
+    }
+}
+
 impl TryFrom<Vec<String>> for PluginMsg {
     type Error = ErrorType;
 
@@ -328,6 +363,45 @@ impl From<RPluginMsg> for PluginMsg {
     }
 }
 
+/// Everything known about a call to a plugin, beyond the message itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invocation {
+    pub sender: Nick,
+    pub real_name: String,
+    /// The sender as `nick!username@host`
+    pub hostmask: String,
+    /// Where the plugin was called from: the channel for a trigger, otherwise the sender
+    pub origin: Target,
+    /// The operator name the sender has authenticated as, if any
+    pub account: Option<String>,
+    /// When the call was made, in milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+}
+
+/// An operator's change to the loaded plugins, made while the server runs.
+/// For example: `PLUGIN LOAD /path/to/plugin.so`, `PLUGIN UNLOAD /remind` or `PLUGIN RELOAD /remind`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PluginAdminMsg {
+    Load(String),
+    Unload(PluginName),
+    Reload(PluginName),
+}
+
+impl TryFrom<Vec<String>> for PluginAdminMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let argument = value.get(2).ok_or(ErrorType::NeedMoreParams)?.to_string();
+
+        match value[1].as_str() {
+            "LOAD" => Ok(PluginAdminMsg::Load(argument)),
+            "UNLOAD" => Ok(PluginAdminMsg::Unload(PluginName::try_from(argument)?)),
+            "RELOAD" => Ok(PluginAdminMsg::Reload(PluginName::try_from(argument)?)),
+            _ => Err(ErrorType::UnknownCommand),
+        }
+    }
+}
+
 /// A private message.
 /// For example: `PRIVMSG tom :Hi Tom, how are you?\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -370,17 +444,65 @@ impl TryFrom<Vec<String>> for QuitMsg {
     }
 }
 
+/// A message to mark yourself as away, or to return if no message is given.
+/// For example: `AWAY :Gone to lunch\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AwayMsg {
+    pub message: Option<String>,
+}
+
+impl TryFrom<Vec<String>> for AwayMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        Ok(AwayMsg {
+            // skip(1) here skips the AWAY instruction.
+            message: value
+                .into_iter()
+                .skip(1)
+                .last()
+                .filter(|message| !message.is_empty()),
+        })
+    }
+}
+
+/// A message to gain operator privileges.
+/// For example: `OPER tfpk hunter2\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OperMsg {
+    pub name: String,
+    pub password: String,
+}
+
+impl TryFrom<Vec<String>> for OperMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let mut args = value.into_iter().skip(1);
+        match (args.next(), args.next()) {
+            (Some(name), Some(password)) => Ok(OperMsg { name, password }),
+            _ => Err(ErrorType::NeedMoreParams),
+        }
+    }
+}
+
 /// A list of every possible message that can be sent.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Message {
     Nick(NickMsg),
     User(UserMsg),
     PrivMsg(PrivMsg),
+    /// Sent like a private message, but never answered automatically, such as when its recipient is missing
+    Notice(PrivMsg),
     Ping(String),
     Join(JoinMsg),
     Part(PartMsg),
     Quit(QuitMsg),
+    Away(AwayMsg),
+    Oper(OperMsg),
     Plugin(PluginMsg),
+    PluginAdmin(PluginAdminMsg),
+    Version,
 }
 
 /// To parse a message, construct this struct.
@@ -401,10 +523,103 @@ pub struct ParsedMessage {
     pub message: Message,
 }
 
-impl<'a> TryFrom<UnparsedMessage<'a>> for ParsedMessage {
-    type Error = ErrorType;
-    fn try_from(value: UnparsedMessage<'a>) -> Result<Self, Self::Error> {
-        let command = split_command(value.message)
+/// Every command the server itself understands, which no plugin may register as a verb.
+pub const BUILTIN_COMMANDS: &[&str] = &[
+    "PING", "PRIVMSG", "NOTICE", "USER", "NICK", "JOIN", "PART", "QUIT", "AWAY", "OPER", "PLUGIN",
+    "VERSION",
+];
+
+/// The commands plugins have registered, which the parser falls back to for commands it does not know.
+/// A verb, such as `REMIND`, is a command of its own: `REMIND 10 :msg` is handled as `PLUGIN /remind 10 :msg`.
+/// A trigger, such as `!remind`, starts a channel message: `!remind 10 :msg` also calls the plugin.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandTable {
+    verbs: BTreeMap<String, PluginName>,
+    triggers: BTreeMap<String, PluginName>,
+}
+
+impl CommandTable {
+    /// Registers `verb` for the plugin, failing if it is malformed, built in, or already taken.
+    pub fn register_verb(&mut self, verb: &str, pl_name: &PluginName) -> Result<(), String> {
+        if verb.is_empty() || !verb.chars().all(|c| c.is_ascii_uppercase()) {
+            return Err(format!(
+                "{pl_name}: the verb {verb:?} must be made up of upper case letters"
+            ));
+        }
+
+        if BUILTIN_COMMANDS.contains(&verb) {
+            return Err(format!("{pl_name}: the verb {verb} is a built in command"));
+        }
+
+        Self::register(&mut self.verbs, "verb", verb, pl_name)
+    }
+
+    /// Registers `trigger` for the plugin, failing if it is malformed or already taken.
+    pub fn register_trigger(&mut self, trigger: &str, pl_name: &PluginName) -> Result<(), String> {
+        let word = trigger.strip_prefix('!').unwrap_or_default();
+        if word.is_empty() || word.contains(char::is_whitespace) {
+            return Err(format!(
+                "{pl_name}: the trigger {trigger:?} must be a single word starting with '!'"
+            ));
+        }
+
+        Self::register(&mut self.triggers, "trigger", trigger, pl_name)
+    }
+
+    fn register(
+        commands: &mut BTreeMap<String, PluginName>,
+        kind: &str,
+        command: &str,
+        pl_name: &PluginName,
+    ) -> Result<(), String> {
+        match commands.get(command) {
+            Some(owner) => Err(format!(
+                "{pl_name}: the {kind} {command} is already registered by {owner}"
+            )),
+            None => {
+                commands.insert(command.to_string(), pl_name.clone());
+                Ok(())
+            }
+        }
+    }
+
+    /// Every verb and trigger registered by the plugin.
+    pub fn registered_by(&self, pl_name: &PluginName) -> Vec<String> {
+        self.verbs
+            .iter()
+            .chain(self.triggers.iter())
+            .filter(|(_, owner)| *owner == pl_name)
+            .map(|(command, _)| command.clone())
+            .collect()
+    }
+
+    /// Forgets every verb and trigger registered by the plugin.
+    pub fn unregister(&mut self, pl_name: &PluginName) {
+        self.verbs.retain(|_, owner| owner != pl_name);
+        self.triggers.retain(|_, owner| owner != pl_name);
+    }
+
+    /// The plugin call for a channel message which starts with a trigger, if it does.
+    /// The rest of the message is split into arguments the same way as a command's.
+    pub fn trigger(&self, priv_msg: &PrivMsg) -> Option<PluginMsg> {
+        if !matches!(priv_msg.target, Target::Channel(_)) {
+            return None;
+        }
+
+        let (trigger, rest) = priv_msg
+            .message
+            .split_once(' ')
+            .unwrap_or((&priv_msg.message, ""));
+        let plugin_name = self.triggers.get(trigger)?.clone();
+
+        Some(PluginMsg::with_text(plugin_name, rest))
+    }
+}
+
+impl<'a> UnparsedMessage<'a> {
+    /// Parses the message, handing any command the server does not know to the plugin which registered it.
+    pub fn parse(&self, commands: &CommandTable) -> Result<ParsedMessage, ErrorType> {
+        let command = split_command(self.message)
             .into_iter()
             .map(str::to_string)
             .collect::<Vec<_>>();
@@ -419,19 +634,41 @@ impl<'a> TryFrom<UnparsedMessage<'a>> for ParsedMessage {
                     .ok_or(ErrorType::NoOrigin)?,
             )),
             "PRIVMSG" => Ok(Message::PrivMsg(PrivMsg::try_from(command)?)),
+            "NOTICE" => Ok(Message::Notice(PrivMsg::try_from(command)?)),
             "USER" => Ok(Message::User(UserMsg::try_from(command)?)),
             "NICK" => Ok(Message::Nick(NickMsg::try_from(command)?)),
             "JOIN" => Ok(Message::Join(JoinMsg::try_from(command)?)),
             "PART" => Ok(Message::Part(PartMsg::try_from(command)?)),
             "QUIT" => Ok(Message::Quit(QuitMsg::try_from(command)?)),
-            "PLUGIN" => Ok(Message::Plugin(PluginMsg::try_from(command)?)),
-            _ => Err(ErrorType::UnknownCommand),
+            "AWAY" => Ok(Message::Away(AwayMsg::try_from(command)?)),
+            "OPER" => Ok(Message::Oper(OperMsg::try_from(command)?)),
+            "VERSION" => Ok(Message::Version),
+            "PLUGIN" => match command.get(1).map(String::as_str) {
+                Some("LOAD" | "UNLOAD" | "RELOAD") => {
+                    Ok(Message::PluginAdmin(PluginAdminMsg::try_from(command)?))
+                }
+                _ => Ok(Message::Plugin(PluginMsg::try_from(command)?)),
+            },
+            verb => match commands.verbs.get(verb) {
+                Some(plugin_name) => Ok(Message::Plugin(PluginMsg {
+                    plugin_name: plugin_name.clone(),
+                    args: command[1..].to_vec(),
+                })),
+                None => Err(ErrorType::UnknownCommand),
+            },
         }?;
 
         Ok(ParsedMessage { message })
     }
 }
 
+impl<'a> TryFrom<UnparsedMessage<'a>> for ParsedMessage {
+    type Error = ErrorType;
+    fn try_from(value: UnparsedMessage<'a>) -> Result<Self, Self::Error> {
+        value.parse(&CommandTable::default())
+    }
+}
+
 impl<'a> TryFrom<&'a str> for ParsedMessage {
     type Error = ErrorType;
     fn try_from(value: &'a str) -> Result<Self, Self::Error> {
@@ -463,6 +700,42 @@ pub struct QuitReply {
     pub sender_nick: Nick,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NickReply {
+    pub message: NickMsg,
+    pub sender_nick: Nick,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AwayReply {
+    pub message: AwayMsg,
+    pub sender_nick: Nick,
+}
+
+/// Lets `target_nick` know that `away_nick` is away.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserAwayReply {
+    pub target_nick: Nick,
+    pub away_nick: Nick,
+    pub message: String,
+}
+
+/// The answer to `VERSION`: the server's version, with the plugins it is running as comments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionReply {
+    pub target_nick: Nick,
+    pub version: String,
+    pub comments: String,
+}
+
+/// A numeric reply which is not one of the server's own, such as one chosen by a plugin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NumericReply {
+    pub target_nick: Nick,
+    pub numeric: u16,
+    pub message: String,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct WelcomeReply {
     pub target_nick: Nick,
@@ -490,55 +763,186 @@ pub enum Reply {
     Pong(String),
     Welcome(WelcomeReply),
     PrivMsg(PrivReply),
+    Notice(PrivReply),
     Join(JoinReply),
     Part(PartReply),
     Error(ErrorType),
     Quit(QuitReply),
+    Nick(NickReply),
+    Away(AwayReply),
+    UserAway(UserAwayReply),
+    Numeric(NumericReply),
+    UnAway(Nick),
+    NowAway(Nick),
+    YoureOper(Nick),
+    Closing(String),
     Plugin(PluginReply),
+    Version(VersionReply),
 }
 
-impl std::fmt::Display for Reply {
-    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+impl Reply {
+    /// Serializes the reply into the lines which are written to the wire (without their CRLF).
+    /// Every line is guaranteed to fit within `MAX_LINE_LENGTH` once the CRLF is appended.
+    /// Replies carrying free text (private messages, notices and plugin replies) are split over as many
+    /// lines as needed, all other replies are truncated.
+    pub fn to_lines(&self) -> Vec<String> {
         match self {
-            Reply::Pong(p) => write!(fmt, "PONG :{p}\r\n"),
+            Reply::Pong(p) => vec![truncate_line(format!("PONG :{p}"))],
             Reply::Plugin(p) => {
                 let target = &p.target;
-                let message = &p.message;
-                write!(fmt, "PLUGIN {target} : {message}\r\n")
+                split_trailing(&format!("PLUGIN {target} : "), &p.message)
             }
             Reply::Welcome(r) => {
                 let nick = &r.target_nick;
                 let message = &r.message;
-                write!(fmt, ":{SERVER_NAME} 001 {nick} :{message}\r\n")
+                vec![truncate_line(format!(
+                    ":{SERVER_NAME} 001 {nick} :{message}"
+                ))]
             }
             Reply::PrivMsg(r) => {
                 let nick = &r.message.target;
-                let message = &r.message.message;
                 let from = &r.sender_nick;
-                write!(fmt, ":{from} PRIVMSG {nick} :{message}\r\n")
+                split_trailing(&format!(":{from} PRIVMSG {nick} :"), &r.message.message)
             }
-            Reply::Error(e) => {
-                write!(fmt, ":{SERVER_NAME} {e}\r\n")
+            Reply::Notice(r) => {
+                let nick = &r.message.target;
+                let from = &r.sender_nick;
+                split_trailing(&format!(":{from} NOTICE {nick} :"), &r.message.message)
             }
+            Reply::Error(e) => vec![truncate_line(format!(":{SERVER_NAME} {e}"))],
             Reply::Join(r) => {
                 let sender = &r.sender_nick;
                 let channel = &r.message.channel;
-                write!(fmt, ":{sender} JOIN {channel}\r\n")
+                vec![truncate_line(format!(":{sender} JOIN {channel}"))]
             }
             Reply::Part(r) => {
                 let sender = &r.sender_nick;
                 let channel = &r.message.channel;
-                write!(fmt, ":{sender} PART {channel}\r\n")
+                vec![truncate_line(format!(":{sender} PART {channel}"))]
             }
             Reply::Quit(r) => {
                 let sender = &r.sender_nick.to_string();
                 let message = &r.message.message.as_ref().unwrap_or(sender);
-                write!(fmt, ":{sender} QUIT :{message}\r\n")
+                vec![truncate_line(format!(":{sender} QUIT :{message}"))]
+            }
+            Reply::Nick(r) => {
+                let sender = &r.sender_nick;
+                let nick = &r.message.nick;
+                vec![truncate_line(format!(":{sender} NICK {nick}"))]
+            }
+            Reply::Away(r) => {
+                let sender = &r.sender_nick;
+                match &r.message.message {
+                    Some(message) => vec![truncate_line(format!(":{sender} AWAY :{message}"))],
+                    None => vec![truncate_line(format!(":{sender} AWAY"))],
+                }
+            }
+            Reply::UserAway(r) => {
+                let target = &r.target_nick;
+                let away = &r.away_nick;
+                let message = &r.message;
+                vec![truncate_line(format!(
+                    ":{SERVER_NAME} 301 {target} {away} :{message}"
+                ))]
+            }
+            Reply::Numeric(r) => {
+                let target = &r.target_nick;
+                let numeric = r.numeric;
+                let message = &r.message;
+                vec![truncate_line(format!(
+                    ":{SERVER_NAME} {numeric:03} {target} :{message}"
+                ))]
+            }
+            Reply::UnAway(nick) => vec![truncate_line(format!(
+                ":{SERVER_NAME} 305 {nick} :You are no longer marked as being away"
+            ))],
+            Reply::NowAway(nick) => vec![truncate_line(format!(
+                ":{SERVER_NAME} 306 {nick} :You have been marked as being away"
+            ))],
+            Reply::YoureOper(nick) => vec![truncate_line(format!(
+                ":{SERVER_NAME} 381 {nick} :You are now an IRC operator"
+            ))],
+            Reply::Closing(reason) => vec![truncate_line(format!("ERROR :{reason}"))],
+            Reply::Version(r) => {
+                let nick = &r.target_nick;
+                let version = &r.version;
+                let comments = &r.comments;
+                vec![truncate_line(format!(
+                    ":{SERVER_NAME} 351 {nick} {version} {SERVER_NAME} :{comments}"
+                ))]
             }
         }
     }
 }
 
+impl std::fmt::Display for Reply {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        for line in self.to_lines() {
+            write!(fmt, "{line}{CRLF}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The largest index no greater than `index` which lies on a UTF-8 character boundary of `text`.
+fn floor_char_boundary(text: &str, index: usize) -> usize {
+    if index >= text.len() {
+        return text.len();
+    }
+
+    (0..=index)
+        .rev()
+        .find(|&i| text.is_char_boundary(i))
+        .unwrap_or(0)
+}
+
+/// Cuts a line down so that it fits on the wire, without splitting a UTF-8 character.
+fn truncate_line(mut line: String) -> String {
+    line.truncate(floor_char_boundary(&line, MAX_LINE_LENGTH - CRLF.len()));
+    line
+}
+
+/// Splits `text` into chunks such that `head` followed by each chunk fits on the wire.
+/// Chunks are split on the last space which fits, falling back to the last UTF-8
+/// character boundary for words which are too long to fit on a line of their own.
+fn split_trailing(head: &str, text: &str) -> Vec<String> {
+    let budget = (MAX_LINE_LENGTH - CRLF.len()).saturating_sub(head.len());
+    if budget == 0 {
+        return vec![truncate_line(format!("{head}{text}"))];
+    }
+
+    let mut lines = vec![];
+    let mut remaining = text;
+    loop {
+        if remaining.len() <= budget {
+            lines.push(format!("{head}{remaining}"));
+            break;
+        }
+
+        let cut = floor_char_boundary(remaining, budget);
+        let space = if remaining[cut..].starts_with(' ') {
+            Some(cut)
+        } else {
+            remaining[..cut].rfind(' ')
+        };
+        let (chunk, rest) = match space {
+            Some(space) if space > 0 => (&remaining[..space], &remaining[space + 1..]),
+            _ if cut > 0 => remaining.split_at(cut),
+            // Not even a single character fits, so give up on splitting.
+            _ => {
+                lines.push(truncate_line(format!("{head}{remaining}")));
+                break;
+            }
+        };
+
+        lines.push(format!("{head}{chunk}"));
+        remaining = rest;
+    }
+
+    lines
+}
+
 mod tests {
     #[allow(unused_imports)]
     use super::*;
@@ -570,6 +974,27 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_notice() {
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "NOTICE #lobby :Back in five\r\n",
+            })
+            .unwrap()
+            .message,
+            Message::Notice(PrivMsg {
+                target: Target::Channel(Channel("#lobby".to_string())),
+                message: "Back in five".to_string()
+            })
+        );
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "NOTICE tom\r\n",
+            }),
+            Err(ErrorType::NoTextToSend)
+        );
+    }
+
     #[test]
     fn test_nick() {
         assert_eq!(
@@ -590,6 +1015,63 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_away() {
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "AWAY :Gone to lunch\r\n",
+            })
+            .unwrap()
+            .message,
+            Message::Away(AwayMsg {
+                message: Some("Gone to lunch".to_string())
+            })
+        );
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "AWAY\r\n",
+            })
+            .unwrap()
+            .message,
+            Message::Away(AwayMsg { message: None })
+        );
+    }
+
+    #[test]
+    fn test_numeric_reply() {
+        let reply = Reply::Numeric(NumericReply {
+            target_nick: Nick("tfpk".to_string()),
+            numeric: 42,
+            message: "Links are not allowed here".to_string(),
+        });
+
+        assert_eq!(
+            reply.to_string(),
+            ":iris-server 042 tfpk :Links are not allowed here\r\n"
+        );
+    }
+
+    #[test]
+    fn test_oper() {
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "OPER tfpk hunter2\r\n",
+            })
+            .unwrap()
+            .message,
+            Message::Oper(OperMsg {
+                name: "tfpk".to_string(),
+                password: "hunter2".to_string(),
+            })
+        );
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "OPER tfpk\r\n",
+            }),
+            Err(ErrorType::NeedMoreParams)
+        );
+    }
+
     #[test]
     fn test_plugin() {
         assert_eq!(
@@ -609,5 +1091,191 @@ mod tests {
             }),
             Err(ErrorType::NoSuchPlugin)
         );
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "PLUGIN / hi\r\n",
+            }),
+            Err(ErrorType::NoSuchPlugin)
+        );
+    }
+
+    #[test]
+    fn test_long_privmsg_is_split() {
+        let message = "word ".repeat(300).trim_end().to_string();
+        let reply = Reply::PrivMsg(PrivReply {
+            message: PrivMsg {
+                target: Target::Channel(Channel("#channel".to_string())),
+                message: message.clone(),
+            },
+            sender_nick: Nick("tfpk".to_string()),
+        });
+
+        let lines = reply.to_lines();
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| line.len() + 2 <= MAX_LINE_LENGTH
+            && line.starts_with(":tfpk PRIVMSG #channel :")
+            && !line.ends_with(' ')));
+        assert_eq!(
+            lines
+                .iter()
+                .map(|line| line.trim_start_matches(":tfpk PRIVMSG #channel :"))
+                .collect::<Vec<_>>()
+                .join(" "),
+            message
+        );
+        assert_eq!(
+            reply.to_string(),
+            lines
+                .iter()
+                .map(|line| format!("{line}\r\n"))
+                .collect::<String>()
+        );
+    }
+
+    #[test]
+    fn test_long_notice_is_split() {
+        let message = "word ".repeat(300).trim_end().to_string();
+        let reply = Reply::Notice(PrivReply {
+            message: PrivMsg {
+                target: Target::User(Nick("tom".to_string())),
+                message: message.clone(),
+            },
+            sender_nick: Nick("tfpk".to_string()),
+        });
+
+        let lines = reply.to_lines();
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(
+            |line| line.len() + 2 <= MAX_LINE_LENGTH && line.starts_with(":tfpk NOTICE tom :")
+        ));
+        assert_eq!(
+            lines
+                .iter()
+                .map(|line| line.trim_start_matches(":tfpk NOTICE tom :"))
+                .collect::<Vec<_>>()
+                .join(" "),
+            message
+        );
+    }
+
+    #[test]
+    fn test_long_plugin_reply_splits_on_char_boundary() {
+        let message = "é".repeat(600);
+        let reply = Reply::Plugin(PluginReply {
+            target: Target::User(Nick("tfpk".to_string())),
+            message: message.clone(),
+        });
+
+        let lines = reply.to_lines();
+        assert!(lines.iter().all(|line| line.len() + 2 <= MAX_LINE_LENGTH));
+        assert_eq!(
+            lines
+                .iter()
+                .map(|line| line.trim_start_matches("PLUGIN tfpk : "))
+                .collect::<String>(),
+            message
+        );
+    }
+
+    #[test]
+    fn test_short_reply_is_single_line() {
+        let reply = Reply::Pong("me".to_string());
+        assert_eq!(reply.to_lines(), vec!["PONG :me".to_string()]);
+        assert_eq!(reply.to_string(), "PONG :me\r\n");
+    }
+
+    #[test]
+    fn test_plugin_verbs_and_triggers() {
+        let remind = PluginName("/remind".to_string());
+        let poll = PluginName("/poll".to_string());
+        let mut commands = CommandTable::default();
+        commands.register_verb("REMIND", &remind).unwrap();
+        commands.register_trigger("!remind", &remind).unwrap();
+
+        assert!(commands.register_verb("REMIND", &poll).is_err());
+        assert!(commands.register_verb("JOIN", &poll).is_err());
+        assert!(commands.register_verb("poll", &poll).is_err());
+        assert!(commands.register_trigger("!remind", &poll).is_err());
+        assert!(commands.register_trigger("poll", &poll).is_err());
+
+        let reminder = PluginMsg {
+            plugin_name: remind.clone(),
+            args: vec!["10".to_string(), "stretch your legs".to_string()],
+        };
+        assert_eq!(
+            UnparsedMessage::from("REMIND 10 :stretch your legs\r\n")
+                .parse(&commands)
+                .unwrap()
+                .message,
+            Message::Plugin(reminder.clone())
+        );
+        assert_eq!(
+            ParsedMessage::try_from("REMIND 10 :stretch your legs\r\n"),
+            Err(ErrorType::UnknownCommand)
+        );
+
+        let mut priv_msg = PrivMsg {
+            target: Target::Channel(Channel("#office".to_string())),
+            message: "!remind 10 :stretch your legs".to_string(),
+        };
+        assert_eq!(commands.trigger(&priv_msg), Some(reminder));
+
+        priv_msg.target = Target::User(Nick("tom".to_string()));
+        assert_eq!(commands.trigger(&priv_msg), None);
+    }
+
+    #[test]
+    fn test_user() {
+        assert_eq!(
+            ParsedMessage::try_from("USER tom 0 * :Tom Thumb\r\n")
+                .unwrap()
+                .message,
+            Message::User(UserMsg {
+                username: "tom".to_string(),
+                real_name: "Tom Thumb".to_string(),
+            })
+        );
+        assert_eq!(
+            ParsedMessage::try_from("USER tom 0 *\r\n"),
+            Err(ErrorType::NeedMoreParams)
+        );
+    }
+
+    #[test]
+    fn test_plugin_admin() {
+        assert_eq!(
+            ParsedMessage::try_from("PLUGIN RELOAD /remind\r\n")
+                .unwrap()
+                .message,
+            Message::PluginAdmin(PluginAdminMsg::Reload(PluginName("/remind".to_string())))
+        );
+        assert_eq!(
+            ParsedMessage::try_from("PLUGIN LOAD :/plugins/my remind.so\r\n")
+                .unwrap()
+                .message,
+            Message::PluginAdmin(PluginAdminMsg::Load("/plugins/my remind.so".to_string()))
+        );
+        assert_eq!(
+            ParsedMessage::try_from("PLUGIN UNLOAD\r\n"),
+            Err(ErrorType::NeedMoreParams)
+        );
+    }
+
+    #[test]
+    fn test_version_reply() {
+        assert_eq!(
+            ParsedMessage::try_from("VERSION\r\n").unwrap().message,
+            Message::Version
+        );
+
+        let reply = Reply::Version(VersionReply {
+            target_nick: Nick("tfpk".to_string()),
+            version: "iris-0.1.0".to_string(),
+            comments: "Plugins: /remind 0.1.0".to_string(),
+        });
+        assert_eq!(
+            reply.to_string(),
+            ":iris-server 351 tfpk iris-0.1.0 iris-server :Plugins: /remind 0.1.0\r\n"
+        );
     }
 }
@@ -0,0 +1,206 @@
+//! # Connection benchmark
+//! Launches the server, then opens an increasing number of registered, idle connections to it,
+//! reporting how many it held and how much memory (and how many threads) each one costs.
+//! Each count is run against the server, then against a baseline server with a reader and a writer
+//! thread for every connection, as the server had before it was driven by a reactor.
+//!
+//! Run with: `cargo bench --bench connections`
+//! The connection counts can be overridden with `IRIS_BENCH_CONNECTIONS=1000,5000`.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const IP_ADDR: &str = "127.0.0.1";
+const FIRST_PORT: u16 = 7100;
+/// Set to the port to listen on when the bench runs itself as the baseline server
+const BASELINE_PORT_VAR: &str = "IRIS_BENCH_BASELINE_PORT";
+
+#[derive(Clone, Copy)]
+enum Server {
+    Reactor,
+    Baseline,
+}
+
+impl Server {
+    fn name(self) -> &'static str {
+        match self {
+            Server::Reactor => "reactor",
+            Server::Baseline => "threads",
+        }
+    }
+}
+
+struct ProcessStats {
+    rss_kb: u64,
+    threads: u64,
+}
+
+fn process_stats(pid: u32) -> ProcessStats {
+    let status = fs::read_to_string(format!("/proc/{pid}/status")).expect("failed to read /proc");
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|value| value.split_whitespace().next())
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0)
+    };
+
+    ProcessStats {
+        rss_kb: field("VmRSS:"),
+        threads: field("Threads:"),
+    }
+}
+
+/// Serves connections the way the server did before the reactor: a thread reads each client's
+/// messages, and another writes its replies. Registration is all it answers, which is all the bench needs.
+fn serve_baseline(port: u16) {
+    let listener = TcpListener::bind((IP_ADDR, port)).expect("failed to bind baseline server");
+
+    for stream in listener.incoming().flatten() {
+        let Ok(mut writer) = stream.try_clone() else {
+            continue;
+        };
+        let (replies, queued) = mpsc::channel::<String>();
+
+        thread::spawn(move || {
+            for reply in queued {
+                if writer.write_all(reply.as_bytes()).is_err() {
+                    break;
+                }
+            }
+        });
+        thread::spawn(move || {
+            let mut nick = None;
+            for line in BufReader::new(stream).lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+
+                if let Some(name) = line.strip_prefix("NICK ") {
+                    nick = Some(name.to_string());
+                } else if let (Some(nick), Some((_, real_name))) = (
+                    &nick,
+                    line.strip_prefix("USER ")
+                        .and_then(|user| user.split_once(':')),
+                ) {
+                    let welcome =
+                        format!(":iris-server 001 {nick} :Hi {real_name}, welcome to IRC\r\n");
+                    let _ = replies.send(welcome);
+                }
+            }
+        });
+    }
+}
+
+fn launch_server(server: Server, port: u16) -> Child {
+    let mut command = match server {
+        Server::Reactor => {
+            let mut command = Command::new(env!("CARGO_BIN_EXE_iris"));
+            command.args([IP_ADDR, &port.to_string()]);
+            command
+        }
+        Server::Baseline => {
+            let mut command =
+                Command::new(std::env::current_exe().expect("failed to find the bench"));
+            command.env(BASELINE_PORT_VAR, port.to_string());
+            command
+        }
+    };
+    let server = command
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to launch server");
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect((IP_ADDR, port)).is_err() {
+        assert!(Instant::now() < deadline, "server did not start");
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    server
+}
+
+/// Opens a connection and waits for its welcome, returning None if the server could not take it.
+fn register(port: u16, id: usize) -> Option<TcpStream> {
+    let mut stream = TcpStream::connect((IP_ADDR, port)).ok()?;
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .ok()?;
+    stream
+        .write_all(format!("NICK u{id}\r\nUSER ignored ignored ignored :Bench User\r\n").as_bytes())
+        .ok()?;
+
+    let mut welcome = String::new();
+    BufReader::new(&stream).read_line(&mut welcome).ok()?;
+    welcome.contains(" 001 ").then_some(stream)
+}
+
+fn bench(server: Server, port: u16, target: usize) {
+    let name = server.name();
+    let mut server = launch_server(server, port);
+    // Give the server a moment to settle after the startup probe
+    thread::sleep(Duration::from_millis(200));
+    let before = process_stats(server.id());
+
+    let start = Instant::now();
+    let connections = (0..target)
+        .map_while(|id| register(port, id))
+        .collect::<Vec<_>>();
+    let elapsed = start.elapsed();
+
+    let after = process_stats(server.id());
+    let held = connections.len();
+    let per_connection_kb =
+        (after.rss_kb.saturating_sub(before.rss_kb)) as f64 / held.max(1) as f64;
+
+    println!(
+        "{name:>8} {target:>7} {held:>7} {:>10} {:>10} {per_connection_kb:>10.1} {:>8} {:>9.2}",
+        before.rss_kb,
+        after.rss_kb,
+        after.threads,
+        elapsed.as_secs_f64(),
+    );
+
+    drop(connections);
+    let _ = server.kill();
+    let _ = server.wait();
+}
+
+fn main() {
+    if let Some(port) = std::env::var(BASELINE_PORT_VAR)
+        .ok()
+        .and_then(|port| port.parse().ok())
+    {
+        serve_baseline(port);
+        return;
+    }
+
+    let targets = std::env::var("IRIS_BENCH_CONNECTIONS")
+        .ok()
+        .map(|targets| {
+            targets
+                .split(',')
+                .filter_map(|target| target.trim().parse().ok())
+                .collect::<Vec<usize>>()
+        })
+        .unwrap_or_else(|| vec![100, 1000, 4000]);
+
+    println!(
+        "{:>8} {:>7} {:>7} {:>10} {:>10} {:>10} {:>8} {:>9}",
+        "server", "target", "held", "rss0 (kB)", "rss (kB)", "kB/conn", "threads", "secs"
+    );
+    let mut port = FIRST_PORT;
+    for target in targets {
+        for server in [Server::Reactor, Server::Baseline] {
+            bench(server, port, target);
+            port += 1;
+        }
+    }
+}
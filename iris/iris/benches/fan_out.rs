@@ -0,0 +1,79 @@
+//! # Fan-out benchmark
+//! Measures how long it takes to deliver one message to every member of a large channel.
+//! Each member's writer keeps what it is handed, like a real outbound queue would.
+//! The message is delivered as the server does, serialized once and shared between every member,
+//! then as a baseline which serializes it again for each member, as the server did before.
+//!
+//! Run with: `cargo bench --bench fan_out`
+
+use common::connect::{ConnectionError, SharedMessage};
+use common::types::{Channel, Nick};
+use iris::user_connections::{ClientWriter, UserConnections};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+const MEMBERS: usize = 10_000;
+const ROUNDS: usize = 200;
+
+#[derive(Clone, Default)]
+struct QueueWriter {
+    queue: Arc<Mutex<Vec<SharedMessage>>>,
+}
+
+impl ClientWriter for QueueWriter {
+    fn write_shared(&self, message: &SharedMessage) -> Result<(), ConnectionError> {
+        self.queue.lock().unwrap().push(message.clone());
+        Ok(())
+    }
+}
+
+fn main() {
+    let user_connections = UserConnections::<QueueWriter>::new();
+    let channel = Channel("#everyone".to_string());
+    let writers = (0..MEMBERS)
+        .map(|id| {
+            let nick = Nick(format!("u{id}"));
+            let writer = QueueWriter::default();
+            user_connections.add_user(&nick, writer.clone()).unwrap();
+            user_connections
+                .add_user_to_channel(&nick, &channel)
+                .unwrap();
+            writer
+        })
+        .collect::<Vec<_>>();
+
+    let message = format!(":u0 PRIVMSG #everyone :{}", "a".repeat(400));
+    let drain = || {
+        // Drain the queues, as the reactor would after flushing them
+        for writer in writers.iter() {
+            writer.queue.lock().unwrap().clear();
+        }
+    };
+
+    bench("shared", || {
+        user_connections
+            .write_to_channel(&channel, &message)
+            .unwrap();
+        drain();
+    });
+    bench("baseline", || {
+        for writer in writers.iter() {
+            let line = SharedMessage::from(format!("{message}\r\n").into_bytes());
+            writer.write_shared(&line).unwrap();
+        }
+        drain();
+    });
+}
+
+fn bench(name: &str, mut deliver: impl FnMut()) {
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        deliver();
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "{name:>8}: fan-out to {MEMBERS} members: {:.3} ms per message ({ROUNDS} rounds)",
+        elapsed.as_secs_f64() * 1000.0 / ROUNDS as f64
+    );
+}
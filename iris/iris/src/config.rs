@@ -0,0 +1,356 @@
+//! # Server configuration
+//! Settings shared by every session, parsed from the command line.
+
+use anyhow::anyhow;
+use clap::Args;
+use common::types::PluginName;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const DEFAULT_FLOOD_BURST: u32 = 20;
+const DEFAULT_FLOOD_RATE: f64 = 2.0;
+const DEFAULT_FLOOD_GRACE_MS: u64 = 2_000;
+const DEFAULT_SEND_QUEUE_LIMIT: usize = 64 * 1024;
+const DEFAULT_QUIT_FLUSH_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_PLUGIN_WORKERS: usize = 8;
+const DEFAULT_PLUGIN_CONCURRENCY: usize = 4;
+const DEFAULT_PLUGIN_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_INTERCEPTOR_TIMEOUT_MS: u64 = 500;
+const DEFAULT_PLUGIN_MAX_FAILURES: usize = 3;
+const DEFAULT_DATA_DIR: &str = "iris-data";
+const DEFAULT_WASM_FUEL: u64 = 10_000_000;
+const DEFAULT_WASM_MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+const DEFAULT_SCRIPT_OPERATIONS: u64 = 1_000_000;
+const DEFAULT_SCRIPT_STRING_SIZE: usize = 1024 * 1024;
+const DEFAULT_SCRIPT_COLLECTION_SIZE: usize = 100_000;
+const DEFAULT_SCRIPT_CALL_LEVELS: usize = 64;
+
+/// What a WebAssembly plugin may ask of the host. None are granted unless given with `--wasm-grant`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// `send`, `join`, `part`, `schedule` and `cancel`
+    Messages,
+    /// `channels`, `channel`, `members` and `user`
+    Queries,
+    /// The plugin's own store, which is the only way it can keep anything on disk
+    Store,
+}
+
+impl FromStr for Capability {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "messages" => Ok(Capability::Messages),
+            "queries" => Ok(Capability::Queries),
+            "store" => Ok(Capability::Store),
+            _ => Err(format!(
+                "`{value}` is not a capability, expected `messages`, `queries` or `store`"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Capability::Messages => "messages",
+            Capability::Queries => "queries",
+            Capability::Store => "store",
+        })
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ServerConfig {
+    /// Paths to the plugins to load: native libraries, `.wasm` modules, `.rhai` scripts,
+    /// or programs to run as process plugins
+    #[clap(long)]
+    pub plugins: Vec<String>,
+
+    /// Operator credentials, given as `name:password`.
+    /// These can be seen by anyone who lists the server's process, so prefer `--operator-file`
+    #[clap(long = "operator", value_parser = parse_operator)]
+    pub operators: Vec<(String, String)>,
+
+    /// A file of operator credentials, with one `name:password` on each line.
+    /// Blank lines and lines starting with `#` are ignored
+    #[clap(long)]
+    pub operator_file: Option<PathBuf>,
+
+    /// The number of messages a client may send in a burst before being rate limited
+    #[clap(long, default_value_t = DEFAULT_FLOOD_BURST)]
+    pub flood_burst: u32,
+
+    /// The number of messages per second a client may sustainably send
+    #[clap(long, default_value_t = DEFAULT_FLOOD_RATE)]
+    pub flood_rate: f64,
+
+    /// The number of milliseconds a client may keep sending over the limit, by up to the burst size,
+    /// before being disconnected
+    #[clap(long = "flood-grace", default_value_t = DEFAULT_FLOOD_GRACE_MS)]
+    pub flood_grace_ms: u64,
+
+    /// The number of bytes which may be queued for a client before they are disconnected
+    #[clap(long, default_value_t = DEFAULT_SEND_QUEUE_LIMIT)]
+    pub send_queue_limit: usize,
+
+    /// The number of milliseconds a client which has quit is given to take the rest of what was queued for it,
+    /// after which it is disconnected anyway
+    #[clap(long = "quit-flush-timeout", default_value_t = DEFAULT_QUIT_FLUSH_TIMEOUT_MS)]
+    pub quit_flush_timeout_ms: u64,
+
+    /// The number of worker threads shared by every plugin call
+    #[clap(long, default_value_t = DEFAULT_PLUGIN_WORKERS)]
+    pub plugin_workers: usize,
+
+    /// The number of calls to a single plugin which may run at once
+    #[clap(long, default_value_t = DEFAULT_PLUGIN_CONCURRENCY)]
+    pub plugin_concurrency: usize,
+
+    /// Overrides the concurrency limit of a single plugin, given as `/name=limit`
+    #[clap(long = "plugin-limit", value_parser = parse_plugin_limit)]
+    pub plugin_limits: Vec<(PluginName, usize)>,
+
+    /// The order interceptors run in, such as `/filter,/rules`.
+    /// Interceptors which are not named run afterwards, in name order
+    #[clap(long, value_delimiter = ',', value_parser = parse_plugin_name)]
+    pub interceptor_order: Vec<PluginName>,
+
+    /// The number of milliseconds the sender's messages wait on an interceptor,
+    /// after which the message goes ahead as it is and the interceptor has failed
+    #[clap(long = "interceptor-timeout", default_value_t = DEFAULT_INTERCEPTOR_TIMEOUT_MS)]
+    pub interceptor_timeout_ms: u64,
+
+    /// The number of milliseconds a plugin call may run for before it counts as a failure
+    #[clap(long = "plugin-timeout", default_value_t = DEFAULT_PLUGIN_TIMEOUT_MS)]
+    pub plugin_timeout_ms: u64,
+
+    /// The number of panics and timeouts after which a plugin is disabled
+    #[clap(long, default_value_t = DEFAULT_PLUGIN_MAX_FAILURES)]
+    pub plugin_max_failures: usize,
+
+    /// A setting handed to a plugin when it is initialised, given as `/name:key=value`
+    #[clap(long = "plugin-setting", value_parser = parse_plugin_setting)]
+    pub plugin_settings: Vec<(PluginName, String, String)>,
+
+    /// The directory plugins keep their stores in, with a directory of its own for each plugin
+    #[clap(long, default_value = DEFAULT_DATA_DIR)]
+    pub data_dir: PathBuf,
+
+    /// The fuel a WebAssembly plugin may burn in a single call, roughly one unit per instruction
+    #[clap(long, default_value_t = DEFAULT_WASM_FUEL)]
+    pub wasm_fuel: u64,
+
+    /// The number of bytes of memory a WebAssembly plugin may use
+    #[clap(long, default_value_t = DEFAULT_WASM_MEMORY_LIMIT)]
+    pub wasm_memory_limit: usize,
+
+    /// Grants a WebAssembly plugin a capability, given as `/name:capability`,
+    /// where the capability is `messages`, `queries` or `store`
+    #[clap(long = "wasm-grant", value_parser = parse_wasm_grant)]
+    pub wasm_grants: Vec<(PluginName, Capability)>,
+
+    /// The number of operations a script plugin may run in a single call
+    #[clap(long, default_value_t = DEFAULT_SCRIPT_OPERATIONS)]
+    pub script_operations: u64,
+
+    /// The longest string, in bytes, a script plugin may build
+    #[clap(long, default_value_t = DEFAULT_SCRIPT_STRING_SIZE)]
+    pub script_string_size: usize,
+
+    /// The most items an array in a script plugin may hold
+    #[clap(long, default_value_t = DEFAULT_SCRIPT_COLLECTION_SIZE)]
+    pub script_array_size: usize,
+
+    /// The most entries a map in a script plugin may hold
+    #[clap(long, default_value_t = DEFAULT_SCRIPT_COLLECTION_SIZE)]
+    pub script_map_size: usize,
+
+    /// How deeply a script plugin's functions may call one another
+    #[clap(long, default_value_t = DEFAULT_SCRIPT_CALL_LEVELS)]
+    pub script_call_levels: usize,
+}
+
+impl ServerConfig {
+    /// Adds the operators listed in `--operator-file`, if one was given.
+    pub fn read_operator_file(&mut self) -> anyhow::Result<()> {
+        let Some(path) = &self.operator_file else {
+            return Ok(());
+        };
+        let operators = fs::read_to_string(path)
+            .map_err(|err| anyhow!("Failed to read {}: {err}", path.display()))?;
+
+        for line in operators.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let operator = parse_operator(line).map_err(|_| {
+                anyhow!("{}: expected `name:password` on every line", path.display())
+            })?;
+            self.operators.push(operator);
+        }
+
+        Ok(())
+    }
+
+    pub fn is_valid_operator(&self, name: &str, password: &str) -> bool {
+        self.operators
+            .iter()
+            .any(|(oper_name, oper_password)| oper_name == name && oper_password == password)
+    }
+
+    pub fn plugin_concurrency(&self, pl_name: &PluginName) -> usize {
+        self.plugin_limits
+            .iter()
+            .rev()
+            .find(|(name, _)| name == pl_name)
+            .map(|(_, limit)| *limit)
+            .unwrap_or(self.plugin_concurrency)
+    }
+
+    /// The settings for a single plugin, where a key given more than once takes its last value.
+    pub fn plugin_settings(&self, pl_name: &PluginName) -> BTreeMap<String, String> {
+        self.plugin_settings
+            .iter()
+            .filter(|(name, _, _)| name == pl_name)
+            .map(|(_, key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    pub fn wasm_grants(&self, pl_name: &PluginName) -> Vec<Capability> {
+        self.wasm_grants
+            .iter()
+            .filter(|(name, _)| name == pl_name)
+            .map(|(_, capability)| *capability)
+            .collect()
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            plugins: vec![],
+            operators: vec![],
+            operator_file: None,
+            flood_burst: DEFAULT_FLOOD_BURST,
+            flood_rate: DEFAULT_FLOOD_RATE,
+            flood_grace_ms: DEFAULT_FLOOD_GRACE_MS,
+            send_queue_limit: DEFAULT_SEND_QUEUE_LIMIT,
+            quit_flush_timeout_ms: DEFAULT_QUIT_FLUSH_TIMEOUT_MS,
+            plugin_workers: DEFAULT_PLUGIN_WORKERS,
+            plugin_concurrency: DEFAULT_PLUGIN_CONCURRENCY,
+            plugin_limits: vec![],
+            interceptor_order: vec![],
+            interceptor_timeout_ms: DEFAULT_INTERCEPTOR_TIMEOUT_MS,
+            plugin_timeout_ms: DEFAULT_PLUGIN_TIMEOUT_MS,
+            plugin_max_failures: DEFAULT_PLUGIN_MAX_FAILURES,
+            plugin_settings: vec![],
+            data_dir: PathBuf::from(DEFAULT_DATA_DIR),
+            wasm_fuel: DEFAULT_WASM_FUEL,
+            wasm_memory_limit: DEFAULT_WASM_MEMORY_LIMIT,
+            wasm_grants: vec![],
+            script_operations: DEFAULT_SCRIPT_OPERATIONS,
+            script_string_size: DEFAULT_SCRIPT_STRING_SIZE,
+            script_array_size: DEFAULT_SCRIPT_COLLECTION_SIZE,
+            script_map_size: DEFAULT_SCRIPT_COLLECTION_SIZE,
+            script_call_levels: DEFAULT_SCRIPT_CALL_LEVELS,
+        }
+    }
+}
+
+fn parse_operator(value: &str) -> Result<(String, String), String> {
+    value
+        .split_once(':')
+        .map(|(name, password)| (name.to_string(), password.to_string()))
+        .ok_or_else(|| format!("expected `name:password`, got `{value}`"))
+}
+
+fn parse_plugin_name(value: &str) -> Result<PluginName, String> {
+    PluginName::try_from(value.to_string())
+        .map_err(|_| format!("`{value}` is not a valid plugin name"))
+}
+
+fn parse_plugin_limit(value: &str) -> Result<(PluginName, usize), String> {
+    let (name, limit) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected `/name=limit`, got `{value}`"))?;
+    let name = parse_plugin_name(name)?;
+    let limit = limit
+        .parse()
+        .map_err(|_| format!("`{limit}` is not a valid limit"))?;
+
+    Ok((name, limit))
+}
+
+fn parse_plugin_setting(value: &str) -> Result<(PluginName, String, String), String> {
+    let (name, setting) = value
+        .split_once(':')
+        .ok_or_else(|| format!("expected `/name:key=value`, got `{value}`"))?;
+    let (key, setting) = setting
+        .split_once('=')
+        .ok_or_else(|| format!("expected `/name:key=value`, got `{value}`"))?;
+
+    Ok((
+        parse_plugin_name(name)?,
+        key.to_string(),
+        setting.to_string(),
+    ))
+}
+
+fn parse_wasm_grant(value: &str) -> Result<(PluginName, Capability), String> {
+    let (name, capability) = value
+        .split_once(':')
+        .ok_or_else(|| format!("expected `/name:capability`, got `{value}`"))?;
+
+    Ok((parse_plugin_name(name)?, capability.parse()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plugin_settings_are_parsed() {
+        let remind = PluginName("/remind".to_string());
+        assert_eq!(
+            parse_plugin_setting("/remind:max_interval=86400"),
+            Ok((
+                remind.clone(),
+                "max_interval".to_string(),
+                "86400".to_string()
+            ))
+        );
+        // Only the first `=` splits the key from the value
+        assert_eq!(
+            parse_plugin_setting("/remind:greeting=a=b"),
+            Ok((remind.clone(), "greeting".to_string(), "a=b".to_string()))
+        );
+
+        assert!(parse_plugin_setting("/remind").is_err());
+        assert!(parse_plugin_setting("/remind:max_interval").is_err());
+        assert!(parse_plugin_setting("remind:max_interval=1").is_err());
+    }
+
+    #[test]
+    fn test_settings_are_only_handed_to_their_plugin() {
+        let (remind, echo) = (
+            PluginName("/remind".to_string()),
+            PluginName("/echo".to_string()),
+        );
+        let config = ServerConfig {
+            plugin_settings: vec![
+                (remind.clone(), "max_interval".to_string(), "60".to_string()),
+                (echo.clone(), "prefix".to_string(), ">".to_string()),
+            ],
+            ..ServerConfig::default()
+        };
+
+        let settings = config.plugin_settings(&remind);
+        assert_eq!(settings.len(), 1);
+        assert_eq!(settings["max_interval"], "60");
+    }
+}
@@ -0,0 +1,161 @@
+//! # Flood control
+//! A token bucket limiting how quickly a single connection may send messages.
+//! Each message takes a token, and tokens refill at a constant rate up to the burst size.
+//! A client which runs out may overdraw the bucket for a short while, by up to the burst size,
+//! so a brief spike over the limit is let through and only a sustained flood is refused.
+//! Once that while is up, a client which has slowed down to the refill rate may carry on as it pays back
+//! the overdraft, and only one still sending faster than that is refused.
+
+use std::time::{Duration, Instant};
+
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+    /// How long the bucket may stay overdrawn
+    grace: Duration,
+    /// When the bucket was first overdrawn, if it has not been paid back since
+    overdrawn_since: Option<Instant>,
+}
+
+impl TokenBucket {
+    pub fn new(burst: u32, refill_per_sec: f64, grace: Duration, now: Instant) -> TokenBucket {
+        TokenBucket {
+            capacity: burst as f64,
+            tokens: burst as f64,
+            refill_per_sec,
+            last_refill: now,
+            grace,
+            overdrawn_since: None,
+        }
+    }
+
+    /// Attempts to take a token for a message received at `now`.
+    /// Returns false if the message would overdraw the bucket by more than the burst size,
+    /// or if the bucket has been overdrawn for longer than the grace period and less than a token
+    /// has refilled since the last message.
+    pub fn try_acquire(&mut self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        let refilled = elapsed * self.refill_per_sec;
+        self.tokens = (self.tokens + refilled).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.overdrawn_since = None;
+            self.tokens -= 1.0;
+            return true;
+        }
+
+        let overdrawn_since = *self.overdrawn_since.get_or_insert(now);
+        // Past the grace period the overdraft may not grow, but a message which costs no more than
+        // has refilled since the last one leaves it where it was
+        let in_grace = now.saturating_duration_since(overdrawn_since) < self.grace;
+        if (in_grace || refilled >= 1.0) && self.tokens - 1.0 >= -self.capacity {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_then_empty() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(3, 1.0, Duration::ZERO, now);
+
+        assert!(bucket.try_acquire(now));
+        assert!(bucket.try_acquire(now));
+        assert!(bucket.try_acquire(now));
+        assert!(!bucket.try_acquire(now));
+    }
+
+    #[test]
+    fn test_refill_is_capped_at_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(2, 1.0, Duration::ZERO, now);
+
+        assert!(bucket.try_acquire(now));
+        assert!(bucket.try_acquire(now));
+        assert!(!bucket.try_acquire(now));
+
+        let later = now + Duration::from_millis(1500);
+        assert!(bucket.try_acquire(later));
+        assert!(!bucket.try_acquire(later));
+
+        let much_later = later + Duration::from_secs(60);
+        assert!(bucket.try_acquire(much_later));
+        assert!(bucket.try_acquire(much_later));
+        assert!(!bucket.try_acquire(much_later));
+    }
+
+    #[test]
+    fn test_overdraft_is_limited_to_the_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(2, 1.0, Duration::from_secs(5), now);
+
+        // Two messages from the bucket, then two more on overdraft
+        for _ in 0..4 {
+            assert!(bucket.try_acquire(now));
+        }
+        assert!(!bucket.try_acquire(now));
+    }
+
+    #[test]
+    fn test_overdraft_ends_after_the_grace_period() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(2, 0.1, Duration::from_secs(1), now);
+
+        assert!(bucket.try_acquire(now));
+        assert!(bucket.try_acquire(now));
+        assert!(bucket.try_acquire(now));
+        assert!(!bucket.try_acquire(now + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_overdraft_must_be_paid_back() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(2, 1.0, Duration::from_secs(1), now);
+
+        for _ in 0..4 {
+            assert!(bucket.try_acquire(now));
+        }
+
+        // The grace period is over, but a client which has slowed down to the refill rate
+        // spends each token as it refills, so it may carry on while the overdraft is outstanding
+        let mut later = now;
+        for _ in 0..10 {
+            later += Duration::from_secs(1);
+            assert!(bucket.try_acquire(later));
+        }
+
+        // One which speeds up again before paying it back is refused
+        assert!(!bucket.try_acquire(later + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_paying_back_the_overdraft_starts_a_new_grace_period() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(2, 1.0, Duration::from_secs(1), now);
+
+        for _ in 0..4 {
+            assert!(bucket.try_acquire(now));
+        }
+
+        // Three seconds refills the two tokens owed and one more, which pays the overdraft back
+        let later = now + Duration::from_secs(3);
+        assert!(bucket.try_acquire(later));
+
+        // So the next spike is let through under a grace period of its own
+        assert!(bucket.try_acquire(later));
+        assert!(bucket.try_acquire(later));
+        assert!(!bucket.try_acquire(later));
+    }
+}
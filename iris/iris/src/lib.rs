@@ -0,0 +1,21 @@
+//! # IRIS
+//! The building blocks of the server, shared by the `iris` binary and its benchmarks.
+
+#[macro_use]
+extern crate log;
+
+pub mod config;
+pub mod flood_control;
+pub mod message_handler;
+pub mod plugin_handler;
+pub mod plugin_host;
+pub mod plugin_process;
+pub mod plugin_protocol;
+pub mod plugin_runtime;
+pub mod plugin_script;
+pub mod plugin_store;
+pub mod plugin_supervisor;
+pub mod plugin_wasm;
+pub mod timer_wheel;
+pub mod user_connections;
+pub mod worker_pool;
@@ -2,66 +2,145 @@
 extern crate log;
 extern crate simplelog;
 
-mod message_handler;
-mod plugin_handler;
-mod user_connections;
-
-use crate::{message_handler::MessageHandler, user_connections::UserConnections};
 use anyhow::anyhow;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use common::plugin::{load_plugin, PLUGIN_API_VERSION};
 use common::{connect::ConnectionManager, types::SERVER_NAME};
+use iris::{
+    config::ServerConfig,
+    message_handler::MessageHandler,
+    plugin_handler::PluginHandler,
+    plugin_runtime::{self, PluginRuntime},
+    timer_wheel::TimerWheel,
+    user_connections::UserConnections,
+};
 use simplelog::*;
 use std::net::IpAddr;
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Parser)]
+#[clap(args_conflicts_with_subcommands = true)]
 struct Arguments {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     #[clap(default_value = "127.0.0.1")]
     ip_address: IpAddr,
 
     #[clap(default_value = "6991")]
     port: u16,
 
-    #[clap(long)]
-    plugins: Vec<String>,
+    #[clap(flatten)]
+    config: ServerConfig,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Loads a plugin library and reports on it, without starting the server.
+    /// Only native libraries can be checked, as they are the only plugins built against the plugin API
+    CheckPlugin { path: PathBuf },
 }
 
 fn main() {
     let arguments = Arguments::parse();
-    begin_server(&arguments.ip_address, arguments.port, &arguments.plugins);
+
+    if let Some(Command::CheckPlugin { path }) = arguments.command {
+        if let Err(err) = check_plugin(&path) {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Err(err) = begin_server(&arguments.ip_address, arguments.port, arguments.config) {
+        error!("Failed to start server: {err}");
+        std::process::exit(1);
+    }
+}
+
+/// Prints what the plugin says about itself, the plugin API it was built against,
+/// and the optional hooks it leaves out, failing if it could not be loaded by the server.
+fn check_plugin(path: &Path) -> anyhow::Result<()> {
+    print!("{}", plugin_report(path)?);
+    Ok(())
+}
+
+/// What `check-plugin` says about the plugin library at `path`, a line at a time.
+fn plugin_report(path: &Path) -> anyhow::Result<String> {
+    if !plugin_runtime::is_library(path) {
+        return Err(anyhow!(
+            "{}: only native libraries can be checked, as other plugins are not built against the plugin API",
+            path.display()
+        ));
+    }
+    let plugin = load_plugin(path).map_err(|err| anyhow!("{}: {err}", path.display()))?;
+    let metadata = plugin.metadata();
+    let built_against = plugin.built_against();
+
+    let mut report = format!("Name: {}\n", plugin.name());
+    report += &format!(
+        "Version: {}\n",
+        metadata.version.as_deref().unwrap_or("not given")
+    );
+    report +=
+        &format!("Plugin API: {built_against} (this server supports up to {PLUGIN_API_VERSION})\n");
+    report += "ABI: compatible\n";
+
+    let missing = plugin
+        .optional_fields()
+        .into_iter()
+        .filter_map(|field| match field.provided {
+            Some(true) => None,
+            Some(false) => Some(field.name.to_string()),
+            None => Some(format!(
+                "{} (added in plugin API {})",
+                field.name, field.since
+            )),
+        })
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        report += "Missing optional hooks: none\n";
+    } else {
+        report += &format!("Missing optional hooks: {}\n", missing.join(", "));
+    }
+
+    Ok(report)
 }
 
-fn begin_server(ip_address: &IpAddr, port: u16, plugins: &[String]) {
+fn begin_server(ip_address: &IpAddr, port: u16, mut config: ServerConfig) -> anyhow::Result<()> {
     let _ = SimpleLogger::init(LevelFilter::Info, Config::default());
+    config.read_operator_file()?;
 
     info!("Launching {} at {}:{}", SERVER_NAME, ip_address, port,);
 
-    let mut connection_manager = ConnectionManager::launch(*ip_address, port);
-    let user_connections = Arc::new(Mutex::new(UserConnections::new()));
+    let user_connections = Arc::new(UserConnections::new());
+    // Plugins are loaded once, and shared by every session
+    // One timer wheel powers every delayed and recurring plugin delivery
+    let timers = TimerWheel::start();
+    let plugin_handler = Arc::new(PluginHandler::new(
+        &config,
+        user_connections.clone(),
+        timers,
+    )?);
+    // Script plugins are reloaded when they are edited
+    plugin_handler.watch();
+    let config = Arc::new(config);
 
-    thread::scope(|s| {
-        loop {
-            // This function call will block until a new client connects!
-            let (mut conn_read, conn_write) = connection_manager.accept_new_connection();
-            let thread_user_connections = user_connections.clone();
-            let thread_plugin_list = plugins.to_vec();
-            info!("New connection from {}", conn_read.id());
-
-            s.spawn(move || {
-                let mut handler =
-                    MessageHandler::new(&thread_user_connections, conn_write, thread_plugin_list);
-                while !handler.has_quit() {
-                    info!("Waiting for message...");
-
-                    let message = conn_read.read_message();
-                    handler.handle(message.map_err(|e| anyhow!(e)));
-                }
+    let connection_manager = ConnectionManager::launch(
+        *ip_address,
+        port,
+        config.send_queue_limit,
+        Duration::from_millis(config.quit_flush_timeout_ms),
+    );
 
-                info!("Connection has closed...");
-            });
-        }
+    // The reactor drives every session's handler as messages arrive
+    connection_manager.serve(|conn_write| {
+        MessageHandler::new(&user_connections, conn_write, &plugin_handler, &config)
     });
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -69,15 +148,19 @@ mod tests {
     #[allow(unused_imports)]
     use super::*;
     use common::irc_client::IrcClient;
-    use std::{net::Ipv4Addr, time::Duration};
+    use common::types::PluginName;
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::{Ipv4Addr, TcpStream};
+    use std::os::unix::fs::PermissionsExt;
+    use std::thread;
+    use std::time::Duration;
 
     static IP_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
     const PORT: u16 = 6991;
-    static PLUGINS: Vec<String> = vec![];
 
     #[test]
     fn test_flow() {
-        let mut client = initialise_test_rig();
+        let mut client = initialise_test_rig(PORT, ServerConfig::default());
 
         // Error handling in nicknames (and ignoring other commands)
         client.send_message("PING :me");
@@ -112,6 +195,21 @@ mod tests {
             client.get_message().unwrap()
         );
 
+        // Notices are delivered, over as many lines as they need, but never answered with an error.
+        // This one only just fits in a message, so it needs two lines once the sender is added
+        let notice = "word ".repeat(99).trim_end().to_string();
+        client.send_message(&format!("NOTICE wiz :{notice}"));
+        let mut lines = vec![];
+        while lines.join(" ").len() < notice.len() {
+            let line = client.get_message().unwrap();
+            lines.push(line.trim_start_matches(":wiz NOTICE wiz :").to_string());
+        }
+        assert!(lines.len() > 1);
+        assert_eq!(lines.join(" "), notice);
+        client.send_message("NOTICE nobody :hi");
+        client.send_message("PING :me");
+        assert_eq!("PONG :me".to_string(), client.get_message().unwrap());
+
         // Channels
         // When you join a channel:
         client.send_message("JOIN #channel");
@@ -133,15 +231,746 @@ mod tests {
         assert_eq!("PONG :me".to_string(), client.get_message().unwrap());
     }
 
-    fn initialise_test_rig() -> IrcClient {
-        thread::spawn(|| {
-            begin_server(&IP_ADDR, PORT, &PLUGINS);
+    #[test]
+    fn test_excess_flood() {
+        let mut client = initialise_test_rig(
+            PORT + 1,
+            ServerConfig {
+                flood_burst: 5,
+                flood_rate: 0.1,
+                flood_grace_ms: 60_000,
+                ..ServerConfig::default()
+            },
+        );
+
+        client.send_message("NICK flooder");
+        client.send_message("USER ignored ignored ignored :Flood Er");
+        assert_eq!(
+            ":iris-server 001 flooder :Hi Flood Er, welcome to IRC",
+            client.get_message().unwrap()
+        );
+
+        // The burst of 5 is spent by the 3rd message, and 5 more may be sent over the limit
+        for _ in 0..9 {
+            client.send_message("PRIVMSG flooder :spam");
+        }
+        for _ in 0..8 {
+            assert_eq!(
+                ":flooder PRIVMSG flooder :spam".to_string(),
+                client.get_message().unwrap()
+            );
+        }
+        assert_eq!(
+            "ERROR :Excess Flood".to_string(),
+            client.get_message().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_operators_are_exempt_from_flood_control() {
+        let mut client = initialise_test_rig(
+            PORT + 2,
+            ServerConfig {
+                operators: vec![("admin".to_string(), "hunter2".to_string())],
+                flood_burst: 5,
+                flood_rate: 0.1,
+                ..ServerConfig::default()
+            },
+        );
+
+        client.send_message("NICK admin");
+        client.send_message("USER ignored ignored ignored :The Admin");
+        assert_eq!(
+            ":iris-server 001 admin :Hi The Admin, welcome to IRC",
+            client.get_message().unwrap()
+        );
+        client.send_message("OPER admin hunter2");
+        assert_eq!(
+            ":iris-server 381 admin :You are now an IRC operator",
+            client.get_message().unwrap()
+        );
+
+        for _ in 0..10 {
+            client.send_message("PING :me");
+            assert_eq!("PONG :me".to_string(), client.get_message().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_operators_can_be_read_from_a_file() {
+        let path = std::env::temp_dir().join(format!("iris-operators-{}", std::process::id()));
+        std::fs::write(&path, "# Operators\n\nadmin:hunter2\n").unwrap();
+        let mut client = initialise_test_rig(
+            PORT + 7,
+            ServerConfig {
+                operator_file: Some(path.clone()),
+                ..ServerConfig::default()
+            },
+        );
+
+        client.send_message("NICK admin");
+        client.send_message("USER ignored ignored ignored :The Admin");
+        assert_eq!(
+            ":iris-server 001 admin :Hi The Admin, welcome to IRC",
+            client.get_message().unwrap()
+        );
+        client.send_message("OPER admin hunter2");
+        assert_eq!(
+            ":iris-server 381 admin :You are now an IRC operator",
+            client.get_message().unwrap()
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_peer_notifications_are_delivered_once() {
+        let mut alice = initialise_test_rig(PORT + 3, ServerConfig::default());
+        let mut bob = IrcClient::new(IP_ADDR, PORT + 3);
+
+        for (client, nick) in [(&mut alice, "alice"), (&mut bob, "bob")] {
+            client.send_message(&format!("NICK {nick}"));
+            client.send_message("USER ignored ignored ignored :Some One");
+            assert_eq!(
+                format!(":iris-server 001 {nick} :Hi Some One, welcome to IRC"),
+                client.get_message().unwrap()
+            );
+
+            for channel in ["#a", "#b", "#c"] {
+                client.send_message(&format!("JOIN {channel}"));
+                assert_eq!(
+                    format!(":{nick} JOIN {channel}"),
+                    client.get_message().unwrap()
+                );
+            }
+        }
+        for channel in ["#a", "#b", "#c"] {
+            assert_eq!(format!(":bob JOIN {channel}"), alice.get_message().unwrap());
+        }
+
+        bob.send_message("NICK robert");
+        assert_eq!(":bob NICK robert", bob.get_message().unwrap());
+        assert_eq!(":bob NICK robert", alice.get_message().unwrap());
+
+        bob.send_message("AWAY :Gone to lunch");
+        assert_eq!(
+            ":iris-server 306 robert :You have been marked as being away",
+            bob.get_message().unwrap()
+        );
+        assert_eq!(":robert AWAY :Gone to lunch", alice.get_message().unwrap());
+
+        alice.send_message("PRIVMSG robert :hello?");
+        assert_eq!(":alice PRIVMSG robert :hello?", bob.get_message().unwrap());
+        assert_eq!(
+            ":iris-server 301 alice robert :Gone to lunch",
+            alice.get_message().unwrap()
+        );
+
+        bob.send_message("QUIT :Bye");
+        assert_eq!(":robert QUIT :Bye", alice.get_message().unwrap());
+
+        // Only one QUIT should have been sent, so the next message is the PONG
+        alice.send_message("PING :me");
+        assert_eq!("PONG :me", alice.get_message().unwrap());
+    }
+
+    #[test]
+    fn test_plugins_message_as_their_service() {
+        let path = std::env::temp_dir().join(format!("iris-echobot-{}.rhai", std::process::id()));
+        std::fs::write(
+            &path,
+            r##"
+                fn describe() { #{name: "/echobot"} }
+                fn init(settings) { join("#lobby"); }
+                fn handle(invocation) {
+                    send(invocation.origin, `you said ${invocation.args}`);
+                    send(#{channel: "#lobby"}, `${invocation.sender} said something`);
+                }
+            "##,
+        )
+        .unwrap();
+        let mut alice = initialise_test_rig(
+            PORT + 8,
+            ServerConfig {
+                plugins: vec![path.display().to_string()],
+                ..ServerConfig::default()
+            },
+        );
+        let mut bob = IrcClient::new(IP_ADDR, PORT + 8);
+
+        alice.send_message("NICK alice");
+        alice.send_message("USER ignored ignored ignored :Alice");
+        assert_eq!(
+            ":iris-server 001 alice :Hi Alice, welcome to IRC",
+            alice.get_message().unwrap()
+        );
+        bob.send_message("NICK bob");
+        bob.send_message("USER ignored ignored ignored :Bob");
+        assert_eq!(
+            ":iris-server 001 bob :Hi Bob, welcome to IRC",
+            bob.get_message().unwrap()
+        );
+        bob.send_message("JOIN #lobby");
+        assert_eq!(":bob JOIN #lobby", bob.get_message().unwrap());
+
+        // Messages to the service reach the plugin, which answers from its service
+        alice.send_message("PRIVMSG echobot :hello there");
+        assert_eq!(
+            ":echobot PRIVMSG alice :you said [\"hello\", \"there\"]",
+            alice.get_message().unwrap()
+        );
+        assert_eq!(
+            ":echobot PRIVMSG #lobby :alice said something",
+            bob.get_message().unwrap()
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_interceptors_run_in_the_configured_order() {
+        let dir = std::env::temp_dir().join(format!("iris-interceptors-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let censor = dir.join("censor.rhai");
+        std::fs::write(
+            &censor,
+            r##"
+                fn describe() { #{name: "/censor"} }
+                fn intercept(event) {
+                    if event.privmsg == () { return (); }
+                    let text = event.privmsg.message;
+                    if text.contains("bogus") { return #{deny: #{numeric: 5, reason: "Bogus"}}; }
+                    if text.contains("spam") { return #{deny: #{numeric: 404, reason: "No spam\r\nQUIT"}}; }
+                    if text.contains("darn") {
+                        text.replace("darn", "****");
+                        return #{modify: text};
+                    }
+                }
+            "##,
+        )
+        .unwrap();
+        let tagger = dir.join("tagger.rhai");
+        std::fs::write(
+            &tagger,
+            r##"
+                fn describe() { #{name: "/tagger"} }
+                fn intercept(event) {
+                    if event.privmsg != () { #{modify: event.privmsg.message + " (darn)\r\n"} }
+                }
+            "##,
+        )
+        .unwrap();
+
+        // By name the censor would go first, and miss what the tagger adds
+        let mut alice = initialise_test_rig(
+            PORT + 10,
+            ServerConfig {
+                plugins: vec![censor.display().to_string(), tagger.display().to_string()],
+                interceptor_order: vec![PluginName::try_from("/tagger".to_string()).unwrap()],
+                ..ServerConfig::default()
+            },
+        );
+        let mut bob = IrcClient::new(IP_ADDR, PORT + 10);
+        for (client, nick) in [(&mut alice, "alice"), (&mut bob, "bob")] {
+            client.send_message(&format!("NICK {nick}"));
+            client.send_message("USER ignored ignored ignored :Some One");
+            assert_eq!(
+                format!(":iris-server 001 {nick} :Hi Some One, welcome to IRC"),
+                client.get_message().unwrap()
+            );
+        }
+
+        // Neither intercepts a JOIN, so it is allowed as it is
+        alice.send_message("JOIN #lobby");
+        assert_eq!(":alice JOIN #lobby", alice.get_message().unwrap());
+
+        // Line breaks are taken out of what the tagger adds, before the censor sees it
+        alice.send_message("PRIVMSG bob :hello");
+        assert_eq!(
+            ":alice PRIVMSG bob :hello (****)",
+            bob.get_message().unwrap()
+        );
+
+        // A notice is put to the interceptors as a private message
+        alice.send_message("NOTICE bob :hello");
+        assert_eq!(
+            ":alice NOTICE bob :hello (****)",
+            bob.get_message().unwrap()
+        );
+
+        alice.send_message("PRIVMSG bob :buy spam");
+        assert_eq!(
+            ":iris-server 404 alice :No spamQUIT",
+            alice.get_message().unwrap()
+        );
+
+        // A denial without a three digit numeric is ignored, leaving the message as the tagger left it
+        alice.send_message("PRIVMSG bob :bogus");
+        assert_eq!(
+            ":alice PRIVMSG bob :bogus (darn)",
+            bob.get_message().unwrap()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_plugins_describe_themselves() {
+        let path = std::env::temp_dir().join(format!("iris-greeter-{}.rhai", std::process::id()));
+        std::fs::write(
+            &path,
+            r##"
+                fn describe() {
+                    #{
+                        name: "/greeter",
+                        version: "1.2.0",
+                        description: "Says hello",
+                        usage: "GREET :name",
+                        author: "Ada",
+                        verbs: ["GREET"],
+                    }
+                }
+            "##,
+        )
+        .unwrap();
+        let mut client = initialise_test_rig(
+            PORT + 11,
+            ServerConfig {
+                plugins: vec![path.display().to_string()],
+                ..ServerConfig::default()
+            },
+        );
+        client.send_message("NICK ada");
+        client.send_message("USER ignored ignored ignored :Ada");
+        assert_eq!(
+            ":iris-server 001 ada :Hi Ada, welcome to IRC",
+            client.get_message().unwrap()
+        );
+
+        client.send_message("PLUGIN /help");
+        assert_eq!(
+            "PLUGIN ada : Loaded plugins (PLUGIN /help /name to find out more):",
+            client.get_message().unwrap()
+        );
+        assert_eq!(
+            "PLUGIN ada : /greeter 1.2.0 - Says hello",
+            client.get_message().unwrap()
+        );
+
+        client.send_message("PLUGIN /help /greeter");
+        for line in [
+            "PLUGIN ada : /greeter 1.2.0 by Ada",
+            "PLUGIN ada : Says hello",
+            "PLUGIN ada : Usage: GREET :name",
+            "PLUGIN ada : Commands: GREET",
+        ] {
+            assert_eq!(line, client.get_message().unwrap());
+        }
+
+        client.send_message("PLUGIN /help /missing");
+        assert_eq!(
+            "PLUGIN ada : Plugin /missing not found",
+            client.get_message().unwrap()
+        );
+
+        client.send_message("VERSION");
+        assert_eq!(
+            format!(
+                ":iris-server 351 ada iris-{} iris-server :Plugins: /greeter 1.2.0",
+                env!("CARGO_PKG_VERSION")
+            ),
+            client.get_message().unwrap()
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_process_plugins_are_started_again_when_they_exit() {
+        let dir = std::env::temp_dir().join(format!("iris-process-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("pyecho.py");
+        std::fs::write(
+            &path,
+            r##"#!/usr/bin/env python3
+import json, os, sys
+
+backlog = []
+
+def write(message):
+    message["jsonrpc"] = "2.0"
+    sys.stdout.write(json.dumps(message) + "\n")
+    sys.stdout.flush()
+
+def host(method, **params):
+    write({"id": 0, "method": method, "params": params})
+    for line in sys.stdin:
+        message = json.loads(line)
+        if "method" in message:
+            backlog.append(message)
+        else:
+            return message.get("result")
+    sys.exit(0)
+
+def handle(invocation):
+    if invocation["args"] == ["crash"]:
+        os._exit(1)
+    if invocation["args"] == ["flood"]:
+        write({"id": 0, "result": "x" * 2 * 1024 * 1024})
+    count = int(host("store_get", key="count") or 0) + 1
+    host("store_set", key="count", value=str(count))
+    return {"target": invocation["origin"], "message": f"{' '.join(invocation['args'])} ({count})"}
+
+def join(params):
+    if params["sender"] != "pyecho":
+        host("send", target={"channel": params["channel"]}, message=f"Hello, {params['sender']}!")
+
+METHODS = {
+    "describe": lambda _: {"name": "/pyecho", "hooks": ["join"]},
+    "init": lambda _: host("join", channel="#lobby"),
+    "handle": handle,
+    "join": join,
+}
+
+for line in sys.stdin:
+    backlog.append(json.loads(line))
+    while backlog:
+        message = backlog.pop(0)
+        result = METHODS.get(message["method"], lambda _: None)(message.get("params"))
+        if "id" in message:
+            write({"id": message["id"], "result": result})
+"##,
+        )
+        .unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut alice = initialise_test_rig(
+            PORT + 12,
+            ServerConfig {
+                plugins: vec![path.display().to_string()],
+                data_dir: dir.join("data"),
+                ..ServerConfig::default()
+            },
+        );
+        let mut bob = IrcClient::new(IP_ADDR, PORT + 12);
+        for (client, nick) in [(&mut alice, "alice"), (&mut bob, "bob")] {
+            client.send_message(&format!("NICK {nick}"));
+            client.send_message("USER ignored ignored ignored :Some One");
+            assert_eq!(
+                format!(":iris-server 001 {nick} :Hi Some One, welcome to IRC"),
+                client.get_message().unwrap()
+            );
+        }
+
+        // The plugin is told of the join, and calls back into the host to greet
+        bob.send_message("JOIN #lobby");
+        assert_eq!(":bob JOIN #lobby", bob.get_message().unwrap());
+        assert_eq!(
+            ":pyecho PRIVMSG #lobby :Hello, bob!",
+            bob.get_message().unwrap()
+        );
+
+        alice.send_message("PRIVMSG pyecho :hi");
+        assert_eq!("PLUGIN alice : hi (1)", alice.get_message().unwrap());
+
+        // A call the process exits during fails, and the process is started and initialised again
+        alice.send_message("PRIVMSG pyecho :crash");
+        assert_eq!(
+            "Plugin (Name: /pyecho) Exception: The plugin exited",
+            alice.get_message().unwrap()
+        );
+        assert_eq!(":pyecho JOIN #lobby", bob.get_message().unwrap());
+        alice.send_message("PRIVMSG pyecho :hi");
+        assert_eq!("PLUGIN alice : hi (2)", alice.get_message().unwrap());
+
+        // So is one writing a line past the limit, which is killed
+        alice.send_message("PRIVMSG pyecho :flood");
+        assert_eq!(
+            "Plugin (Name: /pyecho) Exception: The plugin exited",
+            alice.get_message().unwrap()
+        );
+        assert_eq!(":pyecho JOIN #lobby", bob.get_message().unwrap());
+        alice.send_message("PRIVMSG pyecho :hi");
+        assert_eq!("PLUGIN alice : hi (3)", alice.get_message().unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_slow_interceptors_only_hold_up_their_sender() {
+        let dir = std::env::temp_dir().join(format!("iris-slow-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sleepy.py");
+        std::fs::write(
+            &path,
+            r##"#!/usr/bin/env python3
+import json, sys, time
+
+def intercept(params):
+    text = params.get("privmsg", {}).get("message")
+    if text == "slow":
+        time.sleep(2)
+    if text is not None:
+        return {"modify": text + " (seen)"}
+
+METHODS = {
+    "describe": lambda _: {"name": "/sleepy", "intercepts": True},
+    "intercept": intercept,
+}
+
+for line in sys.stdin:
+    message = json.loads(line)
+    result = METHODS.get(message["method"], lambda _: None)(message.get("params"))
+    if "id" in message:
+        sys.stdout.write(json.dumps({"jsonrpc": "2.0", "id": message["id"], "result": result}) + "\n")
+        sys.stdout.flush()
+"##,
+        )
+        .unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut alice = initialise_test_rig(
+            PORT + 13,
+            ServerConfig {
+                plugins: vec![path.display().to_string()],
+                interceptor_timeout_ms: 200,
+                ..ServerConfig::default()
+            },
+        );
+        let mut bob = IrcClient::new(IP_ADDR, PORT + 13);
+        for (client, nick) in [(&mut alice, "alice"), (&mut bob, "bob")] {
+            client.send_message(&format!("NICK {nick}"));
+            client.send_message("USER ignored ignored ignored :Some One");
+            assert_eq!(
+                format!(":iris-server 001 {nick} :Hi Some One, welcome to IRC"),
+                client.get_message().unwrap()
+            );
+        }
+
+        alice.send_message("PRIVMSG bob :hello");
+        assert_eq!(
+            ":alice PRIVMSG bob :hello (seen)",
+            bob.get_message().unwrap()
+        );
+
+        // Bob is answered while alice's message waits on the interceptor,
+        // which then runs out of time, and her messages go ahead as they are and in order
+        alice.send_message("PRIVMSG bob :slow");
+        alice.send_message("PRIVMSG bob :after");
+        bob.send_message("PING :me");
+        assert_eq!("PONG :me", bob.get_message().unwrap());
+        assert_eq!(":alice PRIVMSG bob :slow", bob.get_message().unwrap());
+        assert_eq!(":alice PRIVMSG bob :after", bob.get_message().unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_plugins_built_against_plugin_api_1_still_load() {
+        let path = build_fixture("plugin_api_1", "api1");
+        let plugin = load_plugin(&path).unwrap();
+        assert!(plugin.on_join().is_none());
+        assert!(plugin.api_version().is_none());
+
+        // It has none of the optional fields, and does not say, so it is taken to be built against API 1
+        assert_eq!(plugin.built_against(), 1);
+        assert_eq!(
+            plugin_report(&path).unwrap(),
+            format!(
+                "Name: /oldtimer\n\
+                 Version: not given\n\
+                 Plugin API: 1 (this server supports up to {PLUGIN_API_VERSION})\n\
+                 ABI: compatible\n\
+                 Missing optional hooks: on_join (added in plugin API 2), on_part (added in plugin API 2), \
+                 on_quit (added in plugin API 2), on_privmsg (added in plugin API 2), \
+                 on_nick (added in plugin API 2), intercept (added in plugin API 3), \
+                 verbs (added in plugin API 4), triggers (added in plugin API 4), \
+                 handler_with_context (added in plugin API 5), deinit (added in plugin API 6), \
+                 version (added in plugin API 7), description (added in plugin API 7), \
+                 usage (added in plugin API 7), author (added in plugin API 7), \
+                 init_with_config (added in plugin API 8), api_version (added in plugin API 9)\n"
+            )
+        );
+
+        let mut client = initialise_test_rig(
+            PORT + 9,
+            ServerConfig {
+                plugins: vec![path.display().to_string()],
+                ..ServerConfig::default()
+            },
+        );
+        client.send_message("NICK old");
+        client.send_message("USER ignored ignored ignored :Old Timer");
+        assert_eq!(
+            ":iris-server 001 old :Hi Old Timer, welcome to IRC",
+            client.get_message().unwrap()
+        );
+
+        // The plugin has none of the hooks a join is offered to
+        client.send_message("JOIN #history");
+        assert_eq!(":old JOIN #history", client.get_message().unwrap());
+        client.send_message("PLUGIN /oldtimer one :two three");
+        assert_eq!(
+            "PLUGIN old : 2 arguments, the old way",
+            client.get_message().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_send_queue_exceeded() {
+        let mut sender = initialise_test_rig(
+            PORT + 4,
+            ServerConfig {
+                flood_burst: u32::MAX,
+                send_queue_limit: 4096,
+                ..ServerConfig::default()
+            },
+        );
+
+        // This client never reads, so its queue will eventually overflow
+        let mut slow = TcpStream::connect((IP_ADDR, PORT + 4)).unwrap();
+        slow.write_all(b"NICK slow\r\nUSER ignored ignored ignored :Slow Poke\r\nJOIN #slow\r\n")
+            .unwrap();
+
+        sender.send_message("NICK sender");
+        sender.send_message("USER ignored ignored ignored :Sen Der");
+        assert_eq!(
+            ":iris-server 001 sender :Hi Sen Der, welcome to IRC",
+            sender.get_message().unwrap()
+        );
+
+        let spam = format!("PRIVMSG #slow :{}", "a".repeat(400));
+        for _ in 0..100_000 {
+            sender.send_message(&spam);
+        }
+        sender.send_message("PING :me");
+        assert_eq!("PONG :me", sender.get_message().unwrap());
+
+        // The server should have hung up on the slow client
+        slow.set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            match slow.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(_) => continue,
+                Err(err) if err.kind() == ErrorKind::ConnectionReset => break,
+                Err(err) => panic!("slow client was not disconnected: {err}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_clients_which_quit_are_dropped_if_they_stop_reading() {
+        let mut sender = initialise_test_rig(
+            PORT + 14,
+            ServerConfig {
+                flood_burst: u32::MAX,
+                send_queue_limit: 256 * 1024 * 1024,
+                quit_flush_timeout_ms: 200,
+                ..ServerConfig::default()
+            },
+        );
+
+        // This client stops reading, and quits with far more queued for it than its socket can take
+        let mut slow = TcpStream::connect((IP_ADDR, PORT + 14)).unwrap();
+        slow.write_all(b"NICK slow\r\nUSER ignored ignored ignored :Slow Poke\r\nJOIN #slow\r\n")
+            .unwrap();
+        sender.send_message("NICK sender");
+        sender.send_message("USER ignored ignored ignored :Sen Der");
+        assert_eq!(
+            ":iris-server 001 sender :Hi Sen Der, welcome to IRC",
+            sender.get_message().unwrap()
+        );
+
+        let spam = format!("PRIVMSG #slow :{}", "a".repeat(400));
+        let relayed = format!(":sender {spam}\r\n").len() * 100_000;
+        for _ in 0..100_000 {
+            sender.send_message(&spam);
+        }
+        sender.send_message("PING :me");
+        assert_eq!("PONG :me", sender.get_message().unwrap());
+        slow.write_all(b"QUIT\r\n").unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        // By the time it reads again, the server has hung up on it without sending the rest
+        slow.set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut buffer = vec![0; 64 * 1024];
+        let mut received = 0;
+        loop {
+            match slow.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n_bytes) => received += n_bytes,
+                Err(err) if err.kind() == ErrorKind::ConnectionReset => break,
+                Err(err) => panic!("slow client was not disconnected: {err}"),
+            }
+        }
+        assert!(received < relayed);
+    }
+
+    #[test]
+    fn test_invalid_plugin_fails_startup() {
+        let config = ServerConfig {
+            plugins: vec!["/path/to/a/missing/plugin.so".to_string()],
+            ..ServerConfig::default()
+        };
+
+        assert!(begin_server(&IP_ADDR, PORT + 5, config).is_err());
+    }
+
+    #[test]
+    fn test_check_plugin_fails_for_a_missing_library() {
+        let err = check_plugin(Path::new("/path/to/a/missing/plugin.so")).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("/path/to/a/missing/plugin.so: "));
+    }
+
+    #[test]
+    fn test_check_plugin_only_takes_libraries() {
+        let err = check_plugin(Path::new("/path/to/a/plugin.rhai")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "/path/to/a/plugin.rhai: only native libraries can be checked, \
+             as other plugins are not built against the plugin API"
+        );
+    }
+
+    #[test]
+    fn test_unknown_interceptor_fails_startup() {
+        let config = ServerConfig {
+            interceptor_order: vec![PluginName::try_from("/missing".to_string()).unwrap()],
+            ..ServerConfig::default()
+        };
+
+        assert!(begin_server(&IP_ADDR, PORT + 6, config).is_err());
+    }
+
+    /// Builds the plugin in `tests/fixtures/{fixture}/plugin`, whose crate is named `name`,
+    /// returning the path of its library.
+    fn build_fixture(fixture: &str, name: &str) -> PathBuf {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(fixture)
+            .join("plugin");
+        let status = std::process::Command::new(env!("CARGO"))
+            .args(["build", "--quiet"])
+            .current_dir(&dir)
+            .status()
+            .unwrap();
+        assert!(status.success(), "failed to build the {fixture} fixture");
+
+        dir.join("target/debug").join(format!(
+            "{}{name}{}",
+            std::env::consts::DLL_PREFIX,
+            std::env::consts::DLL_SUFFIX
+        ))
+    }
+
+    fn initialise_test_rig(port: u16, config: ServerConfig) -> IrcClient {
+        thread::spawn(move || {
+            begin_server(&IP_ADDR, port, config).unwrap();
         });
 
         // Having timing in tests is bad
         // However, I'm too lazy to refactor this to have
         // a proper integration testing rig
         thread::sleep(Duration::from_secs(1));
-        IrcClient::new(IP_ADDR, PORT)
+        IrcClient::new(IP_ADDR, port)
     }
 }
@@ -1,13 +1,22 @@
 //! # Message handler
 //! Implements state design pattern to handle transitions between handler states
 //! Very loosely based off of: https://hoverbear.org/blog/rust-state-machine-pattern/
+//!
+//! Every message is handled on the connection manager's single reactor thread, so nothing here
+//! may block on anything slower than a lock. Plugin calls are handed to the plugin handler,
+//! which runs them on its worker pool. A message which interceptors may change is parked until they
+//! have decided on it, and the session waits, with its later messages held back, until it is woken with the verdict.
 
-use crate::plugin_handler::PluginHandler;
-use crate::user_connections::UserConnections;
-use common::connect::{ConnectionError, ConnectionWrite};
+use crate::config::ServerConfig;
+use crate::flood_control::TokenBucket;
+use crate::plugin_handler::{PluginHandler, Verdict};
+use crate::user_connections::{Recipient, UserConnections};
+use anyhow::anyhow;
+use common::connect::{ConnectionError, ConnectionEvents, ConnectionSender};
 use common::types::*;
-use log::{error, info};
-use std::sync::{Arc, Mutex};
+use log::{error, info, warn};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub enum ClientState {
     Fresh(Fresh),
@@ -16,9 +25,7 @@ pub enum ClientState {
     Quit,
 }
 
-pub struct Fresh {
-    curr_writer: Arc<Mutex<ConnectionWrite>>,
-}
+pub struct Fresh;
 
 pub struct Nicked {
     nick: Nick,
@@ -26,31 +33,56 @@ pub struct Nicked {
 
 pub struct Initialised {
     real_name: String,
+    username: String,
     nick: Nick,
+    /// The operator name the user authenticated as with OPER, if they have
+    account: Option<String>,
 }
 
 pub struct MessageHandler {
     state: ClientState,
-    user_connections: Arc<Mutex<UserConnections>>,
-    plugin_handler: PluginHandler,
+    writer: ConnectionSender,
+    user_connections: Arc<UserConnections>,
+    plugin_handler: Arc<PluginHandler>,
+    config: Arc<ServerConfig>,
+    flood_bucket: TokenBucket,
+    /// Set while a message is with the interceptors, which leave their verdict in `verdict`
+    intercepting: bool,
+    verdict: Arc<Mutex<Option<Verdict>>>,
 }
 
 impl MessageHandler {
     pub fn new(
-        user_connections: &Arc<Mutex<UserConnections>>,
-        curr_writer: ConnectionWrite,
-        plugin_paths: Vec<String>,
+        user_connections: &Arc<UserConnections>,
+        writer: ConnectionSender,
+        plugin_handler: &Arc<PluginHandler>,
+        config: &Arc<ServerConfig>,
     ) -> MessageHandler {
         MessageHandler {
-            state: ClientState::Fresh(Fresh {
-                curr_writer: Arc::new(Mutex::new(curr_writer)),
-            }),
+            state: ClientState::Fresh(Fresh),
+            writer,
             user_connections: user_connections.clone(),
-            plugin_handler: PluginHandler::new(&plugin_paths, user_connections.clone()),
+            plugin_handler: plugin_handler.clone(),
+            config: config.clone(),
+            flood_bucket: TokenBucket::new(
+                config.flood_burst,
+                config.flood_rate,
+                Duration::from_millis(config.flood_grace_ms),
+                Instant::now(),
+            ),
+            intercepting: false,
+            verdict: Arc::new(Mutex::new(None)),
         }
     }
 
     pub fn handle(&mut self, message: anyhow::Result<String>) {
+        if message.is_ok() && !self.is_operator() && !self.flood_bucket.try_acquire(Instant::now())
+        {
+            warn!("Disconnecting {:?} for flooding", self.get_nick());
+            self.disconnect("Excess Flood");
+            return;
+        }
+
         match self.transition(message) {
             Ok(_) => {}
             Err(err) => {
@@ -59,8 +91,7 @@ impl MessageHandler {
                 error!("{err}");
 
                 if let Some(nick) = self.get_nick() {
-                    let mut user_conn_guard = self.user_connections.lock().unwrap();
-                    user_conn_guard.remove_user(&nick);
+                    self.user_connections.remove_user(&nick);
                 }
 
                 self.state = ClientState::Quit;
@@ -72,66 +103,178 @@ impl MessageHandler {
         matches!(self.state, ClientState::Quit)
     }
 
+    /// Handles the message the interceptors have decided on, if they have.
+    pub fn resume(&mut self) {
+        let verdict = self
+            .verdict
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        let Some(verdict) = verdict else { return };
+        self.intercepting = false;
+        // The session may have been ended while it waited
+        if self.has_quit() {
+            return;
+        }
+
+        if let Err(err) = self.conclude(verdict) {
+            error!("{err}");
+
+            if let Some(nick) = self.get_nick() {
+                self.user_connections.remove_user(&nick);
+            }
+
+            self.state = ClientState::Quit;
+        }
+    }
+
+    /// Forcibly ends the session, letting the client and everyone sharing a channel with them know why.
+    fn disconnect(&mut self, reason: &str) {
+        let closing = Reply::Closing(reason.to_string()).to_string();
+
+        match (&self.state, self.get_nick()) {
+            (ClientState::Fresh(_), _) => {
+                let _ = self.writer.write_message(&closing);
+            }
+            (_, Some(nick)) => {
+                let user_connections = &self.user_connections;
+                let _ = user_connections.write_to_user(&nick, &closing);
+                let quit_msg = QuitMsg {
+                    message: Some(reason.to_string()),
+                };
+                user_connections.write_to_peers(
+                    &nick,
+                    &Reply::Quit(QuitReply {
+                        message: quit_msg.clone(),
+                        sender_nick: nick.clone(),
+                    })
+                    .to_string(),
+                );
+                user_connections.remove_user(&nick);
+
+                if let ClientState::Initialised(_) = &self.state {
+                    self.plugin_handler.notify(&nick, &Message::Quit(quit_msg));
+                }
+            }
+            _ => {}
+        }
+
+        self.state = ClientState::Quit;
+    }
+
     fn transition(&mut self, message: anyhow::Result<String>) -> anyhow::Result<()> {
-        let message = message.as_deref().map(ParsedMessage::try_from);
-        let message =
-            match message {
-                Ok(Ok(message)) => message,
-                Err(err) => match err.downcast_ref::<ConnectionError>() {
-                    Some(ConnectionError::ConnectionLost | ConnectionError::ConnectionClosed) => {
-                        info!("Lost connection.");
-
-                        if let Some(nick) = self.get_nick() {
-                            let mut user_conn_guard = self.user_connections.lock().unwrap();
-                            user_conn_guard.remove_user(&nick);
-                        }
-
-                        self.state = ClientState::Quit;
-                        return Ok(());
-                    }
-                    Some(_) | None => {
-                        error!("Invalid message received... ignoring message. (Error: {err})");
+        let message = message
+            .as_deref()
+            .map(|message| self.plugin_handler.parse(message));
+        let message = match message {
+            Ok(Ok(message)) => message,
+            Err(err) => match err.downcast_ref::<ConnectionError>() {
+                Some(ConnectionError::ConnectionLost | ConnectionError::ConnectionClosed) => {
+                    info!("Lost connection.");
 
-                        return Ok(());
-                    }
-                },
-                Ok(Err(err)) => {
-                    error!("{err}");
-
-                    if let Some(nick) = self.get_nick() {
-                        let mut user_conn_guard = self.user_connections.lock().unwrap();
-                        let _ = user_conn_guard.write_to_user(&nick, &err.to_string());
-                    } else if let ClientState::Fresh(state) = &self.state {
-                        // The user has not yet been stored in the connections manager
-                        // So, to write the error to console we need to utilise the curr writer
-
-                        state.curr_writer.lock().unwrap().write_message(
-                            format!("{}\r\n", err.to_string().trim_end()).as_str(),
-                        )?;
-                    }
+                    let reason = self
+                        .writer
+                        .close_reason()
+                        .unwrap_or_else(|| "Connection closed".to_string());
+                    self.disconnect(&reason);
+                    return Ok(());
+                }
+                Some(_) | None => {
+                    error!("Invalid message received... ignoring message. (Error: {err})");
 
                     return Ok(());
                 }
-            };
+            },
+            Ok(Err(err)) => {
+                error!("{err}");
+
+                if let Some(nick) = self.get_nick() {
+                    let _ = self.user_connections.write_to_user(&nick, &err.to_string());
+                } else if let ClientState::Fresh(_) = &self.state {
+                    // The user has not yet been stored in the connections manager
+                    // So, to write the error to console we need to utilise the curr writer
+
+                    self.writer
+                        .write_message(format!("{}\r\n", err.to_string().trim_end()).as_str())?;
+                }
+
+                return Ok(());
+            }
+        };
+
+        let message = message.message;
+        let nick = match &self.state {
+            ClientState::Initialised(state) if self.plugin_handler.intercepts(&message) => {
+                state.nick.clone()
+            }
+            _ => return self.transition_parsed(message),
+        };
+
+        // The rest of the message's handling waits for the interceptors, and so do the messages after it
+        self.intercepting = true;
+        let verdict = self.verdict.clone();
+        let writer = self.writer.clone();
+        self.plugin_handler
+            .intercept(&nick, message, move |outcome| {
+                *verdict.lock().unwrap_or_else(PoisonError::into_inner) = Some(outcome);
+                writer.wake();
+            });
 
-        self.transition_parsed(message.message)
+        Ok(())
+    }
+
+    /// Handles a registered user's message as the interceptors have left it.
+    fn conclude(&mut self, verdict: Verdict) -> anyhow::Result<()> {
+        let nick = match self.get_nick() {
+            Some(nick) => nick,
+            None => return Ok(()),
+        };
+
+        match verdict {
+            Verdict::Allow(message) => self.transition_parsed(message),
+            Verdict::Deny { numeric, reason } => {
+                let reply = Reply::Numeric(NumericReply {
+                    target_nick: nick.clone(),
+                    numeric,
+                    message: reason,
+                });
+                self.user_connections
+                    .write_to_user(&nick, &reply.to_string())?;
+
+                Ok(())
+            }
+        }
     }
 
     fn transition_parsed(&mut self, message: Message) -> anyhow::Result<()> {
         match (&self.state, message) {
-            (ClientState::Fresh(state), Message::Nick(nick_msg)) => {
+            (ClientState::Fresh(_), Message::Nick(nick_msg)) => {
                 let nick = nick_msg.nick;
 
-                let mut user_conn_guard = self.user_connections.lock().unwrap();
-                user_conn_guard.add_user(&nick, state.curr_writer.clone())?;
+                self.user_connections
+                    .add_user(&nick, Recipient::Client(self.writer.clone()))?;
                 self.state = ClientState::Nicked(Nicked { nick });
             }
+            (ClientState::Nicked(state), Message::Nick(nick_msg)) => {
+                let user_connections = &self.user_connections;
+                match user_connections.rename_user(&state.nick, &nick_msg.nick) {
+                    Ok(()) => {
+                        self.state = ClientState::Nicked(Nicked {
+                            nick: nick_msg.nick,
+                        })
+                    }
+                    Err(err) => user_connections.write_to_user(&state.nick, &err.to_string())?,
+                }
+            }
             (ClientState::Nicked(state), Message::User(user_msg)) => {
-                let real_name = user_msg.real_name;
-                let mut user_conn_guard = self.user_connections.lock().unwrap();
+                let UserMsg {
+                    username,
+                    real_name,
+                } = user_msg;
+                let user_connections = &self.user_connections;
 
                 let nick = state.nick.clone();
-                user_conn_guard.write_to_user(
+                user_connections.write_to_user(
                     &nick,
                     &Reply::Welcome(WelcomeReply {
                         target_nick: nick.clone(),
@@ -140,72 +283,209 @@ impl MessageHandler {
                     .to_string(),
                 )?;
 
-                self.state = ClientState::Initialised(Initialised { nick, real_name });
+                self.state = ClientState::Initialised(Initialised {
+                    nick,
+                    real_name,
+                    username,
+                    account: None,
+                });
             }
             (ClientState::Initialised(state), Message::Ping(ping_msg)) => {
-                let mut user_conn_guard = self.user_connections.lock().unwrap();
+                let user_connections = &self.user_connections;
                 let nick = state.nick.clone();
-                user_conn_guard.write_to_user(&nick, &Reply::Pong(ping_msg).to_string())?;
+                user_connections.write_to_user(&nick, &Reply::Pong(ping_msg).to_string())?;
             }
             (ClientState::Initialised(state), Message::Quit(quit_msg)) => {
-                let mut user_conn_guard = self.user_connections.lock().unwrap();
+                let user_connections = &self.user_connections;
                 let nick = state.nick.clone();
-                user_conn_guard.write_to_users_channel(
+                user_connections.write_to_peers(
                     &nick,
                     &Reply::Quit(QuitReply {
-                        message: quit_msg,
+                        message: quit_msg.clone(),
                         sender_nick: nick.clone(),
                     })
                     .to_string(),
-                )?;
+                );
 
                 info!("{nick} has quit...");
-                user_conn_guard.remove_user(&nick);
+                user_connections.remove_user(&nick);
+                self.plugin_handler.notify(&nick, &Message::Quit(quit_msg));
 
                 self.state = ClientState::Quit;
             }
+            (ClientState::Initialised(state), Message::Nick(nick_msg)) => {
+                let user_connections = &self.user_connections;
+                let nick = state.nick.clone();
+
+                if let Err(err) = user_connections.rename_user(&nick, &nick_msg.nick) {
+                    user_connections.write_to_user(&nick, &err.to_string())?;
+                    return Ok(());
+                }
+
+                let new_nick = nick_msg.nick.clone();
+                let reply = Reply::Nick(NickReply {
+                    message: nick_msg.clone(),
+                    sender_nick: nick.clone(),
+                })
+                .to_string();
+                user_connections.write_to_user(&new_nick, &reply)?;
+                user_connections.write_to_peers(&new_nick, &reply);
+                self.plugin_handler.notify(&nick, &Message::Nick(nick_msg));
+
+                info!("{nick} is now known as {new_nick}");
+                self.state = ClientState::Initialised(Initialised {
+                    nick: new_nick,
+                    real_name: state.real_name.clone(),
+                    username: state.username.clone(),
+                    account: state.account.clone(),
+                });
+            }
+            (ClientState::Initialised(state), Message::Away(away_msg)) => {
+                let user_connections = &self.user_connections;
+                let nick = state.nick.clone();
+
+                user_connections.set_away(&nick, away_msg.message.clone());
+                let status = match away_msg.message {
+                    Some(_) => Reply::NowAway(nick.clone()),
+                    None => Reply::UnAway(nick.clone()),
+                };
+                user_connections.write_to_user(&nick, &status.to_string())?;
+                user_connections.write_to_peers(
+                    &nick,
+                    &Reply::Away(AwayReply {
+                        message: away_msg,
+                        sender_nick: nick.clone(),
+                    })
+                    .to_string(),
+                );
+            }
             (ClientState::Initialised(state), Message::PrivMsg(priv_msg)) => {
-                let mut user_conn_guard = self.user_connections.lock().unwrap();
+                let user_connections = &self.user_connections;
                 let nick = state.nick.clone();
-                user_conn_guard.write(
+                let delivery = user_connections.write(
                     &priv_msg.target,
                     &Reply::PrivMsg(PrivReply {
                         message: priv_msg.clone(),
-                        sender_nick: nick,
+                        sender_nick: nick.clone(),
                     })
                     .to_string(),
-                )?;
+                );
+
+                // A missing recipient is reported back to the sender,
+                // whereas a recipient's broken connection is their own session's problem
+                if let Err(err) = delivery {
+                    if let Some(err) = err.downcast_ref::<ErrorType>() {
+                        user_connections.write_to_user(&nick, &err.to_string())?;
+                    }
+                    return Ok(());
+                }
+                self.plugin_handler
+                    .notify(&nick, &Message::PrivMsg(priv_msg.clone()));
+                if let Some(plugin_msg) = self.plugin_handler.trigger(&priv_msg) {
+                    // A plugin messaged directly answers the sender, rather than its own service
+                    let origin = match &priv_msg.target {
+                        Target::Channel(_) => priv_msg.target.clone(),
+                        Target::User(_) => Target::User(nick.clone()),
+                    };
+                    let invocation = self.invocation(state, origin);
+                    self.plugin_handler.handle(invocation, plugin_msg);
+                }
+
+                if let Target::User(target_nick) = &priv_msg.target {
+                    if let Some(away_message) = user_connections.away_message(target_nick) {
+                        let reply = Reply::UserAway(UserAwayReply {
+                            target_nick: nick.clone(),
+                            away_nick: target_nick.clone(),
+                            message: away_message.clone(),
+                        });
+                        user_connections.write_to_user(&nick, &reply.to_string())?;
+                    }
+                }
+            }
+            (ClientState::Initialised(state), Message::Notice(notice)) => {
+                // Nothing answers a notice, so one which cannot be delivered is simply dropped
+                let _ = self.user_connections.write(
+                    &notice.target,
+                    &Reply::Notice(PrivReply {
+                        message: notice.clone(),
+                        sender_nick: state.nick.clone(),
+                    })
+                    .to_string(),
+                );
             }
             (ClientState::Initialised(state), Message::Join(join_msg)) => {
-                let mut user_conn_guard = self.user_connections.lock().unwrap();
+                let user_connections = &self.user_connections;
                 let nick = state.nick.clone();
-                user_conn_guard.add_user_to_channel(&nick, &join_msg.channel)?;
-                user_conn_guard.write_to_channel(
+                user_connections.add_user_to_channel(&nick, &join_msg.channel)?;
+                user_connections.write_to_channel(
                     &join_msg.channel,
                     &Reply::Join(JoinReply {
                         message: join_msg.clone(),
-                        sender_nick: nick,
+                        sender_nick: nick.clone(),
                     })
                     .to_string(),
                 )?;
+                self.plugin_handler.notify(&nick, &Message::Join(join_msg));
             }
             (ClientState::Initialised(state), Message::Part(part_msg)) => {
-                let mut user_conn_guard = self.user_connections.lock().unwrap();
+                let user_connections = &self.user_connections;
                 let nick = state.nick.clone();
-                user_conn_guard.remove_user_from_channel(&nick, &part_msg.channel)?;
-                user_conn_guard.write_to_channel(
+                user_connections.remove_user_from_channel(&nick, &part_msg.channel)?;
+                user_connections.write_to_channel(
                     &part_msg.channel,
                     &Reply::Part(PartReply {
                         message: part_msg.clone(),
-                        sender_nick: nick,
+                        sender_nick: nick.clone(),
                     })
                     .to_string(),
                 )?;
+                self.plugin_handler.notify(&nick, &Message::Part(part_msg));
             }
-            (ClientState::Initialised(state), Message::Plugin(plugin_msg)) => {
+            (ClientState::Initialised(state), Message::Oper(oper_msg)) => {
+                let user_connections = &self.user_connections;
                 let nick = state.nick.clone();
-                self.plugin_handler
-                    .handle(&nick, &state.real_name, plugin_msg);
+
+                if self
+                    .config
+                    .is_valid_operator(&oper_msg.name, &oper_msg.password)
+                {
+                    info!("{nick} is now an operator");
+                    user_connections.set_operator(&nick);
+                    user_connections
+                        .write_to_user(&nick, &Reply::YoureOper(nick.clone()).to_string())?;
+                    self.state = ClientState::Initialised(Initialised {
+                        nick,
+                        real_name: state.real_name.clone(),
+                        username: state.username.clone(),
+                        account: Some(oper_msg.name),
+                    });
+                } else {
+                    user_connections.write_to_user(
+                        &nick,
+                        &Reply::Error(ErrorType::PasswdMismatch).to_string(),
+                    )?;
+                }
+            }
+            (ClientState::Initialised(state), Message::Version) => {
+                let reply = Reply::Version(VersionReply {
+                    target_nick: state.nick.clone(),
+                    version: format!("iris-{}", env!("CARGO_PKG_VERSION")),
+                    comments: self.plugin_handler.version_comments(),
+                });
+                self.user_connections
+                    .write_to_user(&state.nick, &reply.to_string())?;
+            }
+            (ClientState::Initialised(state), Message::PluginAdmin(admin_msg)) => {
+                if state.account.is_some() {
+                    self.plugin_handler.administer(&state.nick, admin_msg);
+                } else {
+                    self.user_connections
+                        .write_to_user(&state.nick, &ErrorType::NoPrivileges.to_string())?;
+                }
+            }
+            (ClientState::Initialised(state), Message::Plugin(plugin_msg)) => {
+                let invocation = self.invocation(state, Target::User(state.nick.clone()));
+                self.plugin_handler.handle(invocation, plugin_msg);
             }
             _ => {}
         };
@@ -213,6 +493,31 @@ impl MessageHandler {
         Ok(())
     }
 
+    /// Describes a plugin call made by the user from `origin`.
+    fn invocation(&self, state: &Initialised, origin: Target) -> Invocation {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+
+        Invocation {
+            sender: state.nick.clone(),
+            real_name: state.real_name.clone(),
+            hostmask: format!(
+                "{}!{}@{}",
+                state.nick,
+                state.username,
+                self.writer.peer_ip()
+            ),
+            origin,
+            account: state.account.clone(),
+            timestamp_ms,
+        }
+    }
+
+    fn is_operator(&self) -> bool {
+        matches!(&self.state, ClientState::Initialised(state) if state.account.is_some())
+    }
+
     fn get_nick(&self) -> Option<Nick> {
         match &self.state {
             ClientState::Nicked(state) => Some(state.nick.clone()),
@@ -221,3 +526,29 @@ impl MessageHandler {
         }
     }
 }
+
+impl ConnectionEvents for MessageHandler {
+    fn on_message(&mut self, message: Result<String, ConnectionError>) {
+        self.handle(message.map_err(|e| anyhow!(e)));
+
+        if self.has_quit() {
+            info!("Connection has closed...");
+        }
+    }
+
+    fn has_quit(&self) -> bool {
+        MessageHandler::has_quit(self)
+    }
+
+    fn is_waiting(&self) -> bool {
+        self.intercepting
+    }
+
+    fn on_wake(&mut self) {
+        self.resume();
+
+        if self.has_quit() {
+            info!("Connection has closed...");
+        }
+    }
+}
@@ -1,115 +1,1253 @@
+use std::collections::btree_map::Entry;
 use std::collections::BTreeMap;
 
+use crate::config::ServerConfig;
+use crate::plugin_host::Host;
+use crate::plugin_runtime::{self, PluginMetadata, PluginRuntime};
+use crate::plugin_supervisor::{Failure, PluginHealth, Supervisor};
+use crate::timer_wheel::TimerWheel;
 use crate::user_connections::UserConnections;
+use crate::worker_pool::{ConcurrencyLimit, ConcurrencyPermit, StandIn, WorkerPool};
+use anyhow::anyhow;
 use closure::closure;
-use common::plugin::PluginMod_Ref;
-use common::types::{ErrorType, Nick, PluginMsg, PluginName, PluginReply, Reply};
-use log::{error, warn};
-use std::path::Path;
-use std::sync::{Arc, Mutex};
+use common::plugin::{PluginHostRef, RVerdict};
+use common::types::{
+    Channel, CommandTable, ErrorType, Invocation, Message, Nick, ParsedMessage, PluginAdminMsg,
+    PluginMsg, PluginName, PluginReply, PrivMsg, Reply, Target, UnparsedMessage,
+};
+use log::error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
 use std::thread;
+use std::time::{Duration, SystemTime};
+use std::vec;
+
+/// How often the files of plugins which reload when they change are checked.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The name `PLUGIN /help` goes by, which no plugin may take.
+const HELP_PLUGIN: &str = "/help";
+
+struct LoadedPlugin {
+    /// The file the plugin was loaded from, which it is reloaded from too
+    path: PathBuf,
+    runtime: Box<dyn PluginRuntime>,
+    metadata: PluginMetadata,
+    limit: ConcurrencyLimit,
+    host: PluginHostRef,
+    /// The server's side of `host`, kept to tidy up after the plugin once it is unloaded
+    host_impl: Host,
+    health: Arc<PluginHealth>,
+    calls: Mutex<Calls>,
+    /// Signalled as each call ends, for an unloading plugin to wait on
+    calls_ended: Condvar,
+}
+
+#[derive(Default)]
+struct Calls {
+    running: usize,
+    /// Set as soon as the plugin starts being unloaded, so calls which were queued before then are never made
+    retired: bool,
+}
+
+/// Held for as long as a call into the plugin runs.
+struct CallGuard<'a> {
+    plugin: &'a LoadedPlugin,
+}
+
+impl Drop for CallGuard<'_> {
+    fn drop(&mut self) {
+        self.plugin.calls().running -= 1;
+        self.plugin.calls_ended.notify_all();
+    }
+}
+
+impl LoadedPlugin {
+    /// Runs the plugin's `init`, handing it its settings if it takes any.
+    fn initialise(&self, settings: &BTreeMap<String, String>) -> Result<(), String> {
+        if !settings.is_empty() && !self.runtime.takes_settings() {
+            warn!(
+                "{} takes no settings, so they are ignored",
+                self.path.display()
+            );
+        }
+
+        self.runtime.init(self.host.clone(), settings)
+    }
+
+    fn calls(&self) -> MutexGuard<'_, Calls> {
+        self.calls.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Notes that a call into the plugin is starting, unless it has been retired, in which case the call must not be made.
+    fn enter(&self) -> Option<CallGuard<'_>> {
+        let mut calls = self.calls();
+        if calls.retired {
+            return None;
+        }
+
+        calls.running += 1;
+        Some(CallGuard { plugin: self })
+    }
+
+    /// Stops any more calls from being made, then waits up to `timeout` for those already running.
+    /// Returns whether they all finished.
+    fn retire(&self, timeout: Duration) -> bool {
+        let mut calls = self.calls();
+        calls.retired = true;
+
+        let (calls, _) = self
+            .calls_ended
+            .wait_timeout_while(calls, timeout, |calls| calls.running > 0)
+            .unwrap_or_else(PoisonError::into_inner);
+        calls.running == 0
+    }
+
+    /// The plugin's name, followed by its version if it gave one.
+    fn summary(&self, pl_name: &PluginName) -> String {
+        match &self.metadata.version {
+            Some(version) => format!("{pl_name} {version}"),
+            None => pl_name.to_string(),
+        }
+    }
+}
+
+type Registry = BTreeMap<PluginName, Arc<LoadedPlugin>>;
 
 pub struct PluginHandler {
-    plugins: Arc<Mutex<BTreeMap<PluginName, PluginMod_Ref>>>,
-    user_connections: Arc<Mutex<UserConnections>>,
+    plugins: RwLock<Registry>,
+    workers: Arc<WorkerPool>,
+    /// Kept apart from `workers`, so that busy handlers never hold up the messages interceptors are waiting on
+    interceptor_workers: Arc<WorkerPool>,
+    supervisor: Arc<Supervisor>,
+    interceptor_order: Vec<PluginName>,
+    commands: RwLock<CommandTable>,
+    /// Held while plugins are loaded and unloaded, so that one change finishes before the next starts
+    lifecycle: Mutex<()>,
+    config: ServerConfig,
+    timers: Arc<TimerWheel>,
+    user_connections: Arc<UserConnections>,
 }
 
-impl PluginHandler {
-    pub fn new(
-        plugin_paths: &[String],
-        user_connections: Arc<Mutex<UserConnections>>,
-    ) -> PluginHandler {
-        let plugins = plugin_paths
-            .iter()
-            .map(|path| {
-                let plugin = abi_stable::library::lib_header_from_path(Path::new(path))
-                    .and_then(|header| header.init_root_module::<PluginMod_Ref>());
+/// The name the plugin gives itself, as long as it is one a user could name it by.
+/// Anything else could reach outside the plugin's own corner of the data directory.
+fn checked_name(path: &Path, runtime: &dyn PluginRuntime) -> anyhow::Result<PluginName> {
+    let pl_name = runtime.name();
+    PluginName::try_from(pl_name.0.clone()).map_err(|_| {
+        anyhow!(
+            "{} names itself {pl_name:?}, which is not a valid plugin name",
+            path.display()
+        )
+    })
+}
 
-                plugin
-            })
-            .filter_map(|p_res| match p_res {
-                Ok(p) => Some(p),
-                Err(err) => {
-                    error!("{err}");
-                    None
+/// Adds the plugin's verbs and triggers to `commands`, returning every one which could not be added.
+fn register_commands(
+    commands: &mut CommandTable,
+    pl_name: &PluginName,
+    runtime: &dyn PluginRuntime,
+) -> Vec<String> {
+    let mut failures = vec![];
+
+    for verb in runtime.verbs() {
+        if let Err(err) = commands.register_verb(&verb, pl_name) {
+            failures.push(err);
+        }
+    }
+
+    for trigger in runtime.triggers() {
+        if let Err(err) = commands.register_trigger(&trigger, pl_name) {
+            failures.push(err);
+        }
+    }
+
+    failures
+}
+
+fn instantiate(
+    path: &Path,
+    pl_name: &PluginName,
+    runtime: Box<dyn PluginRuntime>,
+    config: &ServerConfig,
+    timers: &Arc<TimerWheel>,
+    user_connections: &Arc<UserConnections>,
+) -> LoadedPlugin {
+    let host_impl = Host::for_plugin(
+        pl_name,
+        timers.clone(),
+        user_connections.clone(),
+        &config.data_dir,
+    );
+
+    LoadedPlugin {
+        path: path.to_path_buf(),
+        metadata: runtime.metadata(),
+        runtime,
+        limit: ConcurrencyLimit::new(config.plugin_concurrency(pl_name)),
+        host: host_impl.to_ref(),
+        host_impl,
+        health: Arc::new(PluginHealth::default()),
+        calls: Mutex::new(Calls::default()),
+        calls_ended: Condvar::new(),
+    }
+}
+
+/// Lets a call which has overrun finish on its own, without holding on to its plugin's slot or its worker.
+fn give_up_on(
+    failure: &Failure,
+    permit: &ConcurrencyPermit,
+    stand_in: &StandIn,
+    workers: &WorkerPool,
+) {
+    if let Failure::TimedOut(_) = failure {
+        permit.release();
+        stand_in.take_over(workers);
+    }
+}
+
+/// `text` with any CR or LF taken out, so that it stays on one line.
+fn without_line_breaks(text: &str) -> String {
+    text.replace(['\r', '\n'], "")
+}
+
+/// The message as the interceptor's verdict leaves it, or the denial to stop it with.
+fn apply_verdict(
+    pl_name: &PluginName,
+    verdict: RVerdict,
+    mut message: Message,
+) -> Result<Message, Verdict> {
+    match verdict {
+        RVerdict::Allow => {}
+        // Line breaks would let a plugin smuggle a command of its own onto the wire
+        RVerdict::Modify(text) => match &mut message {
+            Message::PrivMsg(msg) | Message::Notice(msg) => {
+                msg.message = without_line_breaks(&text)
+            }
+            Message::Join(msg) => match Channel::try_from(without_line_breaks(&text)) {
+                Ok(channel) => msg.channel = channel,
+                Err(_) => {
+                    warn!("{pl_name} redirected a JOIN to an invalid channel");
+                    return Err(Verdict::Deny {
+                        numeric: ErrorType::NoSuchChannel as u16,
+                        reason: "No such channel".to_string(),
+                    });
                 }
+            },
+            _ => unreachable!("only PRIVMSG, NOTICE and JOIN are intercepted"),
+        },
+        RVerdict::Deny(denial) if !(100..=999).contains(&denial.numeric) => {
+            warn!(
+                "{pl_name} denied a message with an invalid numeric {}",
+                denial.numeric
+            );
+        }
+        RVerdict::Deny(denial) => {
+            return Err(Verdict::Deny {
+                numeric: denial.numeric,
+                reason: without_line_breaks(&denial.reason),
             })
-            .collect::<Vec<_>>();
+        }
+    }
 
-        info!(
-            "Session Loaded Plugins: {:?}",
-            plugins
-                .iter()
-                .map(|pl| { pl.pl_name()().into() })
-                .collect::<Vec<PluginName>>()
-        );
+    Ok(message)
+}
+
+/// The outcome of running a message through every interceptor.
+pub enum Verdict {
+    /// The message to handle, which may have been changed along the way
+    Allow(Message),
+    Deny {
+        numeric: u16,
+        reason: String,
+    },
+}
 
+impl PluginHandler {
+    /// Loads every plugin once, at startup, and runs each plugin's `init` exactly once.
+    /// Every plugin is validated before any is initialised: if a plugin fails to load, shares its name
+    /// with another, or registers a command which is already taken, the report lists the failures
+    /// and the server does not start. Nor does it start if any plugin rejects its settings.
+    pub fn new(
+        config: &ServerConfig,
+        user_connections: Arc<UserConnections>,
+        timers: Arc<TimerWheel>,
+    ) -> anyhow::Result<PluginHandler> {
         let mut plugin_map = BTreeMap::new();
-        for pl in plugins {
-            let pl_name = PluginName::from(pl.pl_name()());
-            pl.init()();
-
-            if plugin_map.contains_key(&pl_name) {
-                warn!(
-                    "Plugin with name {} already loaded. Overwriting...",
-                    &pl_name
-                )
+        let mut failures = vec![];
+
+        for path in config.plugins.iter() {
+            let plugin = plugin_runtime::open(Path::new(path), config)
+                .and_then(|pl| Ok((checked_name(Path::new(path), pl.as_ref())?, pl)));
+
+            match plugin {
+                Ok((pl_name, pl)) => match plugin_map.entry(pl_name) {
+                    Entry::Occupied(entry) => failures.push(format!(
+                        "{path}: a plugin named {} is already loaded",
+                        entry.key()
+                    )),
+                    Entry::Vacant(entry) if entry.key().0 == HELP_PLUGIN => {
+                        failures.push(format!("{path}: {HELP_PLUGIN} is reserved for the server"))
+                    }
+                    Entry::Vacant(entry) => {
+                        let plugin = instantiate(
+                            Path::new(path),
+                            entry.key(),
+                            pl,
+                            config,
+                            &timers,
+                            &user_connections,
+                        );
+                        info!("[OK]   {} ({path})", plugin.summary(entry.key()));
+                        entry.insert(Arc::new(plugin));
+                    }
+                },
+                Err(err) => failures.push(err.to_string()),
+            }
+        }
+
+        let mut commands = CommandTable::default();
+        for (pl_name, pl) in plugin_map.iter() {
+            failures.extend(register_commands(
+                &mut commands,
+                pl_name,
+                pl.runtime.as_ref(),
+            ));
+        }
+
+        for pl_name in config.interceptor_order.iter() {
+            match plugin_map.get(pl_name) {
+                None => failures.push(format!("--interceptor-order: {pl_name} is not loaded")),
+                Some(pl) if !pl.runtime.intercepts() => failures.push(format!(
+                    "--interceptor-order: {pl_name} does not intercept messages"
+                )),
+                Some(_) => {}
+            }
+        }
+
+        for failure in failures.iter() {
+            error!("[FAIL] {failure}");
+        }
+
+        if !failures.is_empty() {
+            return Err(anyhow!(
+                "{} problem(s) found validating {} plugin(s)",
+                failures.len(),
+                config.plugins.len()
+            ));
+        }
+
+        for (pl_name, pl) in plugin_map.iter() {
+            if let Err(err) = pl.initialise(&config.plugin_settings(pl_name)) {
+                failures.push(format!("{}: {err}", pl.path.display()));
             }
+        }
+
+        for failure in failures.iter() {
+            error!("[FAIL] {failure}");
+        }
 
-            plugin_map.insert(pl_name, pl);
+        if !failures.is_empty() {
+            return Err(anyhow!(
+                "{} plugin(s) rejected their settings",
+                failures.len()
+            ));
         }
 
-        PluginHandler {
-            plugins: Arc::new(Mutex::new(plugin_map)),
+        info!("Loaded Plugins: [{}]", Self::summarise(&plugin_map));
+
+        let supervisor = Supervisor::new(
+            timers.clone(),
+            Duration::from_millis(config.plugin_timeout_ms),
+            config.plugin_max_failures,
+            user_connections.clone(),
+        );
+
+        Ok(PluginHandler {
+            plugins: RwLock::new(plugin_map),
+            workers: Arc::new(WorkerPool::new("plugin", config.plugin_workers)),
+            interceptor_workers: Arc::new(WorkerPool::new("interceptor", config.plugin_workers)),
+            supervisor,
+            interceptor_order: config.interceptor_order.clone(),
+            commands: RwLock::new(commands),
+            lifecycle: Mutex::new(()),
+            config: config.clone(),
+            timers,
             user_connections,
+        })
+    }
+
+    /// The loaded plugins. A panic while the registry was held does not stop it from being read.
+    fn registry(&self) -> RwLockReadGuard<'_, Registry> {
+        self.plugins.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn commands(&self) -> RwLockReadGuard<'_, CommandTable> {
+        self.commands.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Parses a message, including any command registered by a plugin.
+    pub fn parse(&self, message: &str) -> Result<ParsedMessage, ErrorType> {
+        UnparsedMessage::from(message).parse(&self.commands())
+    }
+
+    /// The plugin call for a private message to a plugin's service, whose text is split into arguments
+    /// the same way as a command's, or for a channel message which starts with a plugin's trigger.
+    pub fn trigger(&self, priv_msg: &PrivMsg) -> Option<PluginMsg> {
+        match &priv_msg.target {
+            Target::User(nick) => {
+                let pl_name = self.user_connections.service(nick)?;
+                Some(PluginMsg::with_text(pl_name, &priv_msg.message))
+            }
+            Target::Channel(_) => self.commands().trigger(priv_msg),
         }
     }
 
-    pub fn handle(&self, nick: &Nick, real_name: &str, plugin_msg: PluginMsg) {
+    fn summarise(plugins: &Registry) -> String {
+        plugins
+            .iter()
+            .map(|(pl_name, plugin)| plugin.summary(pl_name))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// What `VERSION` says about the plugins.
+    pub fn version_comments(&self) -> String {
+        let plugins = self.registry();
+        if plugins.is_empty() {
+            "No plugins loaded".to_string()
+        } else {
+            format!("Plugins: {}", Self::summarise(&plugins))
+        }
+    }
+
+    /// Answers `PLUGIN /help`, which lists every plugin, and `PLUGIN /help /name`, which describes one.
+    fn help(&self, nick: &Nick, args: &[String]) {
+        let plugins = self.registry();
+        let lines = match args.first() {
+            None => {
+                let mut lines =
+                    vec!["Loaded plugins (PLUGIN /help /name to find out more):".to_string()];
+                lines.extend(plugins.iter().map(|(pl_name, plugin)| {
+                    match &plugin.metadata.description {
+                        Some(description) => format!("{} - {description}", plugin.summary(pl_name)),
+                        None => plugin.summary(pl_name),
+                    }
+                }));
+                lines
+            }
+            Some(pl_name) => match plugins.get_key_value(&PluginName(pl_name.clone())) {
+                Some((pl_name, plugin)) => {
+                    let metadata = &plugin.metadata;
+                    let mut lines = vec![match &metadata.author {
+                        Some(author) => format!("{} by {author}", plugin.summary(pl_name)),
+                        None => plugin.summary(pl_name),
+                    }];
+                    lines.extend(metadata.description.clone());
+                    lines.extend(
+                        metadata
+                            .usage
+                            .as_ref()
+                            .map(|usage| format!("Usage: {usage}")),
+                    );
+
+                    let commands = self.commands().registered_by(pl_name);
+                    if !commands.is_empty() {
+                        lines.push(format!("Commands: {}", commands.join(", ")));
+                    }
+                    lines
+                }
+                None => vec![format!("Plugin {pl_name} not found")],
+            },
+        };
+
+        let target = Target::User(nick.clone());
+        for line in lines {
+            let reply = Reply::Plugin(PluginReply {
+                target: target.clone(),
+                message: line,
+            });
+            let _ = self.user_connections.write(&target, &reply.to_string());
+        }
+    }
+
+    pub fn handle(&self, invocation: Invocation, plugin_msg: PluginMsg) {
         let pl_name = plugin_msg.plugin_name.clone();
-        let plugins = self.plugins.clone();
-        let nick = nick.clone();
-        let real_name = real_name.to_string();
+        let nick = invocation.sender.clone();
         let user_connections = self.user_connections.clone();
 
-        // Run a detached thread with the plugin
+        if pl_name.0 == HELP_PLUGIN {
+            self.help(&nick, &plugin_msg.args);
+            return;
+        }
+
+        // Only hold the registry lock long enough to find the plugin,
+        // so a slow handler never blocks calls to any other plugin
+        let plugin = self.registry().get(&pl_name).cloned();
+        let plugin = match plugin {
+            Some(plugin) => plugin,
+            None => {
+                let error_str = format!("Plugin {} not found\r\n", &pl_name);
+                error!("{error_str}");
+
+                let _ = user_connections.write_to_user(&nick, &error_str);
+                return;
+            }
+        };
+
+        if plugin.health.is_disabled() {
+            let error_str = format!("Plugin {} is disabled\r\n", &pl_name);
+            let _ = user_connections.write_to_user(&nick, &error_str);
+            return;
+        }
+
+        let permit = match plugin.limit.try_acquire() {
+            Some(permit) => permit,
+            None => {
+                let error_str = format!("Plugin {} is busy, try again later\r\n", &pl_name);
+                warn!("{error_str}");
+
+                let _ = user_connections.write_to_user(&nick, &error_str);
+                return;
+            }
+        };
+
+        // Run the plugin on the worker pool
         // This is to allow the plugin to implement delays
         // without slowing the server down
-        thread::spawn(
-            closure!(move pl_name, move plugins, move nick, move real_name, move user_connections, || {
-                let plugins_guard = plugins.lock().unwrap();
-                let plugin = plugins_guard.get(&pl_name)
-                    .ok_or(ErrorType::NoSuchPlugin)
-                    .map_err(|_| {
-                        let error_str = format!("Plugin {} not found\r\n", &pl_name);
+        let supervisor = self.supervisor.clone();
+        let workers = self.workers.clone();
+        let permit = Arc::new(permit);
+        let stand_in = StandIn::default();
+        self.workers.execute(
+            closure!(move pl_name, move plugin, move permit, move nick, move invocation, move user_connections, move supervisor, move workers, move stand_in, || {
+                let Some(_call) = plugin.enter() else {
+                    let error_str = format!("Plugin {} has been unloaded\r\n", &pl_name);
+                    let _ = user_connections.write_to_user(&nick, &error_str);
+                    return;
+                };
+
+                let on_failure = closure!(clone pl_name, clone nick, clone user_connections, clone permit, clone stand_in, |failure: &Failure| {
+                    give_up_on(failure, &permit, &stand_in, &workers);
+                    let error_str = format!("Plugin {} {}\r\n", &pl_name, failure);
+                    let _ = user_connections.write_to_user(&nick, &error_str);
+                });
+
+                let host = plugin.host.clone();
+                let plugin_reply = supervisor.call(&pl_name, &plugin.health, on_failure, || {
+                    plugin.runtime.handle(host, invocation, plugin_msg)
+                });
+                stand_in.finish();
+
+                // The sender has already been told why the call failed
+                let plugin_reply = match plugin_reply {
+                    Some(plugin_reply) => plugin_reply,
+                    None => return,
+                };
+                let plugin_reply = plugin_reply
+                    .map_err(|e| {
+                        let error_str = format!("Plugin (Name: {}) Exception: {}\r\n", &pl_name, e);
                         error!("{error_str}");
 
-                        let mut user_conn_guard = user_connections.lock().unwrap();
-                        let _ = user_conn_guard.write_to_user(&nick, &error_str);
+                        let _ = user_connections.write_to_user(&nick, &error_str);
                     });
 
-                if let Ok(plugin) = plugin {
-                    let plugin_reply = Result::from(plugin.handler()(nick.clone().into(), real_name.clone().into(), plugin_msg.into()))
-                        .map_err(|e| {
-                            let error_str = format!("Plugin (Name: {}) Exception: {}\r\n", &pl_name, e);
-                            error!("{error_str}");
+                if let Ok(Some(plugin_reply)) = plugin_reply {
+                    // We ignore any errors when writing, as if a plugin's output gets lost, it is not mission critical
+                    let _ = user_connections.write(&plugin_reply.target.clone(), &Reply::Plugin(plugin_reply).to_string());
+                }
+
+                // The slot is only given back once the handler has returned
+                drop(permit);
+            }),
+        );
+    }
+
+    /// Lets every plugin with a hook for this kind of message know that `sender` has sent it.
+    /// Hooks run on the worker pool and count towards the plugin's concurrency limit,
+    /// so a plugin which is already busy misses the event rather than taking over the pool.
+    /// As they run concurrently, a plugin may see events in a different order to the one they happened in.
+    pub fn notify(&self, sender: &Nick, message: &Message) {
+        let plugins = self
+            .registry()
+            .iter()
+            .filter(|(_, plugin)| !plugin.health.is_disabled() && plugin.runtime.hooks(message))
+            .map(|(pl_name, plugin)| (pl_name.clone(), plugin.clone()))
+            .collect::<Vec<_>>();
+
+        for (pl_name, plugin) in plugins {
+            let permit = match plugin.limit.try_acquire() {
+                Some(permit) => permit,
+                None => {
+                    warn!("Plugin {} is busy, so its hook was skipped", &pl_name);
+                    continue;
+                }
+            };
+            let supervisor = self.supervisor.clone();
+            let workers = self.workers.clone();
+            let permit = Arc::new(permit);
+            let stand_in = StandIn::default();
+            let sender = sender.clone();
+            let message = message.clone();
+            self.workers.execute(move || {
+                let Some(_call) = plugin.enter() else {
+                    return;
+                };
+
+                let on_failure = closure!(clone permit, clone stand_in, |failure: &Failure| {
+                    give_up_on(failure, &permit, &stand_in, &workers);
+                });
+                supervisor.call(&pl_name, &plugin.health, on_failure, || {
+                    plugin.runtime.notify(plugin.host.clone(), sender, message)
+                });
+                stand_in.finish();
+                drop(permit);
+            });
+        }
+    }
+
+    /// Whether any plugin may change or stop the message, which is then to be run past `intercept`.
+    /// Only PRIVMSG, NOTICE and JOIN can be intercepted; every other message is allowed as it is.
+    /// Interceptors are shown a NOTICE as a PRIVMSG, so that a notice cannot get round them.
+    pub fn intercepts(&self, message: &Message) -> bool {
+        matches!(
+            message,
+            Message::PrivMsg(_) | Message::Notice(_) | Message::Join(_)
+        ) && !self.interceptors().is_empty()
+    }
+
+    /// Runs a message through each plugin which intercepts messages, in the configured order,
+    /// handing the outcome to `resume`. Nothing waits on the interceptors: each runs on a worker pool of
+    /// their own, and `resume` is called from whichever thread the last one finished on.
+    /// One which fails, takes longer than the interceptor time limit, or answers with a numeric which is not
+    /// three digits lets the message through as it is.
+    pub fn intercept(
+        self: &Arc<Self>,
+        sender: &Nick,
+        message: Message,
+        resume: impl FnOnce(Verdict) + Send + 'static,
+    ) {
+        let interceptors = match message {
+            Message::PrivMsg(_) | Message::Notice(_) | Message::Join(_) => self.interceptors(),
+            _ => vec![],
+        };
+
+        self.intercept_from(
+            interceptors.into_iter(),
+            sender.clone(),
+            message,
+            Box::new(resume),
+        );
+    }
+
+    /// Runs the message past the next of `interceptors`, then the rest of them once it has answered.
+    fn intercept_from(
+        self: &Arc<Self>,
+        mut interceptors: vec::IntoIter<(PluginName, Arc<LoadedPlugin>)>,
+        sender: Nick,
+        message: Message,
+        resume: Box<dyn FnOnce(Verdict) + Send>,
+    ) {
+        let Some((pl_name, plugin)) = interceptors.next() else {
+            return resume(Verdict::Allow(message));
+        };
+
+        let handler = self.clone();
+        let interceptor = pl_name.clone();
+        self.supervisor.call_async(
+            &self.interceptor_workers,
+            &interceptor,
+            &plugin.health,
+            Duration::from_millis(self.config.interceptor_timeout_ms),
+            closure!(clone plugin, clone sender, clone message, || {
+                let Some(_call) = plugin.enter() else {
+                    return RVerdict::Allow;
+                };
+
+                plugin.runtime.intercept(plugin.host.clone(), sender, message)
+            }),
+            move |verdict| match apply_verdict(
+                &pl_name,
+                verdict.unwrap_or(RVerdict::Allow),
+                message,
+            ) {
+                Ok(message) => handler.intercept_from(interceptors, sender, message, resume),
+                Err(denial) => resume(denial),
+            },
+        );
+    }
+
+    /// Every plugin with an interceptor: first those named in the configured order, then the rest by name.
+    fn interceptors(&self) -> Vec<(PluginName, Arc<LoadedPlugin>)> {
+        let plugins = self.registry();
+        let ordered = self
+            .interceptor_order
+            .iter()
+            .filter_map(|pl_name| plugins.get_key_value(pl_name));
+        let unordered = plugins
+            .iter()
+            .filter(|(pl_name, _)| !self.interceptor_order.contains(pl_name));
+
+        ordered
+            .chain(unordered)
+            .filter(|(_, plugin)| !plugin.health.is_disabled() && plugin.runtime.intercepts())
+            .map(|(pl_name, plugin)| (pl_name.clone(), plugin.clone()))
+            .collect()
+    }
+}
+
+/// Loading and unloading plugins while the server runs.
+impl PluginHandler {
+    fn registry_mut(&self) -> RwLockWriteGuard<'_, Registry> {
+        self.plugins.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn commands_mut(&self) -> RwLockWriteGuard<'_, CommandTable> {
+        self.commands
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Carries out an operator's change to the plugins, telling them how it went once it is done.
+    /// Unloading waits for the plugin's calls to finish, so it happens away from the operator's session.
+    pub fn administer(self: &Arc<Self>, operator: &Nick, admin_msg: PluginAdminMsg) {
+        let handler = self.clone();
+        let operator = operator.clone();
+
+        thread::Builder::new()
+            .name("plugin-admin".to_string())
+            .spawn(move || {
+                let outcome = match admin_msg {
+                    PluginAdminMsg::Load(path) => handler
+                        .load(Path::new(&path))
+                        .map(|pl_name| format!("Plugin {pl_name} loaded")),
+                    PluginAdminMsg::Unload(pl_name) => handler
+                        .unload(&pl_name)
+                        .map(|_| format!("Plugin {pl_name} unloaded")),
+                    PluginAdminMsg::Reload(pl_name) => handler
+                        .reload(&pl_name)
+                        .map(|_| format!("Plugin {pl_name} reloaded")),
+                };
+
+                let message = match outcome {
+                    Ok(message) => {
+                        info!("{message} by {operator}");
+                        message
+                    }
+                    Err(err) => {
+                        error!("{err}");
+                        err.to_string()
+                    }
+                };
+                let _ = handler.user_connections.write_to_user(&operator, &message);
+            })
+            .expect("failed to spawn plugin admin thread");
+    }
+
+    /// Reloads each plugin which asks to be whenever its file changes, for as long as the handler is in use.
+    /// A change which fails to load is reported once, and the plugin is left as it was until the next change.
+    /// One which a reload leaves unloaded, as its new copy rejected its settings, is still watched,
+    /// and loaded again once its file next changes.
+    pub fn watch(self: &Arc<Self>) {
+        let handler = Arc::downgrade(self);
 
-                            let mut user_conn_guard = user_connections.lock().unwrap();
-                            let _ = user_conn_guard.write_to_user(&nick, &error_str);
-                        });
+        thread::Builder::new()
+            .name("plugin-watch".to_string())
+            .spawn(move || {
+                let mut modified = BTreeMap::<PluginName, (PathBuf, SystemTime)>::new();
+                // Plugins a reload has left unloaded, which are not in the registry to be found
+                let mut stranded = BTreeMap::<PluginName, PathBuf>::new();
 
-                    if let Ok(plugin_reply) = plugin_reply {
-                        let plugin_reply = Option::<PluginReply>::from(plugin_reply.map(|repl| repl.into()));
+                while let Some(handler) = handler.upgrade() {
+                    let mut watched = handler
+                        .registry()
+                        .iter()
+                        .filter(|(_, pl)| pl.runtime.reloads_on_change())
+                        .map(|(pl_name, pl)| (pl_name.clone(), pl.path.clone()))
+                        .collect::<BTreeMap<_, _>>();
+                    // One which has since been loaded by hand is watched as any other
+                    stranded.retain(|pl_name, _| !handler.registry().contains_key(pl_name));
+                    watched.extend(stranded.clone());
+                    modified.retain(|pl_name, _| watched.contains_key(pl_name));
 
-                        if let Some(plugin_reply) = plugin_reply {
-                            let mut user_conn_guard = user_connections.lock().unwrap();
+                    for (pl_name, path) in watched {
+                        let Ok(time) = fs::metadata(&path).and_then(|meta| meta.modified()) else {
+                            continue;
+                        };
 
-                            // We ignore any errors when writing, as if a plugin's output gets lost, it is not mission critical
-                            let _ = user_conn_guard.write(&plugin_reply.target.clone(), &Reply::Plugin(plugin_reply).to_string());
+                        // A plugin seen for the first time, or loaded from somewhere else, is only noted
+                        let previous = modified.insert(pl_name.clone(), (path.clone(), time));
+                        if previous.is_none_or(|previous| previous.0 != path || previous.1 == time)
+                        {
+                            continue;
+                        }
+
+                        let reloaded = if stranded.contains_key(&pl_name) {
+                            handler.load(&path).map(|_| ())
+                        } else {
+                            handler.reload(&pl_name)
+                        };
+                        match reloaded {
+                            Ok(()) => {
+                                info!("Plugin {pl_name} reloaded, as {} changed", path.display());
+                                stranded.remove(&pl_name);
+                            }
+                            Err(err) => {
+                                error!("{err}");
+                                if !handler.registry().contains_key(&pl_name) {
+                                    stranded.insert(pl_name, path);
+                                }
+                            }
                         }
                     }
+
+                    drop(handler);
+                    thread::sleep(WATCH_INTERVAL);
                 }
-            }),
+            })
+            .expect("failed to spawn plugin watch thread");
+    }
+
+    fn load(&self, path: &Path) -> anyhow::Result<PluginName> {
+        let _lifecycle = self
+            .lifecycle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let runtime = plugin_runtime::open(path, &self.config)?;
+        let pl_name = checked_name(path, runtime.as_ref())?;
+        if self.registry().contains_key(&pl_name) {
+            return Err(anyhow!("A plugin named {pl_name} is already loaded"));
+        }
+        if pl_name.0 == HELP_PLUGIN {
+            return Err(anyhow!("{HELP_PLUGIN} is reserved for the server"));
+        }
+
+        let mut commands = self.commands().clone();
+        let failures = register_commands(&mut commands, &pl_name, runtime.as_ref());
+        if !failures.is_empty() {
+            return Err(anyhow!(failures.join(", ")));
+        }
+
+        self.start(path, &pl_name, runtime, commands)?;
+        Ok(pl_name)
+    }
+
+    fn unload(&self, pl_name: &PluginName) -> anyhow::Result<()> {
+        let _lifecycle = self
+            .lifecycle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let plugin = self
+            .registry_mut()
+            .remove(pl_name)
+            .ok_or_else(|| anyhow!("Plugin {pl_name} is not loaded"))?;
+        self.commands_mut().unregister(pl_name);
+        self.stop(pl_name, plugin);
+
+        Ok(())
+    }
+
+    /// Swaps the plugin for a fresh copy of its library, which must still go by the same name.
+    /// If the new copy cannot be opened, is named differently, or its commands clash, the old one is left running.
+    /// The old copy is stopped before the new one is initialised though, so a new copy which then
+    /// rejects its settings leaves the plugin unloaded.
+    fn reload(&self, pl_name: &PluginName) -> anyhow::Result<()> {
+        let _lifecycle = self
+            .lifecycle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let old = self
+            .registry()
+            .get(pl_name)
+            .cloned()
+            .ok_or_else(|| anyhow!("Plugin {pl_name} is not loaded"))?;
+        let path = old.path.clone();
+
+        let runtime = plugin_runtime::open(&path, &self.config)?;
+        let new_name = checked_name(&path, runtime.as_ref())?;
+        if new_name != *pl_name {
+            return Err(anyhow!(
+                "{} is now named {new_name}, not {pl_name}",
+                path.display()
+            ));
+        }
+
+        let mut commands = self.commands().clone();
+        commands.unregister(pl_name);
+        let failures = register_commands(&mut commands, pl_name, runtime.as_ref());
+        if !failures.is_empty() {
+            return Err(anyhow!(failures.join(", ")));
+        }
+
+        // The old copy is finished with before the new one starts, so the two never run side by side
+        self.registry_mut().remove(pl_name);
+        self.stop(pl_name, old);
+        if let Err(err) = self.start(&path, pl_name, runtime, commands) {
+            self.commands_mut().unregister(pl_name);
+            return Err(anyhow!("{err}, so {pl_name} has been left unloaded"));
+        }
+
+        Ok(())
+    }
+
+    /// Initialises the plugin, then makes it and its commands available.
+    fn start(
+        &self,
+        path: &Path,
+        pl_name: &PluginName,
+        runtime: Box<dyn PluginRuntime>,
+        commands: CommandTable,
+    ) -> anyhow::Result<()> {
+        let plugin = instantiate(
+            path,
+            pl_name,
+            runtime,
+            &self.config,
+            &self.timers,
+            &self.user_connections,
+        );
+        if let Err(err) = plugin.initialise(&self.config.plugin_settings(pl_name)) {
+            // Anything it set up before rejecting them, such as joining a channel, is undone
+            plugin.host_impl.retire();
+            return Err(anyhow!("{pl_name} rejected its settings: {err}"));
+        }
+
+        self.registry_mut()
+            .insert(pl_name.clone(), Arc::new(plugin));
+        *self.commands_mut() = commands;
+
+        Ok(())
+    }
+
+    /// Waits for the calls already running in a plugin taken out of the registry, then deinitialises it
+    /// and cancels its timers. Calls which were queued but had not started are never made.
+    /// A plugin whose calls are still running after the plugin time limit is never deinitialised,
+    /// as `deinit` must not run alongside them, and operators are told it was left that way.
+    /// The library itself stays in memory, as it cannot be safely unmapped, but is never called again.
+    fn stop(&self, pl_name: &PluginName, plugin: Arc<LoadedPlugin>) {
+        if !plugin.retire(Duration::from_millis(self.config.plugin_timeout_ms)) {
+            self.supervisor.report(&format!(
+                "Plugin {pl_name} was unloaded with calls still running, so it has not been deinitialised"
+            ));
+            plugin.host_impl.retire();
+            return;
+        }
+
+        let host = plugin.host.clone();
+        self.supervisor.call(
+            pl_name,
+            &plugin.health,
+            |_| {},
+            || plugin.runtime.deinit(host),
+        );
+        plugin.host_impl.retire();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin_store::PluginStore;
+    use std::sync::mpsc;
+    use std::time::Instant;
+
+    /// A handler with no plugins to start with, and a single worker.
+    fn handler(dir: &Path) -> PluginHandler {
+        handler_with_settings(dir, vec![])
+    }
+
+    fn handler_with_settings(
+        dir: &Path,
+        plugin_settings: Vec<(PluginName, String, String)>,
+    ) -> PluginHandler {
+        let config = ServerConfig {
+            data_dir: dir.to_path_buf(),
+            plugin_workers: 1,
+            plugin_timeout_ms: 2000,
+            plugin_settings,
+            ..ServerConfig::default()
+        };
+        PluginHandler::new(
+            &config,
+            Arc::new(UserConnections::new()),
+            TimerWheel::start(),
+        )
+        .unwrap()
+    }
+
+    /// Writes a script plugin named `/counter`, which counts its calls in its store.
+    fn counter(dir: &Path, version: &str) -> PathBuf {
+        let path = dir.join("counter.rhai");
+        fs::write(
+            &path,
+            format!(
+                r#"
+                    fn describe() {{ #{{name: "/counter", version: "{version}", verbs: ["COUNT"]}} }}
+                    fn handle(invocation) {{
+                        let calls = parse_int(store_get("calls") ?? "0") + 1;
+                        store_set("calls", calls.to_string());
+                    }}
+                "#
+            ),
+        )
+        .unwrap();
+        path
+    }
+
+    fn calls(dir: &Path) -> Option<String> {
+        let pl_name = PluginName("/counter".to_string());
+        PluginStore::for_plugin(dir, &pl_name).get("calls").unwrap()
+    }
+
+    fn call(handler: &PluginHandler) {
+        let sender = Nick("alice".to_string());
+        let invocation = Invocation {
+            sender: sender.clone(),
+            real_name: "Alice".to_string(),
+            hostmask: "alice!alice@::1".to_string(),
+            origin: Target::User(sender),
+            account: None,
+            timestamp_ms: 0,
+        };
+        handler.handle(
+            invocation,
+            PluginMsg::with_text(PluginName("/counter".to_string()), ""),
+        );
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("iris-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_plugins_can_be_loaded_reloaded_and_unloaded() {
+        let dir = scratch_dir("lifecycle");
+        let handler = handler(&dir);
+        let pl_name = PluginName("/counter".to_string());
+
+        let path = counter(&dir, "1");
+        assert_eq!(handler.load(&path).unwrap(), pl_name);
+        assert!(handler.load(&path).is_err());
+        assert_eq!(handler.commands().registered_by(&pl_name), vec!["COUNT"]);
+        assert_eq!(handler.registry()[&pl_name].summary(&pl_name), "/counter 1");
+
+        counter(&dir, "2");
+        handler.reload(&pl_name).unwrap();
+        assert_eq!(handler.registry()[&pl_name].summary(&pl_name), "/counter 2");
+
+        handler.unload(&pl_name).unwrap();
+        assert!(handler.registry().is_empty());
+        assert!(handler.commands().registered_by(&pl_name).is_empty());
+        assert!(handler.unload(&pl_name).is_err());
+        assert!(handler.reload(&pl_name).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_unloading_never_makes_queued_calls() {
+        let dir = scratch_dir("draining");
+        let handler = handler(&dir);
+        let pl_name = handler.load(&counter(&dir, "1")).unwrap();
+
+        call(&handler);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while calls(&dir).is_none() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(calls(&dir).as_deref(), Some("1"));
+
+        // The only worker is busy, so the next call is queued behind it
+        let (release, wait_release) = mpsc::channel::<()>();
+        handler.workers.execute(move || {
+            let _ = wait_release.recv();
+        });
+        call(&handler);
+
+        // It has not started, so the unload does not wait for it, and once it is picked up it is not made
+        let started = Instant::now();
+        handler.unload(&pl_name).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        release.send(()).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(calls(&dir).as_deref(), Some("1"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Stands in for a call into the plugin, which runs on another thread for `duration`, returning once it has started.
+    fn hold_call(handler: &PluginHandler, pl_name: &PluginName, duration: Duration) {
+        let plugin = handler.registry()[pl_name].clone();
+        let (started, wait_started) = mpsc::channel();
+        thread::spawn(move || {
+            let _call = plugin.enter().unwrap();
+            started.send(()).unwrap();
+            thread::sleep(duration);
+        });
+        wait_started.recv().unwrap();
+    }
+
+    #[test]
+    fn test_plugins_are_only_deinitialised_once_their_calls_end() {
+        let dir = scratch_dir("deinit");
+        let config = ServerConfig {
+            data_dir: dir.clone(),
+            plugin_timeout_ms: 300,
+            ..ServerConfig::default()
+        };
+        let handler = PluginHandler::new(
+            &config,
+            Arc::new(UserConnections::new()),
+            TimerWheel::start(),
+        )
+        .unwrap();
+        let path = dir.join("tidy.rhai");
+        fs::write(
+            &path,
+            r#"
+                fn describe() { #{name: "/tidy"} }
+                fn deinit() { store_set("deinit", "done"); }
+            "#,
+        )
+        .unwrap();
+        let store = PluginStore::for_plugin(&dir, &PluginName("/tidy".to_string()));
+
+        // A call which ends within the time limit is waited for
+        let pl_name = handler.load(&path).unwrap();
+        hold_call(&handler, &pl_name, Duration::from_millis(100));
+        let started = Instant::now();
+        handler.unload(&pl_name).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(store.get("deinit").unwrap().as_deref(), Some("done"));
+
+        // One which is still running once it is up leaves the plugin without being deinitialised
+        store.remove("deinit").unwrap();
+        let pl_name = handler.load(&path).unwrap();
+        hold_call(&handler, &pl_name, Duration::from_secs(1));
+        let started = Instant::now();
+        handler.unload(&pl_name).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        thread::sleep(Duration::from_secs(1));
+        assert_eq!(store.get("deinit").unwrap(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_plugins_must_give_themselves_valid_names() {
+        let dir = scratch_dir("names");
+        let handler = handler(&dir);
+        let path = dir.join("sneaky.rhai");
+
+        for name in ["/", "/../karma", "karma", "/a-very-long-name-indeed"] {
+            fs::write(&path, format!(r#"fn describe() {{ #{{name: "{name}"}} }}"#)).unwrap();
+            let err = handler.load(&path).unwrap_err();
+            assert!(err.to_string().contains("not a valid plugin name"), "{err}");
+        }
+
+        // Nor can a reload rename a plugin to one
+        fs::write(&path, r#"fn describe() { #{name: "/sneaky"} }"#).unwrap();
+        let pl_name = handler.load(&path).unwrap();
+        fs::write(&path, r#"fn describe() { #{name: "/"} }"#).unwrap();
+        assert!(handler.reload(&pl_name).is_err());
+        assert!(handler.registry().contains_key(&pl_name));
+        assert_eq!(handler.registry().len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_settings_reach_the_plugin() {
+        let dir = scratch_dir("settings");
+        let pl_name = PluginName("/greeter".to_string());
+        let handler = handler_with_settings(
+            &dir,
+            vec![(pl_name.clone(), "greeting".to_string(), "hi".to_string())],
         );
+        let path = dir.join("greeter.rhai");
+        fs::write(
+            &path,
+            r#"
+                fn describe() { #{name: "/greeter"} }
+                fn init(settings) { store_set("greeting", settings.greeting); }
+            "#,
+        )
+        .unwrap();
+
+        handler.load(&path).unwrap();
+        let store = PluginStore::for_plugin(&dir, &pl_name);
+        assert_eq!(store.get("greeting").unwrap().as_deref(), Some("hi"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_rejected_settings_stop_the_load() {
+        let dir = scratch_dir("rejected");
+        let handler = handler(&dir);
+        let pl_name = PluginName("/greeter".to_string());
+        let path = dir.join("greeter.rhai");
+        fs::write(
+            &path,
+            r##"
+                fn describe() { #{name: "/greeter", verbs: ["GREET"]} }
+                fn init(settings) {
+                    join("#lobby");
+                    if settings.greeting == () { throw "greeting is required"; }
+                }
+            "##,
+        )
+        .unwrap();
+
+        let err = handler.load(&path).unwrap_err().to_string();
+        assert!(err.contains("/greeter rejected its settings"), "{err}");
+        assert!(handler.registry().is_empty());
+        assert!(handler.commands().registered_by(&pl_name).is_empty());
+        // The service nick it joined with before rejecting them is gone too
+        let greeter = Nick("greeter".to_string());
+        assert!(handler.user_connections.user_snapshot(&greeter).is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_plugins_a_reload_unloads_are_still_watched() {
+        let dir = scratch_dir("stranded");
+        let handler = Arc::new(handler(&dir));
+        let pl_name = PluginName("/greeter".to_string());
+        let path = dir.join("greeter.rhai");
+        let greeter = |init: &str| {
+            fs::write(
+                &path,
+                format!(
+                    r#"fn describe() {{ #{{name: "/greeter"}} }} fn init(settings) {{ {init} }}"#
+                ),
+            )
+            .unwrap();
+        };
+        let loaded = |expected: bool| {
+            let deadline = Instant::now() + WATCH_INTERVAL * 5;
+            while handler.registry().contains_key(&pl_name) != expected {
+                assert!(
+                    Instant::now() < deadline,
+                    "the watcher did not pick up the change"
+                );
+                thread::sleep(Duration::from_millis(100));
+            }
+        };
+
+        greeter("");
+        handler.load(&path).unwrap();
+        handler.watch();
+        // Let the watcher note the file as it is, before changing it
+        thread::sleep(WATCH_INTERVAL / 2);
+
+        greeter(r#"throw "no thanks";"#);
+        loaded(false);
+        greeter("");
+        loaded(true);
+        fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -0,0 +1,575 @@
+//! # Plugin host
+//! The server side of `common::plugin::PluginHost`: what a plugin may ask of the server,
+//! whether from inside `handler` or at any time afterwards.
+
+use crate::plugin_store::PluginStore;
+use crate::timer_wheel::{TimerId, TimerWheel};
+use crate::user_connections::{Recipient, UserConnections};
+use abi_stable::sabi_trait::TD_Opaque;
+use abi_stable::std_types::{RArc, ROption, RResult, RString, RVec};
+use common::plugin::{
+    PluginHost, PluginHostRef, PluginHost_TO, RChannel, RChannelInfo, RNick, RPluginReply, RTarget,
+    RTimerHandle, RUserInfo,
+};
+use common::types::{
+    Channel, JoinMsg, JoinReply, Nick, PartMsg, PartReply, PluginName, PluginReply, PrivMsg,
+    PrivReply, QuitMsg, QuitReply, Reply, Target,
+};
+use log::warn;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+/// The most timers a single plugin may have pending at once, counting every recurring timer until it is cancelled.
+const MAX_TIMERS: usize = 1_000;
+
+/// One plugin's handle on the server.
+#[derive(Clone)]
+pub struct Host {
+    pl_name: PluginName,
+    timers: Arc<TimerWheel>,
+    /// The timers the plugin has pending, which are the only ones it may cancel.
+    /// A timer which only fires once is taken out as it fires
+    scheduled: Arc<Mutex<HashSet<TimerId>>>,
+    /// Set once the plugin has been unloaded, after which nothing it schedules fires
+    retired: Arc<AtomicBool>,
+    user_connections: Arc<UserConnections>,
+    /// Whether the plugin's service nick has been registered, which happens when it first joins a channel
+    service_registered: Arc<Mutex<bool>>,
+    store: Arc<PluginStore>,
+}
+
+impl Host {
+    /// Creates the server's side of the plugin named `pl_name`.
+    pub fn for_plugin(
+        pl_name: &PluginName,
+        timers: Arc<TimerWheel>,
+        user_connections: Arc<UserConnections>,
+        data_dir: &Path,
+    ) -> Host {
+        Host {
+            pl_name: pl_name.clone(),
+            timers,
+            scheduled: Arc::new(Mutex::new(HashSet::new())),
+            retired: Arc::new(AtomicBool::new(false)),
+            user_connections,
+            service_registered: Arc::new(Mutex::new(false)),
+            store: Arc::new(PluginStore::for_plugin(data_dir, pl_name)),
+        }
+    }
+
+    /// The handle given to the plugin, which shares this host's state.
+    pub fn to_ref(&self) -> PluginHostRef {
+        PluginHost_TO::from_ptr(RArc::new(self.clone()), TD_Opaque)
+    }
+
+    /// Cancels the plugin's timers and takes its service nick off the server, once the plugin has been unloaded.
+    /// A call still running by then may schedule more, but they are cancelled straight away.
+    pub fn retire(&self) {
+        self.retired.store(true, Ordering::Release);
+        let mut scheduled = self
+            .scheduled
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        for id in scheduled.drain() {
+            self.timers.cancel(id);
+        }
+        drop(scheduled);
+
+        let mut service_registered = self
+            .service_registered
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if !*service_registered {
+            return;
+        }
+
+        if let Ok(nick) = self.service_nick() {
+            let reply = Reply::Quit(QuitReply {
+                message: QuitMsg {
+                    message: Some("Plugin unloaded".to_string()),
+                },
+                sender_nick: nick.clone(),
+            });
+            self.user_connections
+                .write_to_peers(&nick, &reply.to_string());
+            self.user_connections.remove_user(&nick);
+        }
+        *service_registered = false;
+    }
+
+    fn service_nick(&self) -> Result<Nick, RString> {
+        Nick::try_from(self.pl_name.0.trim_start_matches('/').to_string())
+            .map_err(|_| format!("{} cannot be used as a nickname", self.pl_name).into())
+    }
+
+    fn channel(channel: RChannel) -> Result<Channel, RString> {
+        let channel = String::from(channel.0);
+        Channel::try_from(channel.clone())
+            .map_err(|_| format!("{channel} is not a valid channel").into())
+    }
+
+    /// Puts the plugin's service nick on the server, if it is not there already, returning the nick.
+    fn register_service(&self) -> Result<Nick, RString> {
+        let nick = self.service_nick()?;
+
+        let mut service_registered = self
+            .service_registered
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if !*service_registered {
+            self.user_connections
+                .add_user(&nick, Recipient::Service(self.pl_name.clone()))
+                .map_err(|_| RString::from(format!("The nickname {nick} is already in use")))?;
+            *service_registered = true;
+        }
+
+        Ok(nick)
+    }
+
+    fn join_channel(&self, channel: RChannel) -> Result<(), RString> {
+        let channel = Self::channel(channel)?;
+        let nick = self.register_service()?;
+
+        let user_connections = &self.user_connections;
+        user_connections
+            .add_user_to_channel(&nick, &channel)
+            .map_err(|err| RString::from(err.to_string()))?;
+        user_connections
+            .write_to_channel(
+                &channel,
+                &Reply::Join(JoinReply {
+                    message: JoinMsg {
+                        channel: channel.clone(),
+                    },
+                    sender_nick: nick,
+                })
+                .to_string(),
+            )
+            .map_err(|err| err.to_string().into())
+    }
+
+    fn part_channel(&self, channel: RChannel) -> Result<(), RString> {
+        let channel = Self::channel(channel)?;
+        let nick = self.service_nick()?;
+
+        if !*self
+            .service_registered
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+        {
+            return Err(format!("{nick} has not joined {channel}").into());
+        }
+
+        let user_connections = &self.user_connections;
+        user_connections
+            .remove_user_from_channel(&nick, &channel)
+            .map_err(|err| RString::from(err.to_string()))?;
+        user_connections
+            .write_to_channel(
+                &channel,
+                &Reply::Part(PartReply {
+                    message: PartMsg {
+                        channel: channel.clone(),
+                    },
+                    sender_nick: nick,
+                })
+                .to_string(),
+            )
+            .map_err(|err| err.to_string().into())
+    }
+}
+
+impl PluginHost for Host {
+    fn schedule(
+        &self,
+        delay_ms: u64,
+        interval_ms: ROption<u64>,
+        reply: RPluginReply,
+    ) -> RTimerHandle {
+        match self.try_schedule(delay_ms, interval_ms, reply) {
+            RResult::ROk(handle) => handle,
+            RResult::RErr(err) => {
+                warn!("{}: {err}", self.pl_name);
+                RTimerHandle(0)
+            }
+        }
+    }
+
+    fn cancel(&self, handle: RTimerHandle) -> bool {
+        let mut scheduled = self
+            .scheduled
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        scheduled.remove(&handle.0) && self.timers.cancel(handle.0)
+    }
+
+    fn send(&self, target: RTarget, message: RString) -> RResult<(), RString> {
+        let target = Target::from(target);
+        let nick = match self.register_service() {
+            Ok(nick) => nick,
+            Err(err) => return RResult::RErr(err),
+        };
+
+        for line in message.lines().filter(|line| !line.is_empty()) {
+            let reply = Reply::PrivMsg(PrivReply {
+                message: PrivMsg {
+                    target: target.clone(),
+                    message: line.to_string(),
+                },
+                sender_nick: nick.clone(),
+            });
+
+            if let Err(err) = self.user_connections.write(&target, &reply.to_string()) {
+                return RResult::RErr(err.to_string().into());
+            }
+        }
+
+        RResult::ROk(())
+    }
+
+    fn join(&self, channel: RChannel) -> RResult<(), RString> {
+        self.join_channel(channel).into()
+    }
+
+    fn part(&self, channel: RChannel) -> RResult<(), RString> {
+        self.part_channel(channel).into()
+    }
+
+    fn channels(&self) -> RVec<RChannelInfo> {
+        self.user_connections
+            .channels()
+            .into_iter()
+            .map(|(channel, member_count)| RChannelInfo {
+                channel: channel.into(),
+                member_count: member_count as u64,
+            })
+            .collect()
+    }
+
+    fn channel(&self, channel: RChannel) -> ROption<RChannelInfo> {
+        let channel = Channel::from(channel);
+        let member_count = self.user_connections.channel_members(&channel).len();
+
+        if member_count == 0 {
+            ROption::RNone
+        } else {
+            ROption::RSome(RChannelInfo {
+                channel: channel.into(),
+                member_count: member_count as u64,
+            })
+        }
+    }
+
+    fn members(&self, channel: RChannel) -> RVec<RNick> {
+        self.user_connections
+            .channel_members(&channel.into())
+            .into_iter()
+            .map(RNick::from)
+            .collect()
+    }
+
+    fn user(&self, nick: RNick) -> ROption<RUserInfo> {
+        self.user_connections
+            .user_snapshot(&nick.into())
+            .map(|user| RUserInfo {
+                nick: user.nick.into(),
+                channels: user.channels.into_iter().map(RChannel::from).collect(),
+                away_message: user.away_message.map(RString::from).into(),
+            })
+            .into()
+    }
+
+    fn store_get(&self, key: RString) -> RResult<ROption<RString>, RString> {
+        self.store
+            .get(&key)
+            .map(|value| value.map(RString::from).into())
+            .map_err(|err| err.to_string().into())
+            .into()
+    }
+
+    fn store_set(&self, key: RString, value: RString) -> RResult<(), RString> {
+        self.store
+            .set(&key, &value)
+            .map_err(|err| err.to_string().into())
+            .into()
+    }
+
+    fn store_remove(&self, key: RString) -> RResult<bool, RString> {
+        self.store
+            .remove(&key)
+            .map_err(|err| err.to_string().into())
+            .into()
+    }
+
+    fn store_keys(&self, prefix: RString) -> RResult<RVec<RString>, RString> {
+        self.store
+            .keys(&prefix)
+            .map(|keys| keys.into_iter().map(RString::from).collect())
+            .map_err(|err| err.to_string().into())
+            .into()
+    }
+
+    fn try_schedule(
+        &self,
+        delay_ms: u64,
+        interval_ms: ROption<u64>,
+        reply: RPluginReply,
+    ) -> RResult<RTimerHandle, RString> {
+        let reply = PluginReply::from(reply);
+        let interval = Option::from(interval_ms).map(Duration::from_millis);
+        let user_connections = self.user_connections.clone();
+        let pending = self.scheduled.clone();
+
+        let mut scheduled = self
+            .scheduled
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if scheduled.len() >= MAX_TIMERS {
+            return RResult::RErr(
+                format!("A plugin may only have {MAX_TIMERS} timers pending at once").into(),
+            );
+        }
+
+        let id = self
+            .timers
+            .schedule(Duration::from_millis(delay_ms), interval, move |id| {
+                if interval.is_none() {
+                    pending
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .remove(&id);
+                }
+
+                // As with replies, a delivery which cannot be written is simply lost
+                let _ = user_connections
+                    .write(&reply.target, &Reply::Plugin(reply.clone()).to_string());
+            });
+        // Checked while holding `scheduled`, so that `retire` cannot miss the timer
+        if self.retired.load(Ordering::Acquire) {
+            self.timers.cancel(id);
+        } else {
+            scheduled.insert(id);
+        }
+
+        RResult::ROk(RTimerHandle(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host(user_connections: &Arc<UserConnections>) -> PluginHostRef {
+        let pl_name = PluginName::try_from("/greeter".to_string()).unwrap();
+        Host::for_plugin(
+            &pl_name,
+            TimerWheel::start(),
+            user_connections.clone(),
+            &std::env::temp_dir(),
+        )
+        .to_ref()
+    }
+
+    /// Stands in for a user, whose messages are dropped.
+    fn service(nick: &str) -> Recipient {
+        Recipient::Service(PluginName(format!("/{nick}")))
+    }
+
+    fn channel(name: &str) -> RChannel {
+        RChannel(name.into())
+    }
+
+    #[test]
+    fn test_join_and_part_as_service() {
+        let user_connections = Arc::new(UserConnections::new());
+        let host = host(&user_connections);
+
+        assert!(host.part(channel("#lobby")).is_err());
+        assert!(host.join(channel("#lobby")).is_ok());
+        assert!(host.join(channel("#games")).is_ok());
+        assert!(host.join(channel("lobby")).is_err());
+
+        // The service can now be messaged like any other user
+        let greeter = RTarget::RUser(RNick("greeter".into()));
+        assert!(host.send(greeter, "first\nsecond".into()).is_ok());
+        assert!(host.part(channel("#lobby")).is_ok());
+    }
+
+    #[test]
+    fn test_queries_describe_the_server() {
+        let user_connections = Arc::new(UserConnections::new());
+        let alice = Nick("alice".to_string());
+        user_connections.add_user(&alice, service("alice")).unwrap();
+        user_connections.set_away(&alice, Some("lunch".to_string()));
+        let host = host(&user_connections);
+        host.join(channel("#lobby")).unwrap();
+        host.join(channel("#games")).unwrap();
+        user_connections
+            .add_user_to_channel(&alice, &Channel("#lobby".to_string()))
+            .unwrap();
+        host.part(channel("#games")).unwrap();
+
+        let channels = host.channels();
+        assert_eq!(channels.len(), 1);
+        assert_eq!(channels[0].channel.0, "#lobby");
+        assert_eq!(channels[0].member_count, 2);
+        assert!(host.channel(channel("#games")).is_none());
+
+        let members = host.members(channel("#lobby"));
+        let members = members
+            .iter()
+            .map(|nick| nick.0.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(members, vec!["alice", "greeter"]);
+
+        let alice = host.user(RNick("alice".into())).unwrap();
+        assert_eq!(alice.channels.len(), 1);
+        assert_eq!(alice.away_message, ROption::RSome("lunch".into()));
+        assert!(host.user(RNick("nobody".into())).is_none());
+    }
+
+    #[test]
+    fn test_retiring_removes_the_service() {
+        let user_connections = Arc::new(UserConnections::new());
+        let pl_name = PluginName::try_from("/greeter".to_string()).unwrap();
+        let host = Host::for_plugin(
+            &pl_name,
+            TimerWheel::start(),
+            user_connections.clone(),
+            &std::env::temp_dir(),
+        );
+
+        host.to_ref().join(channel("#lobby")).unwrap();
+        host.retire();
+
+        let greeter = Nick("greeter".to_string());
+        assert!(user_connections.user_snapshot(&greeter).is_none());
+        assert!(host.to_ref().join(channel("#lobby")).is_ok());
+    }
+
+    #[test]
+    fn test_retiring_cancels_the_plugins_timers() {
+        let timers = TimerWheel::start();
+        let pl_name = PluginName::try_from("/greeter".to_string()).unwrap();
+        let host = Host::for_plugin(
+            &pl_name,
+            timers.clone(),
+            Arc::new(UserConnections::new()),
+            &std::env::temp_dir(),
+        );
+        let reply = || RPluginReply {
+            target: RTarget::RUser(RNick("nobody".into())),
+            message: "later".into(),
+        };
+
+        let every_minute = host
+            .to_ref()
+            .schedule(60_000, ROption::RSome(60_000), reply());
+        assert!(timers.is_pending(every_minute.0));
+        host.retire();
+        assert!(!timers.is_pending(every_minute.0));
+
+        let too_late = host.to_ref().schedule(60_000, ROption::RNone, reply());
+        assert!(!timers.is_pending(too_late.0));
+    }
+
+    #[test]
+    fn test_join_fails_when_nick_is_taken() {
+        let user_connections = Arc::new(UserConnections::new());
+        let greeter = Nick("greeter".to_string());
+        user_connections
+            .add_user(&greeter, service("greeter"))
+            .unwrap();
+
+        assert!(host(&user_connections).join(channel("#lobby")).is_err());
+    }
+
+    #[test]
+    fn test_plugins_only_cancel_their_own_timers() {
+        let user_connections = Arc::new(UserConnections::new());
+        let timers = TimerWheel::start();
+        let host = |pl_name: &str| {
+            let pl_name = PluginName::try_from(pl_name.to_string()).unwrap();
+            Host::for_plugin(
+                &pl_name,
+                timers.clone(),
+                user_connections.clone(),
+                &std::env::temp_dir(),
+            )
+            .to_ref()
+        };
+        let (greeter, remind) = (host("/greeter"), host("/remind"));
+        let reply = || RPluginReply {
+            target: RTarget::RUser(RNick("nobody".into())),
+            message: "later".into(),
+        };
+
+        let handle = greeter.schedule(60_000, ROption::RNone, reply());
+        assert!(!remind.cancel(handle));
+        assert!(greeter.cancel(handle));
+        assert!(!greeter.cancel(handle));
+    }
+
+    #[test]
+    fn test_plugins_have_a_limited_number_of_timers() {
+        let timers = TimerWheel::start();
+        let pl_name = PluginName::try_from("/greeter".to_string()).unwrap();
+        let host = Host::for_plugin(
+            &pl_name,
+            timers.clone(),
+            Arc::new(UserConnections::new()),
+            &std::env::temp_dir(),
+        );
+        let reply = || RPluginReply {
+            target: RTarget::RUser(RNick("nobody".into())),
+            message: "later".into(),
+        };
+
+        let handles = (0..MAX_TIMERS)
+            .map(|_| host.try_schedule(60_000, ROption::RNone, reply()).unwrap())
+            .collect::<Vec<_>>();
+        assert!(host.try_schedule(60_000, ROption::RNone, reply()).is_err());
+        let handle = host.schedule(60_000, ROption::RNone, reply());
+        assert!(!timers.is_pending(handle.0));
+
+        // Cancelling one makes room for another
+        assert!(host.cancel(handles[0]));
+        assert!(host.try_schedule(60_000, ROption::RNone, reply()).is_ok());
+        host.retire();
+    }
+
+    #[test]
+    fn test_fired_timers_are_forgotten() {
+        let pl_name = PluginName::try_from("/greeter".to_string()).unwrap();
+        let host = Host::for_plugin(
+            &pl_name,
+            TimerWheel::start(),
+            Arc::new(UserConnections::new()),
+            &std::env::temp_dir(),
+        );
+        let reply = || RPluginReply {
+            target: RTarget::RUser(RNick("nobody".into())),
+            message: "now".into(),
+        };
+
+        let once = host.try_schedule(0, ROption::RNone, reply()).unwrap();
+        let every_tick = host.try_schedule(0, ROption::RSome(0), reply()).unwrap();
+        std::thread::sleep(Duration::from_millis(500));
+
+        let scheduled = host.scheduled.lock().unwrap().clone();
+        assert_eq!(scheduled, HashSet::from([every_tick.0]));
+        assert!(!host.cancel(once));
+        host.retire();
+    }
+
+    #[test]
+    fn test_send_to_missing_user_fails() {
+        let user_connections = Arc::new(UserConnections::new());
+        let nobody = RTarget::RUser(RNick("nobody".into()));
+
+        assert!(host(&user_connections).send(nobody, "hi".into()).is_err());
+    }
+}
@@ -0,0 +1,443 @@
+//! # Process plugins
+//! A plugin which is a program of its own, so it may be written in any language, and a crash
+//! only takes down its own process. The server starts the program, then the two exchange
+//! JSON-RPC 2.0 messages, one per line, over the program's stdin and stdout.
+//! Anything the program writes to stderr ends up in the server's log.
+//!
+//! The messages are those of `plugin_protocol`, with the plugin's answers and its calls of the host
+//! sent as JSON-RPC responses and requests, and hooked messages as notifications, which are not answered.
+//! A plugin whose process exits is started again, and initialised with the same settings,
+//! after a delay which grows while it keeps exiting. So is one which stops reading what it is sent,
+//! or which writes a line longer than `MAX_LINE_LENGTH`, as its process is killed.
+
+use crate::plugin_protocol::{self, CallError, Description, HOST_ERROR};
+use crate::plugin_runtime::{PluginMetadata, PluginRuntime};
+use anyhow::anyhow;
+use common::plugin::{PluginHostRef, RVerdict};
+use common::types::{Invocation, Message, Nick, PluginMsg, PluginName, PluginReply};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a plugin which has exited waits before it is started again, the first time.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+/// The longest a plugin waits to be started again. One which has run for at least this long
+/// before exiting is started again after `RESTART_DELAY`.
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+/// How many messages may wait to be written to a plugin before it is taken to have stopped reading.
+const MAX_QUEUED_MESSAGES: usize = 256;
+
+/// The longest line, in bytes, a plugin may write.
+const MAX_LINE_LENGTH: usize = 1024 * 1024;
+
+type Response = Result<Value, String>;
+
+/// One run of the plugin's program.
+struct Process {
+    child: Mutex<Child>,
+    /// Lines waiting to be written to the process's stdin, which a thread of its own does,
+    /// so that no caller waits on a process which has stopped reading
+    outbox: SyncSender<String>,
+    /// Requests awaiting their response, by id
+    pending: Mutex<HashMap<u64, mpsc::Sender<Response>>>,
+    next_id: AtomicU64,
+    started: Instant,
+    exited: AtomicBool,
+}
+
+impl Process {
+    fn spawn(path: &Path) -> anyhow::Result<(Arc<Process>, ChildStdout)> {
+        let mut child = Command::new(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|err| anyhow!("{}: {err}", path.display()))?;
+
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let (outbox, lines) = mpsc::sync_channel(MAX_QUEUED_MESSAGES);
+        Self::write_all(stdin, lines);
+        let process = Arc::new(Process {
+            child: Mutex::new(child),
+            outbox,
+            pending: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+            started: Instant::now(),
+            exited: AtomicBool::new(false),
+        });
+
+        Ok((process, stdout))
+    }
+
+    /// Writes each line to the process on a thread of its own, until the process stops reading or exits.
+    fn write_all(mut stdin: ChildStdin, lines: mpsc::Receiver<String>) {
+        thread::Builder::new()
+            .name("plugin-process-writer".to_string())
+            .spawn(move || {
+                for line in lines {
+                    if writeln!(stdin, "{line}")
+                        .and_then(|_| stdin.flush())
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+            })
+            .expect("failed to spawn plugin process writer thread");
+    }
+
+    /// Queues a message to be written to the process.
+    /// A process which has fallen too far behind is killed, so that it is started again.
+    fn write(&self, message: &Value) -> Result<(), String> {
+        match self.outbox.try_send(message.to_string()) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.kill();
+                Err("The plugin stopped reading what it is sent".to_string())
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                Err("The plugin could not be written to".to_string())
+            }
+        }
+    }
+
+    fn pending(&self) -> MutexGuard<'_, HashMap<u64, mpsc::Sender<Response>>> {
+        self.pending.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Sends a request, waiting up to `timeout` for its response.
+    fn request(&self, method: &str, params: Value, timeout: Duration) -> Response {
+        if self.exited.load(Ordering::Acquire) {
+            return Err("The plugin exited, and is being started again".to_string());
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::channel();
+        self.pending().insert(id, sender);
+
+        let request = json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params});
+        if let Err(err) = self.write(&request) {
+            self.pending().remove(&id);
+            return Err(err);
+        }
+
+        match receiver.recv_timeout(timeout) {
+            Ok(response) => response,
+            Err(RecvTimeoutError::Timeout) => {
+                self.pending().remove(&id);
+                Err(format!("The plugin did not answer {method} in time"))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err("The plugin exited".to_string()),
+        }
+    }
+
+    fn notify(&self, method: &str, params: Value) {
+        // A notification which cannot be written is lost along with the process
+        let _ = self.write(&json!({"jsonrpc": "2.0", "method": method, "params": params}));
+    }
+
+    fn respond(&self, id: Value, result: Result<Value, CallError>) {
+        let mut response = plugin_protocol::answer_json(result);
+        response["jsonrpc"] = json!("2.0");
+        response["id"] = id;
+        let _ = self.write(&response);
+    }
+
+    /// Hands a response to the request waiting on it, if it is still waiting.
+    fn settle(&self, response: &Value) {
+        let Some(id) = response.get("id").and_then(Value::as_u64) else {
+            return;
+        };
+        let Some(sender) = self.pending().remove(&id) else {
+            return;
+        };
+
+        let _ = sender.send(plugin_protocol::parse_answer(response));
+    }
+
+    fn kill(&self) {
+        let mut child = self.child.lock().unwrap_or_else(PoisonError::into_inner);
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+/// A plugin run as a process, which is started again whenever it exits until the plugin is unloaded.
+pub struct ProcessPlugin {
+    path: PathBuf,
+    description: Description,
+    timeout: Duration,
+    process: Mutex<Arc<Process>>,
+    /// The host and settings the plugin was initialised with, which it is initialised with again after a restart
+    session: OnceLock<(PluginHostRef, BTreeMap<String, String>)>,
+    /// The number of times in a row the plugin has exited soon after starting
+    restarts: AtomicU32,
+    stopped: AtomicBool,
+}
+
+impl ProcessPlugin {
+    /// Starts the program at `path`, and asks it to describe itself.
+    pub fn spawn(path: &Path, timeout: Duration) -> anyhow::Result<Arc<ProcessPlugin>> {
+        let (process, stdout) = Process::spawn(path)?;
+
+        // The process is read from before it is asked anything, so that its answer is heard
+        let mut described = Ok(());
+        let plugin = Arc::new_cyclic(|plugin| {
+            Self::read(plugin.clone(), process.clone(), stdout);
+            let description = process
+                .request("describe", Value::Null, timeout)
+                .and_then(Description::parse);
+
+            ProcessPlugin {
+                path: path.to_path_buf(),
+                description: description.unwrap_or_else(|err| {
+                    described = Err(err);
+                    Description::default()
+                }),
+                timeout,
+                process: Mutex::new(process.clone()),
+                session: OnceLock::new(),
+                restarts: AtomicU32::new(0),
+                stopped: AtomicBool::new(false),
+            }
+        });
+
+        // Dropping the plugin stops its process
+        described.map_err(|err| anyhow!("{}: describe failed: {err}", path.display()))?;
+        Ok(plugin)
+    }
+
+    fn process(&self) -> Arc<Process> {
+        self.process
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn request(&self, method: &str, params: Value) -> Response {
+        self.process().request(method, params, self.timeout)
+    }
+
+    /// Reads everything the process says on a thread of its own, until it exits.
+    fn read(plugin: Weak<ProcessPlugin>, process: Arc<Process>, stdout: ChildStdout) {
+        thread::Builder::new()
+            .name("plugin-process".to_string())
+            .spawn(move || {
+                let mut stdout = BufReader::new(stdout);
+                loop {
+                    // A line is read a byte past the limit, to tell one which is too long from one which is not
+                    let mut line = String::new();
+                    let limit = MAX_LINE_LENGTH as u64 + 1;
+                    match stdout.by_ref().take(limit).read_line(&mut line) {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) if line.len() > MAX_LINE_LENGTH => {
+                            error!(
+                                "A plugin process wrote a line longer than {MAX_LINE_LENGTH} bytes"
+                            );
+                            break;
+                        }
+                        Ok(_) => {}
+                    }
+
+                    let message = match serde_json::from_str::<Value>(&line) {
+                        Ok(message) => message,
+                        Err(err) => {
+                            warn!("A plugin process sent something which is not JSON: {err}");
+                            continue;
+                        }
+                    };
+
+                    match message.get("method").and_then(Value::as_str) {
+                        Some(method) => {
+                            let result = match plugin.upgrade() {
+                                Some(plugin) => plugin.serve(method, &message["params"]),
+                                None => Err((HOST_ERROR, "The plugin is unloaded".to_string())),
+                            };
+                            if let Some(id) = message.get("id") {
+                                process.respond(id.clone(), result);
+                            }
+                        }
+                        None => process.settle(&message),
+                    }
+                }
+
+                // Anyone still waiting is told the process has gone
+                process.exited.store(true, Ordering::Release);
+                process.pending().clear();
+                process.kill();
+                if let Some(plugin) = plugin.upgrade() {
+                    plugin.restart(&process);
+                }
+            })
+            .expect("failed to spawn plugin process thread");
+    }
+
+    /// Starts the program again once the process has exited, unless the plugin has been stopped.
+    fn restart(self: &Arc<Self>, exited: &Process) {
+        let pl_name = &self.description.name;
+        if exited.started.elapsed() >= MAX_RESTART_DELAY {
+            self.restarts.store(0, Ordering::Relaxed);
+        }
+
+        while !self.stopped.load(Ordering::Acquire) {
+            let restarts = self.restarts.fetch_add(1, Ordering::Relaxed);
+            let delay = RESTART_DELAY
+                .saturating_mul(2u32.saturating_pow(restarts))
+                .min(MAX_RESTART_DELAY);
+            warn!(
+                "{pl_name} exited, starting it again in {}s",
+                delay.as_secs()
+            );
+            thread::sleep(delay);
+
+            if self.stopped.load(Ordering::Acquire) {
+                return;
+            }
+
+            let (process, stdout) = match Process::spawn(&self.path) {
+                Ok(spawned) => spawned,
+                Err(err) => {
+                    error!("{pl_name} could not be started again: {err}");
+                    continue;
+                }
+            };
+            *self.process.lock().unwrap_or_else(PoisonError::into_inner) = process.clone();
+            Self::read(Arc::downgrade(self), process.clone(), stdout);
+
+            // A plugin which was never initialised is not initialised now either
+            let Some((_, settings)) = self.session.get() else {
+                return;
+            };
+            match process.request("init", plugin_protocol::init_params(settings), self.timeout) {
+                Ok(_) => {
+                    info!("{pl_name} has been started again");
+                    return;
+                }
+                // The process is stopped, so its reader sees it exit and tries again
+                Err(err) => {
+                    error!("{pl_name} failed to initialise after starting again: {err}");
+                    process.kill();
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Carries out a request the plugin made of the host.
+    fn serve(&self, method: &str, params: &Value) -> Result<Value, CallError> {
+        let (host, _) = self.session.get().ok_or_else(|| {
+            (
+                HOST_ERROR,
+                "The plugin has not been initialised".to_string(),
+            )
+        })?;
+        plugin_protocol::serve(host, method, params)
+    }
+}
+
+impl Drop for ProcessPlugin {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Release);
+        self.process().kill();
+    }
+}
+
+/// The plugin is shared with its reader threads, which hold it weakly so that unloading it stops them.
+impl PluginRuntime for Arc<ProcessPlugin> {
+    fn name(&self) -> PluginName {
+        PluginName(self.description.name.clone())
+    }
+
+    fn metadata(&self) -> PluginMetadata {
+        self.description.metadata()
+    }
+
+    fn verbs(&self) -> Vec<String> {
+        self.description.verbs.clone()
+    }
+
+    fn triggers(&self) -> Vec<String> {
+        self.description.triggers.clone()
+    }
+
+    fn takes_settings(&self) -> bool {
+        true
+    }
+
+    fn init(&self, host: PluginHostRef, settings: &BTreeMap<String, String>) -> Result<(), String> {
+        if self.session.set((host, settings.clone())).is_err() {
+            return Err("The plugin has already been initialised".to_string());
+        }
+
+        self.request("init", plugin_protocol::init_params(settings))
+            .map(|_| ())
+    }
+
+    fn handle(
+        &self,
+        _: PluginHostRef,
+        invocation: Invocation,
+        plugin_msg: PluginMsg,
+    ) -> Result<Option<PluginReply>, String> {
+        let reply = self.request(
+            "handle",
+            plugin_protocol::handle_params(invocation, plugin_msg),
+        )?;
+        plugin_protocol::parse_reply(reply)
+    }
+
+    fn hooks(&self, message: &Message) -> bool {
+        self.description.hooks(message)
+    }
+
+    fn notify(&self, _: PluginHostRef, sender: Nick, message: Message) {
+        if let Some((hook, params)) = plugin_protocol::notification(sender, message) {
+            self.process().notify(hook, params);
+        }
+    }
+
+    fn intercepts(&self) -> bool {
+        self.description.intercepts
+    }
+
+    fn intercept(&self, _: PluginHostRef, sender: Nick, message: Message) -> RVerdict {
+        let Some(params) = plugin_protocol::intercept_params(sender, message) else {
+            return RVerdict::Allow;
+        };
+
+        // An interceptor which cannot answer lets the message through as it is
+        let verdict = match self.request("intercept", params) {
+            Ok(verdict) => verdict,
+            Err(err) => {
+                warn!(
+                    "{} could not intercept a message: {err}",
+                    self.description.name
+                );
+                return RVerdict::Allow;
+            }
+        };
+
+        plugin_protocol::parse_verdict(&verdict)
+    }
+
+    fn deinit(&self, _: PluginHostRef) {
+        // The plugin is stopped first, so that the process exiting is not taken for a crash
+        self.stopped.store(true, Ordering::Release);
+        if let Err(err) = self.request("deinit", Value::Null) {
+            warn!(
+                "{} could not be deinitialised: {err}",
+                self.description.name
+            );
+        }
+        self.process().kill();
+    }
+}
@@ -0,0 +1,326 @@
+//! # Plugin protocol
+//! The JSON messages plugins which are not native libraries are spoken to with,
+//! whichever way they are carried to the plugin.
+//!
+//! The server calls these methods of the plugin:
+//! - `describe`, answered with `{"name": "/name"}`, which may also have `version`, `description`,
+//!   `usage` and `author`, the `verbs` and `triggers` it answers to, the `hooks` it wants, from
+//!   `join`, `part`, `quit`, `privmsg` and `nick`, and `"intercepts": true` if it intercepts messages
+//! - `init` with `{"settings": {...}}`
+//! - `handle` with the invocation, such as `{"sender": "tom", "real_name": "Tom", "hostmask": "tom!tom@::1",
+//!   "origin": {"channel": "#lobby"}, "account": null, "timestamp_ms": 0, "args": ["hi"]}`,
+//!   answered with `null` or a reply such as `{"target": {"user": "tom"}, "message": "hello"}`
+//! - `intercept` with `{"sender": "tom", "privmsg": {"target": {...}, "message": "hi"}}`
+//!   or `{"sender": "tom", "join": {"channel": "#lobby"}}`, answered with `null` to allow it,
+//!   `{"modify": "text"}`, or `{"deny": {"numeric": 404, "reason": "..."}}`
+//! - `deinit`
+//!
+//! Hooked messages are sent without expecting an answer, named after the hook:
+//! `join` and `part` with `{"sender", "channel"}`, `quit` with `{"sender", "message"}`,
+//! `privmsg` with `{"sender", "target", "message"}`, and `nick` with `{"sender", "nick"}`.
+//!
+//! The plugin may call the host's methods at any time after `init`:
+//! `send`, `join`, `part`, `schedule`, `cancel`, `channels`, `channel`, `members`, `user`,
+//! `store_get`, `store_set`, `store_remove` and `store_keys`, whose parameters are named as in
+//! `common::plugin::PluginHost`.
+//!
+//! Every answer is either `{"result": ...}` or `{"error": {"code": -32000, "message": "..."}}`.
+
+use crate::plugin_runtime::{server_info, PluginMetadata};
+use abi_stable::std_types::{ROption, RResult, RString};
+use common::plugin::{PluginHostRef, RDenial, RPluginReply, RTimerHandle, RVerdict};
+use common::types::{Channel, Invocation, Message, Nick, PluginMsg, PluginReply, Target};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+// JSON-RPC error codes
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const HOST_ERROR: i64 = -32000;
+
+/// An error answering a call, with its code.
+pub type CallError = (i64, String);
+
+/// The answer to `describe`.
+#[derive(Deserialize, Default)]
+pub struct Description {
+    pub name: String,
+    version: Option<String>,
+    description: Option<String>,
+    usage: Option<String>,
+    author: Option<String>,
+    #[serde(default)]
+    pub verbs: Vec<String>,
+    #[serde(default)]
+    pub triggers: Vec<String>,
+    #[serde(default)]
+    hooks: Vec<String>,
+    #[serde(default)]
+    pub intercepts: bool,
+}
+
+impl Description {
+    pub fn parse(description: Value) -> Result<Description, String> {
+        serde_json::from_value(description).map_err(|err| err.to_string())
+    }
+
+    pub fn metadata(&self) -> PluginMetadata {
+        PluginMetadata {
+            version: self.version.clone(),
+            description: self.description.clone(),
+            usage: self.usage.clone(),
+            author: self.author.clone(),
+        }
+    }
+
+    /// Whether the plugin asked to hear about this kind of message.
+    pub fn hooks(&self, message: &Message) -> bool {
+        hook_name(message).is_some_and(|hook| self.hooks.iter().any(|h| h == hook))
+    }
+}
+
+fn target_json(target: &Target) -> Value {
+    match target {
+        Target::Channel(channel) => json!({ "channel": channel.0 }),
+        Target::User(nick) => json!({ "user": nick.0 }),
+    }
+}
+
+fn parse_target(target: &Value) -> Option<Target> {
+    if let Some(channel) = target.get("channel").and_then(Value::as_str) {
+        return Some(Target::Channel(Channel(channel.to_string())));
+    }
+    let nick = target.get("user").and_then(Value::as_str)?;
+    Some(Target::User(Nick(nick.to_string())))
+}
+
+/// The name of the hook for this kind of message, if it has one.
+pub fn hook_name(message: &Message) -> Option<&'static str> {
+    match message {
+        Message::Join(_) => Some("join"),
+        Message::Part(_) => Some("part"),
+        Message::Quit(_) => Some("quit"),
+        Message::PrivMsg(_) => Some("privmsg"),
+        Message::Nick(_) => Some("nick"),
+        _ => None,
+    }
+}
+
+/// The result of an answer, or the error it carries.
+pub fn parse_answer(answer: &Value) -> Result<Value, String> {
+    match answer.get("error") {
+        Some(error) => Err(error
+            .get("message")
+            .and_then(Value::as_str)
+            .unwrap_or("failed without saying why")
+            .to_string()),
+        None => Ok(answer.get("result").cloned().unwrap_or(Value::Null)),
+    }
+}
+
+pub fn answer_json(answer: Result<Value, CallError>) -> Value {
+    match answer {
+        Ok(result) => json!({ "result": result }),
+        Err((code, message)) => json!({"error": {"code": code, "message": message}}),
+    }
+}
+
+pub fn init_params(settings: &impl serde::Serialize) -> Value {
+    json!({ "settings": settings })
+}
+
+pub fn handle_params(invocation: Invocation, plugin_msg: PluginMsg) -> Value {
+    let server = server_info();
+    json!({
+        "sender": invocation.sender.0,
+        "real_name": invocation.real_name,
+        "hostmask": invocation.hostmask,
+        "origin": target_json(&invocation.origin),
+        "account": invocation.account,
+        "timestamp_ms": invocation.timestamp_ms,
+        "server": {"name": server.name.as_str(), "version": server.version.as_str()},
+        "args": plugin_msg.args,
+    })
+}
+
+pub fn parse_reply(reply: Value) -> Result<Option<PluginReply>, String> {
+    if reply.is_null() {
+        return Ok(None);
+    }
+
+    let target = reply.get("target").and_then(parse_target);
+    let message = reply.get("message").and_then(Value::as_str);
+    match (target, message) {
+        (Some(target), Some(message)) => Ok(Some(PluginReply {
+            target,
+            message: message.to_string(),
+        })),
+        _ => Err("The plugin's reply has no target or message".to_string()),
+    }
+}
+
+/// The hook a message is sent to, and what it is sent with.
+pub fn notification(sender: Nick, message: Message) -> Option<(&'static str, Value)> {
+    let hook = hook_name(&message)?;
+    let params = match message {
+        Message::Join(msg) => json!({"sender": sender.0, "channel": msg.channel.0}),
+        Message::Part(msg) => json!({"sender": sender.0, "channel": msg.channel.0}),
+        Message::Quit(msg) => json!({"sender": sender.0, "message": msg.message}),
+        Message::PrivMsg(msg) => json!({
+            "sender": sender.0,
+            "target": target_json(&msg.target),
+            "message": msg.message,
+        }),
+        Message::Nick(msg) => json!({"sender": sender.0, "nick": msg.nick.0}),
+        _ => return None,
+    };
+
+    Some((hook, params))
+}
+
+/// What `intercept` is sent with, for the only messages which can be intercepted.
+/// A NOTICE is sent as a PRIVMSG.
+pub fn intercept_params(sender: Nick, message: Message) -> Option<Value> {
+    match message {
+        Message::PrivMsg(msg) | Message::Notice(msg) => Some(json!({
+            "sender": sender.0,
+            "privmsg": {"target": target_json(&msg.target), "message": msg.message},
+        })),
+        Message::Join(msg) => Some(json!({"sender": sender.0, "join": {"channel": msg.channel.0}})),
+        _ => None,
+    }
+}
+
+pub fn parse_verdict(verdict: &Value) -> RVerdict {
+    if let Some(text) = verdict.get("modify").and_then(Value::as_str) {
+        return RVerdict::Modify(text.into());
+    }
+
+    match verdict.get("deny") {
+        Some(denial) => RVerdict::Deny(RDenial {
+            numeric: denial
+                .get("numeric")
+                .and_then(Value::as_u64)
+                .and_then(|numeric| u16::try_from(numeric).ok())
+                .unwrap_or(404),
+            reason: denial
+                .get("reason")
+                .and_then(Value::as_str)
+                .unwrap_or("Denied")
+                .into(),
+        }),
+        None => RVerdict::Allow,
+    }
+}
+
+/// Carries out a call the plugin made of the host.
+pub fn serve(host: &PluginHostRef, method: &str, params: &Value) -> Result<Value, CallError> {
+    let string = |name: &str| -> Result<String, CallError> {
+        params
+            .get(name)
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| (INVALID_PARAMS, format!("{method} expects a string {name}")))
+    };
+    let number = |name: &str| -> Result<u64, CallError> {
+        params
+            .get(name)
+            .and_then(Value::as_u64)
+            .ok_or_else(|| (INVALID_PARAMS, format!("{method} expects a number {name}")))
+    };
+    let target = || -> Result<Target, CallError> {
+        params
+            .get("target")
+            .and_then(parse_target)
+            .ok_or_else(|| (INVALID_PARAMS, format!("{method} expects a target")))
+    };
+    let done = |result: RResult<(), RString>| {
+        Result::from(result)
+            .map(|_| Value::Null)
+            .map_err(|err| (HOST_ERROR, String::from(err)))
+    };
+
+    match method {
+        "send" => done(host.send(target()?.into(), string("message")?.into())),
+        "join" => done(host.join(Channel(string("channel")?).into())),
+        "part" => done(host.part(Channel(string("channel")?).into())),
+        "schedule" => {
+            let reply = RPluginReply::from(PluginReply {
+                target: target()?,
+                message: string("message")?,
+            });
+            let interval_ms = params.get("interval_ms").and_then(Value::as_u64);
+            Result::from(host.try_schedule(number("delay_ms")?, interval_ms.into(), reply))
+                .map(|handle| json!(handle.0))
+                .map_err(|err| (HOST_ERROR, String::from(err)))
+        }
+        "cancel" => Ok(json!(host.cancel(RTimerHandle(number("handle")?)))),
+        "channels" => Ok(host
+            .channels()
+            .into_iter()
+            .map(|info| json!({"channel": info.channel.0.as_str(), "member_count": info.member_count}))
+            .collect()),
+        "channel" => Ok(match host.channel(Channel(string("channel")?).into()) {
+            ROption::RSome(info) => {
+                json!({"channel": info.channel.0.as_str(), "member_count": info.member_count})
+            }
+            ROption::RNone => Value::Null,
+        }),
+        "members" => Ok(host
+            .members(Channel(string("channel")?).into())
+            .into_iter()
+            .map(|nick| json!(nick.0.as_str()))
+            .collect()),
+        "user" => Ok(match host.user(Nick(string("nick")?).into()) {
+            ROption::RSome(user) => json!({
+                "nick": user.nick.0.as_str(),
+                "channels": user.channels.iter().map(|channel| channel.0.as_str()).collect::<Vec<_>>(),
+                "away_message": user.away_message.into_option().map(String::from),
+            }),
+            ROption::RNone => Value::Null,
+        }),
+        "store_get" => Result::from(host.store_get(string("key")?.into()))
+            .map(|value| json!(value.into_option().map(String::from)))
+            .map_err(|err| (HOST_ERROR, String::from(err))),
+        "store_set" => done(host.store_set(string("key")?.into(), string("value")?.into())),
+        "store_remove" => Result::from(host.store_remove(string("key")?.into()))
+            .map(|removed| json!(removed))
+            .map_err(|err| (HOST_ERROR, String::from(err))),
+        "store_keys" => Result::from(host.store_keys(string("prefix")?.into()))
+            .map(|keys| keys.iter().map(|key| json!(key.as_str())).collect())
+            .map_err(|err| (HOST_ERROR, String::from(err))),
+        _ => Err((METHOD_NOT_FOUND, format!("There is no method {method}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_targets_round_trip() {
+        let channel = Target::Channel(Channel("#lobby".to_string()));
+        let user = Target::User(Nick("tom".to_string()));
+
+        assert_eq!(parse_target(&target_json(&channel)), Some(channel));
+        assert_eq!(parse_target(&target_json(&user)), Some(user));
+        assert_eq!(parse_target(&json!({"server": "iris"})), None);
+    }
+
+    #[test]
+    fn test_descriptions_only_need_a_name() {
+        let description = Description::parse(json!({"name": "/echo"})).unwrap();
+        assert_eq!(description.name, "/echo");
+        assert!(description.verbs.is_empty());
+        assert!(!description.intercepts);
+
+        let description = Description::parse(json!({
+            "name": "/echo",
+            "version": "1.0",
+            "hooks": ["join"],
+        }))
+        .unwrap();
+        assert_eq!(description.version.as_deref(), Some("1.0"));
+        assert_eq!(description.hooks, vec!["join".to_string()]);
+    }
+}
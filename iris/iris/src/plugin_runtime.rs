@@ -0,0 +1,287 @@
+//! # Plugin runtimes
+//! The ways a plugin can be run, behind the one interface `PluginHandler` calls.
+//! Which runtime a plugin uses is decided by the file it is loaded from:
+//! a `.so`, `.dylib` or `.dll` is a native library, a `.wasm` or `.wat` file is a WebAssembly module,
+//! a `.rhai` file is a script, and anything else is a program run as a process.
+
+use crate::config::ServerConfig;
+use crate::plugin_process::ProcessPlugin;
+use crate::plugin_script::ScriptPlugin;
+use crate::plugin_wasm::WasmPlugin;
+use abi_stable::std_types::{RHashMap, RString};
+use anyhow::anyhow;
+use common::plugin::{
+    load_plugin, PluginHostRef, PluginMod_Ref, RInterceptedMsg, RInvocation, RNick, RServerInfo,
+    RVerdict,
+};
+use common::types::{Invocation, Message, Nick, PluginMsg, PluginName, PluginReply, SERVER_NAME};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use std::{env, fs, process};
+
+/// The extensions of native plugin libraries.
+const LIBRARY_EXTENSIONS: [&str; 3] = ["so", "dylib", "dll"];
+/// The extensions of WebAssembly modules, in the binary or the text format.
+const WASM_EXTENSIONS: [&str; 2] = ["wasm", "wat"];
+const SCRIPT_EXTENSION: &str = "rhai";
+
+/// What plugins are told about the server they are running in.
+pub fn server_info() -> RServerInfo {
+    RServerInfo {
+        name: SERVER_NAME.into(),
+        version: env!("CARGO_PKG_VERSION").into(),
+    }
+}
+
+/// What a plugin says about itself, asked for once when it is loaded.
+pub struct PluginMetadata {
+    pub version: Option<String>,
+    pub description: Option<String>,
+    pub usage: Option<String>,
+    pub author: Option<String>,
+}
+
+/// A loaded plugin, however it is run. Calls are made from the worker pool, under the supervisor,
+/// and may be made concurrently.
+pub trait PluginRuntime: Send + Sync {
+    fn name(&self) -> PluginName;
+
+    fn metadata(&self) -> PluginMetadata;
+
+    /// The commands the plugin answers to besides `PLUGIN /name`.
+    fn verbs(&self) -> Vec<String>;
+
+    fn triggers(&self) -> Vec<String>;
+
+    /// Whether the plugin is handed its settings, rather than having them ignored.
+    fn takes_settings(&self) -> bool;
+
+    /// Whether the plugin is reloaded whenever the file it was loaded from changes.
+    fn reloads_on_change(&self) -> bool {
+        false
+    }
+
+    /// Called once, before any other call but those above.
+    fn init(&self, host: PluginHostRef, settings: &BTreeMap<String, String>) -> Result<(), String>;
+
+    fn handle(
+        &self,
+        host: PluginHostRef,
+        invocation: Invocation,
+        plugin_msg: PluginMsg,
+    ) -> Result<Option<PluginReply>, String>;
+
+    /// Whether the plugin wants to hear about this kind of message.
+    fn hooks(&self, message: &Message) -> bool;
+
+    /// Tells the plugin about a message it hooks.
+    fn notify(&self, host: PluginHostRef, sender: Nick, message: Message);
+
+    fn intercepts(&self) -> bool;
+
+    /// Asks the plugin what should happen to a PRIVMSG, NOTICE or JOIN, a NOTICE being put to it as a PRIVMSG.
+    fn intercept(&self, host: PluginHostRef, sender: Nick, message: Message) -> RVerdict;
+
+    /// Called once the plugin has been unloaded, after which it is never called again.
+    fn deinit(&self, host: PluginHostRef);
+}
+
+/// Loads the plugin at `path`, with the runtime its kind of file calls for.
+pub fn open(path: &Path, config: &ServerConfig) -> anyhow::Result<Box<dyn PluginRuntime>> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("");
+
+    let runtime: Box<dyn PluginRuntime> = if is_library(path) {
+        Box::new(load_copy(path)?)
+    } else if WASM_EXTENSIONS.contains(&extension) {
+        Box::new(WasmPlugin::load(path, config)?)
+    } else if extension == SCRIPT_EXTENSION {
+        Box::new(ScriptPlugin::load(path, config)?)
+    } else {
+        let timeout = Duration::from_millis(config.plugin_timeout_ms);
+        Box::new(ProcessPlugin::spawn(path, timeout)?)
+    };
+
+    Ok(runtime)
+}
+
+/// Whether the plugin at `path` is a native library, rather than a module, a script or a program.
+pub fn is_library(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| LIBRARY_EXTENSIONS.contains(&extension))
+}
+
+/// Loads a copy of the library, as the library at `path` itself may already be loaded,
+/// and loading it again would hand back the code already in memory rather than what is on disk.
+fn load_copy(path: &Path) -> anyhow::Result<PluginMod_Ref> {
+    static COPIES: AtomicUsize = AtomicUsize::new(0);
+
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a library", path.display()))?;
+    let copy = env::temp_dir().join(format!(
+        "iris-{}-{}-{}",
+        process::id(),
+        COPIES.fetch_add(1, Ordering::Relaxed),
+        file_name.to_string_lossy()
+    ));
+
+    fs::copy(path, &copy).map_err(|err| anyhow!("{}: {err}", path.display()))?;
+    let module = load_plugin(&copy);
+    // Once loaded, the library stays in memory, so the copy is no longer needed
+    let _ = fs::remove_file(&copy);
+
+    module.map_err(|err| anyhow!("{}: {err}", path.display()))
+}
+
+/// A native library built against `common::plugin`.
+/// Fields added to `PluginMod` after the plugin was built are missing, which is treated as the plugin leaving them out.
+impl PluginRuntime for PluginMod_Ref {
+    fn name(&self) -> PluginName {
+        PluginName::from(self.pl_name()())
+    }
+
+    fn metadata(&self) -> PluginMetadata {
+        let ask = |field: Option<Option<extern "C" fn() -> RString>>| {
+            field.flatten().map(|field| String::from(field()))
+        };
+
+        PluginMetadata {
+            version: ask(self.version()),
+            description: ask(self.description()),
+            usage: ask(self.usage()),
+            author: ask(self.author()),
+        }
+    }
+
+    fn verbs(&self) -> Vec<String> {
+        self.verbs()
+            .flatten()
+            .map(|verbs| verbs().into_iter().map(String::from).collect())
+            .unwrap_or_default()
+    }
+
+    fn triggers(&self) -> Vec<String> {
+        self.triggers()
+            .flatten()
+            .map(|triggers| triggers().into_iter().map(String::from).collect())
+            .unwrap_or_default()
+    }
+
+    fn takes_settings(&self) -> bool {
+        self.init_with_config().flatten().is_some()
+    }
+
+    fn init(&self, host: PluginHostRef, settings: &BTreeMap<String, String>) -> Result<(), String> {
+        let init_with_config = match self.init_with_config().flatten() {
+            Some(init_with_config) => init_with_config,
+            None => {
+                self.init()(host);
+                return Ok(());
+            }
+        };
+
+        let settings = settings
+            .iter()
+            .map(|(key, value)| (RString::from(key.as_str()), RString::from(value.as_str())))
+            .collect::<RHashMap<_, _>>();
+        Result::from(init_with_config(host, settings)).map_err(String::from)
+    }
+
+    fn handle(
+        &self,
+        host: PluginHostRef,
+        invocation: Invocation,
+        plugin_msg: PluginMsg,
+    ) -> Result<Option<PluginReply>, String> {
+        let reply = match self.handler_with_context().flatten() {
+            Some(handler) => handler(
+                host,
+                RInvocation::new(invocation, server_info()),
+                plugin_msg.into(),
+            ),
+            None => self.handler()(
+                host,
+                invocation.sender.into(),
+                invocation.real_name.into(),
+                plugin_msg.into(),
+            ),
+        };
+
+        Result::from(reply)
+            .map(|reply| reply.into_option().map(PluginReply::from))
+            .map_err(String::from)
+    }
+
+    fn hooks(&self, message: &Message) -> bool {
+        match message {
+            Message::Join(_) => self.on_join().flatten().is_some(),
+            Message::Part(_) => self.on_part().flatten().is_some(),
+            Message::Quit(_) => self.on_quit().flatten().is_some(),
+            Message::PrivMsg(_) => self.on_privmsg().flatten().is_some(),
+            Message::Nick(_) => self.on_nick().flatten().is_some(),
+            _ => false,
+        }
+    }
+
+    fn notify(&self, host: PluginHostRef, sender: Nick, message: Message) {
+        let sender = RNick::from(sender);
+
+        match message {
+            Message::Join(msg) => {
+                if let Some(hook) = self.on_join().flatten() {
+                    hook(host, sender, msg.into());
+                }
+            }
+            Message::Part(msg) => {
+                if let Some(hook) = self.on_part().flatten() {
+                    hook(host, sender, msg.into());
+                }
+            }
+            Message::Quit(msg) => {
+                if let Some(hook) = self.on_quit().flatten() {
+                    hook(host, sender, msg.into());
+                }
+            }
+            Message::PrivMsg(msg) => {
+                if let Some(hook) = self.on_privmsg().flatten() {
+                    hook(host, sender, msg.into());
+                }
+            }
+            Message::Nick(msg) => {
+                if let Some(hook) = self.on_nick().flatten() {
+                    hook(host, sender, msg.into());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn intercepts(&self) -> bool {
+        self.intercept().flatten().is_some()
+    }
+
+    fn intercept(&self, host: PluginHostRef, sender: Nick, message: Message) -> RVerdict {
+        let intercepted = match message {
+            Message::PrivMsg(msg) | Message::Notice(msg) => RInterceptedMsg::PrivMsg(msg.into()),
+            Message::Join(msg) => RInterceptedMsg::Join(msg.into()),
+            _ => return RVerdict::Allow,
+        };
+
+        match self.intercept().flatten() {
+            Some(intercept) => intercept(host, sender.into(), intercepted),
+            None => RVerdict::Allow,
+        }
+    }
+
+    fn deinit(&self, host: PluginHostRef) {
+        if let Some(deinit) = self.deinit().flatten() {
+            deinit(host);
+        }
+    }
+}
@@ -0,0 +1,505 @@
+//! # Script plugins
+//! A plugin written as a [Rhai](https://rhai.rs) script, for bots too small to be worth compiling.
+//! The script's top level runs once when it is loaded, and the server then calls its functions:
+//! - `describe()`, which returns a map such as `#{name: "/name"}`, with the same fields as the
+//!   answer to `describe` in `plugin_protocol`, besides `hooks` and `intercepts`
+//! - `init(settings)`, `handle(invocation)` and `deinit()`, as in `plugin_protocol`,
+//!   where `handle` returns `()` or a reply such as `#{target: invocation.origin, message: "hello"}`
+//! - `on_join(event)`, `on_part(event)`, `on_quit(event)`, `on_privmsg(event)` and `on_nick(event)`,
+//!   whichever the script wants to hear about
+//! - `intercept(event)`, if the script intercepts messages, which returns `()` to allow the message,
+//!   `#{modify: "text"}` or `#{deny: #{numeric: 404, reason: "..."}}`
+//!
+//! Only `describe` is required. Each function may keep anything it likes in `this`, a map which lasts
+//! until the script is reloaded, and may call the host with `send(target, message)`, `join(channel)`,
+//! `part(channel)`, `schedule(target, message, delay_ms)`, `schedule(target, message, delay_ms, interval_ms)`,
+//! `cancel(handle)`, `channels()`, `channel(channel)`, `members(channel)`, `user(nick)`,
+//! `store_get(key)`, `store_set(key, value)`, `store_remove(key)` and `store_keys(prefix)`.
+//! What it prints goes to the server's log. Scripts cannot import other files.
+//!
+//! Every call may only run for so many operations, build strings, arrays and maps only so large,
+//! and nest its calls only so deep. Rhai checks a map's size as maps are built or combined, not as
+//! single keys are set, which only the operations limit bounds. A script is reloaded whenever its file changes.
+
+use crate::config::ServerConfig;
+use crate::plugin_protocol::{self, Description};
+use crate::plugin_runtime::{PluginMetadata, PluginRuntime};
+use anyhow::anyhow;
+use common::plugin::{PluginHostRef, RVerdict};
+use common::types::{Invocation, Message, Nick, PluginMsg, PluginName, PluginReply};
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::serde::{from_dynamic, to_dynamic};
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, FuncArgs, Map, Scope, AST};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// The host of the call being made, which host functions reach through.
+/// There is none while the script is loaded and describes itself.
+type HostSlot = Arc<Mutex<Option<PluginHostRef>>>;
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// What a script keeps between calls, which only one call may use at a time.
+struct Session {
+    scope: Scope<'static>,
+    this: Dynamic,
+}
+
+/// A plugin run from a script.
+pub struct ScriptPlugin {
+    description: Description,
+    engine: Engine,
+    ast: AST,
+    /// The functions the script defines
+    functions: BTreeSet<String>,
+    host: HostSlot,
+    session: Mutex<Session>,
+}
+
+/// Carries out a call the script made of the host.
+fn call_host(slot: &HostSlot, method: &str, params: Value) -> ScriptResult<Dynamic> {
+    let host = slot
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+        .ok_or("The plugin has not been initialised")?;
+
+    let result = plugin_protocol::serve(&host, method, &params).map_err(|(_, err)| err)?;
+    to_dynamic(result)
+}
+
+/// Adds the host's methods to the engine, each of which calls the host of the call being made.
+fn register_host(engine: &mut Engine, slot: &HostSlot) {
+    let host = slot.clone();
+    engine.register_fn("send", move |target: Dynamic, message: &str| {
+        let target = from_dynamic::<Value>(&target)?;
+        call_host(&host, "send", json!({"target": target, "message": message}))
+    });
+    let host = slot.clone();
+    engine.register_fn("join", move |channel: &str| {
+        call_host(&host, "join", json!({ "channel": channel }))
+    });
+    let host = slot.clone();
+    engine.register_fn("part", move |channel: &str| {
+        call_host(&host, "part", json!({ "channel": channel }))
+    });
+    let host = slot.clone();
+    engine.register_fn(
+        "schedule",
+        move |target: Dynamic, message: &str, delay_ms: i64| {
+            let target = from_dynamic::<Value>(&target)?;
+            let params = json!({"target": target, "message": message, "delay_ms": delay_ms});
+            call_host(&host, "schedule", params)
+        },
+    );
+    let host = slot.clone();
+    engine.register_fn(
+        "schedule",
+        move |target: Dynamic, message: &str, delay_ms: i64, interval_ms: i64| {
+            let target = from_dynamic::<Value>(&target)?;
+            let params = json!({
+                "target": target,
+                "message": message,
+                "delay_ms": delay_ms,
+                "interval_ms": interval_ms,
+            });
+            call_host(&host, "schedule", params)
+        },
+    );
+    let host = slot.clone();
+    engine.register_fn("cancel", move |handle: i64| {
+        call_host(&host, "cancel", json!({ "handle": handle }))
+    });
+    let host = slot.clone();
+    engine.register_fn("channels", move || call_host(&host, "channels", json!({})));
+    let host = slot.clone();
+    engine.register_fn("channel", move |channel: &str| {
+        call_host(&host, "channel", json!({ "channel": channel }))
+    });
+    let host = slot.clone();
+    engine.register_fn("members", move |channel: &str| {
+        call_host(&host, "members", json!({ "channel": channel }))
+    });
+    let host = slot.clone();
+    engine.register_fn("user", move |nick: &str| {
+        call_host(&host, "user", json!({ "nick": nick }))
+    });
+    let host = slot.clone();
+    engine.register_fn("store_get", move |key: &str| {
+        call_host(&host, "store_get", json!({ "key": key }))
+    });
+    let host = slot.clone();
+    engine.register_fn("store_set", move |key: &str, value: &str| {
+        call_host(&host, "store_set", json!({"key": key, "value": value}))
+    });
+    let host = slot.clone();
+    engine.register_fn("store_remove", move |key: &str| {
+        call_host(&host, "store_remove", json!({ "key": key }))
+    });
+    let host = slot.clone();
+    engine.register_fn("store_keys", move |prefix: &str| {
+        call_host(&host, "store_keys", json!({ "prefix": prefix }))
+    });
+}
+
+fn describe_error(err: &EvalAltResult) -> String {
+    match err {
+        EvalAltResult::ErrorTooManyOperations(_) => {
+            "The script ran for too many operations".to_string()
+        }
+        EvalAltResult::ErrorDataTooLarge(what, _) => {
+            format!("The script built a value too large: {what}")
+        }
+        EvalAltResult::ErrorStackOverflow(_) => {
+            "The script nested its calls too deeply".to_string()
+        }
+        err => err.to_string(),
+    }
+}
+
+impl ScriptPlugin {
+    /// Compiles and runs the script at `path`, and asks it to describe itself.
+    pub fn load(path: &Path, config: &ServerConfig) -> anyhow::Result<ScriptPlugin> {
+        let host: HostSlot = Arc::new(Mutex::new(None));
+        let mut engine = Engine::new();
+        engine.set_max_operations(config.script_operations);
+        engine.set_max_string_size(config.script_string_size);
+        engine.set_max_array_size(config.script_array_size);
+        engine.set_max_map_size(config.script_map_size);
+        engine.set_max_call_levels(config.script_call_levels);
+        engine.set_module_resolver(DummyModuleResolver::new());
+        register_host(&mut engine, &host);
+
+        // What the script prints is logged under its file until it has said what it is called
+        let print_prefix = Arc::new(Mutex::new(path.display().to_string()));
+        let prefix = print_prefix.clone();
+        engine.on_print(move |text| {
+            let prefix = prefix.lock().unwrap_or_else(PoisonError::into_inner);
+            info!("{prefix}: {text}");
+        });
+
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|err| anyhow!("{}: {}", path.display(), describe_error(&err)))?;
+        let functions = ast.iter_functions().map(|f| f.name.to_string()).collect();
+
+        let mut scope = Scope::new();
+        engine
+            .run_ast_with_scope(&mut scope, &ast)
+            .map_err(|err| anyhow!("{}: {}", path.display(), describe_error(&err)))?;
+
+        let mut plugin = ScriptPlugin {
+            description: Description::default(),
+            engine,
+            ast,
+            functions,
+            host,
+            session: Mutex::new(Session {
+                scope,
+                this: Map::new().into(),
+            }),
+        };
+        plugin.description = plugin
+            .call(None, "describe", ())
+            .and_then(Description::parse)
+            .map_err(|err| anyhow!("{}: describe failed: {err}", path.display()))?;
+
+        *print_prefix.lock().unwrap_or_else(PoisonError::into_inner) =
+            plugin.description.name.clone();
+
+        Ok(plugin)
+    }
+
+    fn defines(&self, function: &str) -> bool {
+        self.functions.contains(function)
+    }
+
+    /// Calls one of the script's functions, which it must define.
+    fn call(
+        &self,
+        host: Option<PluginHostRef>,
+        function: &str,
+        args: impl FuncArgs,
+    ) -> Result<Value, String> {
+        let mut session = self.session.lock().unwrap_or_else(PoisonError::into_inner);
+        *self.host.lock().unwrap_or_else(PoisonError::into_inner) = host;
+
+        let Session { scope, this } = &mut *session;
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .rewind_scope(true)
+            .bind_this_ptr(this);
+        let result = self
+            .engine
+            .call_fn_with_options::<Dynamic>(options, scope, &self.ast, function, args);
+        *self.host.lock().unwrap_or_else(PoisonError::into_inner) = None;
+
+        let result = result.map_err(|err| describe_error(&err))?;
+        from_dynamic::<Value>(&result).map_err(|err| describe_error(&err))
+    }
+
+    fn to_dynamic(value: Value) -> Result<Dynamic, String> {
+        to_dynamic(value).map_err(|err| describe_error(&err))
+    }
+}
+
+impl PluginRuntime for ScriptPlugin {
+    fn name(&self) -> PluginName {
+        PluginName(self.description.name.clone())
+    }
+
+    fn metadata(&self) -> PluginMetadata {
+        self.description.metadata()
+    }
+
+    fn verbs(&self) -> Vec<String> {
+        self.description.verbs.clone()
+    }
+
+    fn triggers(&self) -> Vec<String> {
+        self.description.triggers.clone()
+    }
+
+    fn takes_settings(&self) -> bool {
+        self.defines("init")
+    }
+
+    fn reloads_on_change(&self) -> bool {
+        true
+    }
+
+    fn init(&self, host: PluginHostRef, settings: &BTreeMap<String, String>) -> Result<(), String> {
+        if !self.defines("init") {
+            return Ok(());
+        }
+
+        let settings = Self::to_dynamic(json!(settings))?;
+        self.call(Some(host), "init", (settings,)).map(|_| ())
+    }
+
+    fn handle(
+        &self,
+        host: PluginHostRef,
+        invocation: Invocation,
+        plugin_msg: PluginMsg,
+    ) -> Result<Option<PluginReply>, String> {
+        if !self.defines("handle") {
+            return Err("The plugin does not handle commands".to_string());
+        }
+
+        let invocation = Self::to_dynamic(plugin_protocol::handle_params(invocation, plugin_msg))?;
+        let reply = self.call(Some(host), "handle", (invocation,))?;
+        plugin_protocol::parse_reply(reply)
+    }
+
+    fn hooks(&self, message: &Message) -> bool {
+        plugin_protocol::hook_name(message).is_some_and(|hook| self.defines(&format!("on_{hook}")))
+    }
+
+    fn notify(&self, host: PluginHostRef, sender: Nick, message: Message) {
+        let Some((hook, params)) = plugin_protocol::notification(sender, message) else {
+            return;
+        };
+
+        let outcome = Self::to_dynamic(params)
+            .and_then(|event| self.call(Some(host), &format!("on_{hook}"), (event,)));
+        if let Err(err) = outcome {
+            warn!("{} failed handling {hook}: {err}", self.description.name);
+        }
+    }
+
+    fn intercepts(&self) -> bool {
+        self.defines("intercept")
+    }
+
+    fn intercept(&self, host: PluginHostRef, sender: Nick, message: Message) -> RVerdict {
+        let Some(params) = plugin_protocol::intercept_params(sender, message) else {
+            return RVerdict::Allow;
+        };
+
+        // An interceptor which cannot answer lets the message through as it is
+        let verdict =
+            Self::to_dynamic(params).and_then(|event| self.call(Some(host), "intercept", (event,)));
+        match verdict {
+            Ok(verdict) => plugin_protocol::parse_verdict(&verdict),
+            Err(err) => {
+                warn!(
+                    "{} could not intercept a message: {err}",
+                    self.description.name
+                );
+                RVerdict::Allow
+            }
+        }
+    }
+
+    fn deinit(&self, host: PluginHostRef) {
+        if !self.defines("deinit") {
+            return;
+        }
+
+        if let Err(err) = self.call(Some(host), "deinit", ()) {
+            warn!(
+                "{} could not be deinitialised: {err}",
+                self.description.name
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin_host::Host;
+    use crate::timer_wheel::TimerWheel;
+    use crate::user_connections::UserConnections;
+    use common::types::{Channel, Target};
+    use std::{env, fs, process};
+
+    fn script(name: &str, source: &str) -> ScriptPlugin {
+        script_with(name, source, &ServerConfig::default())
+    }
+
+    fn script_with(name: &str, source: &str, config: &ServerConfig) -> ScriptPlugin {
+        let path = env::temp_dir().join(format!("iris-script-test-{}-{name}.rhai", process::id()));
+        fs::write(&path, source).unwrap();
+        let plugin = ScriptPlugin::load(&path, config);
+        let _ = fs::remove_file(&path);
+        plugin.unwrap()
+    }
+
+    fn host() -> PluginHostRef {
+        let pl_name = PluginName::try_from("/counter".to_string()).unwrap();
+        Host::for_plugin(
+            &pl_name,
+            TimerWheel::start(),
+            Arc::new(UserConnections::new()),
+            &env::temp_dir(),
+        )
+        .to_ref()
+    }
+
+    fn invocation(args: &[&str]) -> (Invocation, PluginMsg) {
+        let invocation = Invocation {
+            sender: Nick("tom".to_string()),
+            real_name: "Tom".to_string(),
+            hostmask: "tom!tom@::1".to_string(),
+            origin: Target::Channel(Channel("#lobby".to_string())),
+            account: None,
+            timestamp_ms: 0,
+        };
+        let plugin_msg = PluginMsg {
+            plugin_name: PluginName("/counter".to_string()),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        };
+        (invocation, plugin_msg)
+    }
+
+    #[test]
+    fn test_scripts_keep_state_and_reach_the_host() {
+        let plugin = script(
+            "counter",
+            r##"
+            fn describe() { #{name: "/counter", version: "1.0", verbs: ["COUNT"]} }
+            fn init(settings) { join(settings.channel); this.count = 0; }
+            fn handle(invocation) {
+                this.count += 1;
+                #{target: invocation.origin, message: `${invocation.args[0]} ${this.count}`}
+            }
+            fn on_join(event) {}
+            "##,
+        );
+
+        assert_eq!(plugin.name(), PluginName("/counter".to_string()));
+        assert_eq!(plugin.metadata().version.as_deref(), Some("1.0"));
+        assert_eq!(plugin.verbs(), vec!["COUNT".to_string()]);
+        assert!(plugin.takes_settings());
+        assert!(!plugin.intercepts());
+
+        let settings = BTreeMap::from([("channel".to_string(), "#counting".to_string())]);
+        plugin.init(host(), &settings).unwrap();
+        for count in 1..=2 {
+            let (invocation, plugin_msg) = invocation(&["tally"]);
+            let reply = plugin
+                .handle(host(), invocation, plugin_msg)
+                .unwrap()
+                .unwrap();
+            assert_eq!(reply.message, format!("tally {count}"));
+            assert_eq!(reply.target, Target::Channel(Channel("#lobby".to_string())));
+        }
+    }
+
+    #[test]
+    fn test_scripts_are_limited_and_must_describe_themselves() {
+        let plugin = script(
+            "forever",
+            r#"
+            fn describe() { #{name: "/forever"} }
+            fn handle(invocation) { loop {} }
+            "#,
+        );
+
+        let (invocation, plugin_msg) = invocation(&[]);
+        let err = plugin.handle(host(), invocation, plugin_msg).unwrap_err();
+        assert_eq!(err, "The script ran for too many operations");
+
+        let config = ServerConfig {
+            script_string_size: 16,
+            script_array_size: 4,
+            script_map_size: 4,
+            script_call_levels: 8,
+            ..ServerConfig::default()
+        };
+        let plugin = script_with(
+            "greedy",
+            r#"
+            fn describe() { #{name: "/greedy"} }
+            fn deeper(depth) { deeper(depth + 1) }
+            fn fill() {
+                let entries = #{};
+                for key in 0..100 {
+                    let entry = #{};
+                    entry[key.to_string()] = key;
+                    entries += entry;
+                }
+                entries
+            }
+            fn handle(invocation) {
+                switch invocation.args[0] {
+                    "string" => { let text = ""; loop { text += "more"; } }
+                    "array" => { let items = []; loop { items.push(1); } }
+                    "map" => fill(),
+                    "calls" => deeper(0),
+                }
+            }
+            "#,
+            &config,
+        );
+        for (limit, expected) in [
+            (
+                "string",
+                "The script built a value too large: Length of string",
+            ),
+            (
+                "array",
+                "The script built a value too large: Size of array/BLOB",
+            ),
+            (
+                "map",
+                "The script built a value too large: Size of object map",
+            ),
+            ("calls", "The script nested its calls too deeply"),
+        ] {
+            let (greedy, plugin_msg) = self::invocation(&[limit]);
+            let err = plugin.handle(host(), greedy, plugin_msg).unwrap_err();
+            assert_eq!(err, expected);
+        }
+
+        let path =
+            env::temp_dir().join(format!("iris-script-test-{}-nameless.rhai", process::id()));
+        fs::write(&path, "fn handle(invocation) {}").unwrap();
+        assert!(ScriptPlugin::load(&path, &ServerConfig::default()).is_err());
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,228 @@
+//! # Plugin store
+//! Persistent key-value storage for a single plugin, kept in a directory of its own under the data directory.
+//! Each key is a file, named by the key's bytes in hex so that no key can reach outside the directory.
+//! A value is written to a temporary file which is then renamed over the old one,
+//! so a reader (or a crash) only ever sees the old value or the new one.
+//! A plugin may only keep so many keys, and values of up to a certain size, so it cannot fill the disk.
+
+use anyhow::anyhow;
+use common::types::PluginName;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+/// The longest key, in bytes, which keeps its file name well within what filesystems allow.
+const MAX_KEY_LENGTH: usize = 100;
+
+/// The longest value, in bytes.
+const MAX_VALUE_LENGTH: usize = 64 * 1024;
+
+/// The most keys a single plugin may keep.
+const MAX_KEYS: usize = 10_000;
+
+pub struct PluginStore {
+    dir: PathBuf,
+    max_keys: usize,
+    /// Held while writing, so that two writes cannot both take the last free key
+    writing: Mutex<()>,
+}
+
+fn encode(key: &str) -> String {
+    key.bytes().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn decode(file_name: &str) -> Option<String> {
+    if !file_name.len().is_multiple_of(2) {
+        return None;
+    }
+
+    let bytes = (0..file_name.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(file_name.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+impl PluginStore {
+    /// The store for the plugin named `pl_name`, which is only created once something is written to it.
+    pub fn for_plugin(data_dir: &Path, pl_name: &PluginName) -> PluginStore {
+        // Plugins are only loaded with a valid name, which is a single path component once the '/' is taken off
+        PluginStore {
+            dir: data_dir.join(&pl_name.0[1..]),
+            max_keys: MAX_KEYS,
+            writing: Mutex::new(()),
+        }
+    }
+
+    fn path(&self, key: &str) -> anyhow::Result<PathBuf> {
+        if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+            return Err(anyhow!(
+                "Keys must be between 1 and {MAX_KEY_LENGTH} bytes long"
+            ));
+        }
+
+        Ok(self.dir.join(encode(key)))
+    }
+
+    pub fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        match fs::read_to_string(self.path(key)?) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Sets the key to `value`, unless the value is too long, or the key is new and the store is full.
+    pub fn set(&self, key: &str, value: &str) -> anyhow::Result<()> {
+        static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+        let path = self.path(key)?;
+        if value.len() > MAX_VALUE_LENGTH {
+            return Err(anyhow!(
+                "Values may be at most {MAX_VALUE_LENGTH} bytes long"
+            ));
+        }
+
+        let _writing = self.writing.lock().unwrap_or_else(PoisonError::into_inner);
+        if !path.exists() && self.keys("")?.len() >= self.max_keys {
+            return Err(anyhow!("A plugin may keep at most {} keys", self.max_keys));
+        }
+        fs::create_dir_all(&self.dir)?;
+
+        // Temporary files start with a '.', which no key's file name does
+        let temp = self.dir.join(format!(
+            ".{}.{}.tmp",
+            encode(key),
+            TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let written = File::create(&temp).and_then(|mut file| {
+            file.write_all(value.as_bytes())?;
+            file.sync_all()
+        });
+
+        match written.and_then(|_| fs::rename(&temp, &path)) {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                let _ = fs::remove_file(&temp);
+                Err(err.into())
+            }
+        }
+    }
+
+    /// Removes the key, returning whether it was there.
+    pub fn remove(&self, key: &str) -> anyhow::Result<bool> {
+        match fs::remove_file(self.path(key)?) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Every key starting with `prefix`, in order.
+    pub fn keys(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut keys = vec![];
+        for entry in entries {
+            let file_name = entry?.file_name();
+            if let Some(key) = file_name.to_str().and_then(decode) {
+                if key.starts_with(prefix) {
+                    keys.push(key);
+                }
+            }
+        }
+        keys.sort();
+
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn data_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("iris-store-test-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn store(name: &str) -> PluginStore {
+        PluginStore::for_plugin(&data_dir(name), &PluginName("/test".to_string()))
+    }
+
+    #[test]
+    fn test_set_get_and_remove() {
+        let store = store("basic");
+        assert_eq!(store.get("karma:tom").unwrap(), None);
+        assert_eq!(store.keys("").unwrap(), Vec::<String>::new());
+
+        store.set("karma:tom", "1").unwrap();
+        store.set("karma:tom", "2").unwrap();
+        store.set("karma:alice", "5").unwrap();
+        store.set("quote:1", "Hello").unwrap();
+        assert_eq!(store.get("karma:tom").unwrap(), Some("2".to_string()));
+        assert_eq!(
+            store.keys("karma:").unwrap(),
+            vec!["karma:alice".to_string(), "karma:tom".to_string()]
+        );
+
+        assert!(store.remove("karma:tom").unwrap());
+        assert!(!store.remove("karma:tom").unwrap());
+        assert_eq!(store.get("karma:tom").unwrap(), None);
+        let _ = fs::remove_dir_all(store.dir.parent().unwrap());
+    }
+
+    #[test]
+    fn test_keys_cannot_escape_the_store() {
+        let store = store("escape");
+        store.set("../../etc/passwd", "nope").unwrap();
+
+        assert_eq!(fs::read_dir(&store.dir).unwrap().count(), 1);
+        assert_eq!(
+            store.keys("..").unwrap(),
+            vec!["../../etc/passwd".to_string()]
+        );
+        assert!(store.set("", "empty").is_err());
+        assert!(store.set(&"k".repeat(MAX_KEY_LENGTH + 1), "long").is_err());
+        let _ = fs::remove_dir_all(store.dir.parent().unwrap());
+    }
+
+    #[test]
+    fn test_plugins_cannot_see_each_others_keys() {
+        let data_dir = data_dir("isolation");
+        let karma = PluginStore::for_plugin(&data_dir, &PluginName("/karma".to_string()));
+        let quotes = PluginStore::for_plugin(&data_dir, &PluginName("/quotes".to_string()));
+
+        karma.set("tom", "1").unwrap();
+        assert_eq!(quotes.get("tom").unwrap(), None);
+        assert!(quotes.keys("").unwrap().is_empty());
+        assert_eq!(karma.dir, data_dir.join("karma"));
+        let _ = fs::remove_dir_all(&data_dir);
+    }
+
+    #[test]
+    fn test_values_and_keys_are_limited() {
+        let mut store = store("limits");
+        store.max_keys = 3;
+        assert!(store.set("big", &"x".repeat(MAX_VALUE_LENGTH + 1)).is_err());
+        store.set("big", &"x".repeat(MAX_VALUE_LENGTH)).unwrap();
+
+        store.set("1", "").unwrap();
+        store.set("2", "").unwrap();
+        assert!(store.set("one too many", "").is_err());
+        // Keys already kept can still be changed, and removing one frees a place
+        store.set("1", "changed").unwrap();
+        assert!(store.remove("1").unwrap());
+        store.set("one too many", "").unwrap();
+        let _ = fs::remove_dir_all(store.dir.parent().unwrap());
+    }
+}
@@ -0,0 +1,396 @@
+//! # Plugin supervisor
+//! Guards every call into a plugin: a call which panics or runs past its time limit counts as a failure,
+//! and a plugin which keeps failing is disabled, with operators told each time.
+//!
+//! A call which overruns cannot be stopped, as it is native code running on one of our threads,
+//! so it is left to finish on its own and whatever it returns is thrown away.
+//! Failures are dealt with on a thread of their own, so telling operators never holds up the timers.
+//!
+//! Panics are caught on our side of the boundary, which covers script, process and WASM plugins.
+//! A panic inside a native plugin aborts the process before it can reach us, as every function it exports
+//! is a `sabi_extern_fn`, unless the plugin catches it itself with `common::plugin::catch_panics`.
+
+use crate::timer_wheel::TimerWheel;
+use crate::user_connections::UserConnections;
+use crate::worker_pool::{StandIn, WorkerPool};
+use common::types::{ErrorType, NumericReply, PluginName, Reply};
+use log::error;
+use std::any::Any;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+type FailureJob = Box<dyn FnOnce() + Send + 'static>;
+
+/// How a single plugin has behaved so far.
+#[derive(Default)]
+pub struct PluginHealth {
+    failures: AtomicUsize,
+    disabled: AtomicBool,
+}
+
+impl PluginHealth {
+    pub fn is_disabled(&self) -> bool {
+        self.disabled.load(Ordering::Acquire)
+    }
+}
+
+/// Why a call into a plugin failed.
+pub enum Failure {
+    Panicked(String),
+    TimedOut(Duration),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Panicked(message) => write!(fmt, "panicked: {message}"),
+            Failure::TimedOut(timeout) => write!(fmt, "timed out after {}ms", timeout.as_millis()),
+        }
+    }
+}
+
+pub struct Supervisor {
+    timers: Arc<TimerWheel>,
+    timeout: Duration,
+    max_failures: usize,
+    user_connections: Arc<UserConnections>,
+    /// Hands timeouts over from the timer thread to the thread which deals with them
+    failures: Sender<FailureJob>,
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown cause")
+        .to_string()
+}
+
+impl Supervisor {
+    pub fn new(
+        timers: Arc<TimerWheel>,
+        timeout: Duration,
+        max_failures: usize,
+        user_connections: Arc<UserConnections>,
+    ) -> Arc<Supervisor> {
+        let (failures, receiver) = mpsc::channel::<FailureJob>();
+        thread::Builder::new()
+            .name("plugin-supervisor".to_string())
+            .spawn(move || {
+                for job in receiver {
+                    job();
+                }
+            })
+            .expect("failed to spawn supervisor thread");
+
+        Arc::new(Supervisor {
+            timers,
+            timeout,
+            max_failures,
+            user_connections,
+            failures,
+        })
+    }
+
+    /// Runs `call` on this thread, returning what it returned if it neither panicked nor timed out.
+    /// `on_failure` is told why the call failed; for a timeout, it runs from the supervisor's own thread
+    /// as soon as the call is found to have overrun, while the call may still be running.
+    /// `on_failure` is dropped as soon as the call is settled, so anything it holds is not kept for the time limit.
+    pub fn call<T>(
+        self: &Arc<Self>,
+        pl_name: &PluginName,
+        health: &Arc<PluginHealth>,
+        on_failure: impl Fn(&Failure) + Send + Sync + 'static,
+        call: impl FnOnce() -> T,
+    ) -> Option<T> {
+        // A cancelled timer keeps its job until it would have fired, so the job only borrows `on_failure`
+        let on_failure = Arc::new(Mutex::new(Some(on_failure)));
+        // Whichever of the call and the timer gets here first decides how the call went
+        let settled = Arc::new(AtomicBool::new(false));
+        let timer = {
+            let supervisor = self.clone();
+            let pl_name = pl_name.clone();
+            let health = health.clone();
+            let settled = settled.clone();
+            let on_failure = on_failure.clone();
+            let timeout = self.timeout;
+
+            self.timers.schedule(timeout, None, move |_| {
+                if !settled.swap(true, Ordering::AcqRel) {
+                    let Some(on_failure) = on_failure
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .take()
+                    else {
+                        return;
+                    };
+                    // The timer thread drives every timer, so it only passes the failure on
+                    let failures = supervisor.failures.clone();
+                    let (supervisor, pl_name, health) =
+                        (supervisor.clone(), pl_name.clone(), health.clone());
+                    let _ = failures.send(Box::new(move || {
+                        let failure = Failure::TimedOut(timeout);
+                        on_failure(&failure);
+                        supervisor.fail(&pl_name, &health, &failure);
+                    }));
+                }
+            })
+        };
+
+        let result = panic::catch_unwind(AssertUnwindSafe(call));
+        self.timers.cancel(timer);
+        let timed_out = settled.swap(true, Ordering::AcqRel);
+        let on_failure = on_failure
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+
+        match (result, on_failure) {
+            (Ok(_), _) if timed_out => None,
+            (Ok(value), _) => Some(value),
+            (Err(_), None) => None,
+            (Err(panic), Some(on_failure)) => {
+                let failure = Failure::Panicked(panic_message(panic.as_ref()));
+                on_failure(&failure);
+                self.fail(pl_name, health, &failure);
+                None
+            }
+        }
+    }
+
+    /// Runs `call` on `workers`, handing what it returned to `done`, or `None` if it panicked or did not
+    /// return within `deadline`. Nothing waits on the call: `done` runs on the worker once the call returns,
+    /// or on the supervisor's own thread once it has overrun. A call which overruns is left to finish on its own,
+    /// with another worker standing in for the one it holds, and is not made at all if it has not yet started.
+    pub fn call_async<T: Send + 'static>(
+        self: &Arc<Self>,
+        workers: &Arc<WorkerPool>,
+        pl_name: &PluginName,
+        health: &Arc<PluginHealth>,
+        deadline: Duration,
+        call: impl FnOnce() -> T + Send + 'static,
+        done: impl FnOnce(Option<T>) + Send + 'static,
+    ) {
+        // As in `call`, the timer only borrows `done`, and whichever of the two gets here first decides
+        let done = Arc::new(Mutex::new(Some(done)));
+        let settled = Arc::new(AtomicBool::new(false));
+        // Only a call which has started holds a worker, which needs standing in for
+        let started = Arc::new(AtomicBool::new(false));
+        let stand_in = StandIn::default();
+        let timer = {
+            let supervisor = self.clone();
+            let workers = workers.clone();
+            let pl_name = pl_name.clone();
+            let health = health.clone();
+            let settled = settled.clone();
+            let started = started.clone();
+            let stand_in = stand_in.clone();
+            let done = done.clone();
+
+            self.timers.schedule(deadline, None, move |_| {
+                if settled.swap(true, Ordering::AcqRel) {
+                    return;
+                }
+                let Some(done) = done.lock().unwrap_or_else(PoisonError::into_inner).take() else {
+                    return;
+                };
+                if started.load(Ordering::Acquire) {
+                    stand_in.take_over(&workers);
+                }
+
+                let (supervisor, pl_name, health) =
+                    (supervisor.clone(), pl_name.clone(), health.clone());
+                let _ = supervisor.failures.clone().send(Box::new(move || {
+                    supervisor.fail(&pl_name, &health, &Failure::TimedOut(deadline));
+                    done(None);
+                }));
+            })
+        };
+
+        let supervisor = self.clone();
+        let pl_name = pl_name.clone();
+        let health = health.clone();
+        workers.execute(move || {
+            // A call which overran before it started is never made
+            started.store(true, Ordering::Release);
+            let result = (!settled.load(Ordering::Acquire))
+                .then(|| panic::catch_unwind(AssertUnwindSafe(call)));
+            stand_in.finish();
+            supervisor.timers.cancel(timer);
+
+            let Some(result) = result else { return };
+            if settled.swap(true, Ordering::AcqRel) {
+                return;
+            }
+            let Some(done) = done.lock().unwrap_or_else(PoisonError::into_inner).take() else {
+                return;
+            };
+
+            match result {
+                Ok(value) => done(Some(value)),
+                Err(panic) => {
+                    let failure = Failure::Panicked(panic_message(panic.as_ref()));
+                    supervisor.fail(&pl_name, &health, &failure);
+                    done(None);
+                }
+            }
+        });
+    }
+
+    /// Records a failure, disabling the plugin once it has failed too often.
+    fn fail(&self, pl_name: &PluginName, health: &PluginHealth, failure: &Failure) {
+        let failures = health.failures.fetch_add(1, Ordering::AcqRel) + 1;
+        self.report(&format!("Plugin {pl_name} {failure}"));
+
+        if failures >= self.max_failures && !health.disabled.swap(true, Ordering::AcqRel) {
+            self.report(&format!(
+                "Plugin {pl_name} has been disabled after {failures} failures"
+            ));
+        }
+    }
+
+    /// Logs a problem, and lets every operator know about it.
+    pub fn report(&self, message: &str) {
+        error!("{message}");
+
+        for operator in self.user_connections.operators() {
+            let reply = Reply::Numeric(NumericReply {
+                target_nick: operator.clone(),
+                numeric: ErrorType::PluginException as u16,
+                message: message.to_string(),
+            });
+            let _ = self
+                .user_connections
+                .write_to_user(&operator, &reply.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn supervisor(timeout: Duration) -> Arc<Supervisor> {
+        Supervisor::new(
+            TimerWheel::start(),
+            timeout,
+            2,
+            Arc::new(UserConnections::new()),
+        )
+    }
+
+    #[test]
+    fn test_repeated_panics_disable_the_plugin() {
+        let supervisor = supervisor(Duration::from_secs(10));
+        let pl_name = PluginName("/broken".to_string());
+        let health = Arc::new(PluginHealth::default());
+
+        assert_eq!(supervisor.call(&pl_name, &health, |_| {}, || 1), Some(1));
+        let panicked = supervisor.call(&pl_name, &health, |_| {}, || -> i32 { panic!("oops") });
+        assert_eq!(panicked, None);
+        assert!(!health.is_disabled());
+
+        supervisor.call(&pl_name, &health, |_| {}, || -> i32 { panic!("oops") });
+        assert!(health.is_disabled());
+    }
+
+    #[test]
+    fn test_slow_calls_time_out() {
+        let supervisor = supervisor(Duration::from_millis(100));
+        let pl_name = PluginName("/slow".to_string());
+        let health = Arc::new(PluginHealth::default());
+        let told = Arc::new(AtomicBool::new(false));
+
+        let on_failure = {
+            let told = told.clone();
+            move |failure: &Failure| {
+                told.store(matches!(failure, Failure::TimedOut(_)), Ordering::SeqCst)
+            }
+        };
+        let result = supervisor.call(&pl_name, &health, on_failure, || {
+            thread::sleep(Duration::from_millis(500));
+            1
+        });
+
+        assert_eq!(result, None);
+        assert!(told.load(Ordering::SeqCst));
+        assert_eq!(health.failures.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_settled_calls_let_go_of_their_failure_handler() {
+        let supervisor = supervisor(Duration::from_secs(10));
+        let pl_name = PluginName("/quick".to_string());
+        let health = Arc::new(PluginHealth::default());
+        let held = Arc::new(());
+
+        let on_failure = {
+            let held = held.clone();
+            move |_: &Failure| {
+                let _ = &held;
+            }
+        };
+        assert_eq!(
+            supervisor.call(&pl_name, &health, on_failure, || 1),
+            Some(1)
+        );
+
+        // The timer has not yet come round, but what the handler held is already given back
+        assert_eq!(Arc::strong_count(&held), 1);
+    }
+
+    #[test]
+    fn test_async_calls_are_never_waited_on_past_their_deadline() {
+        let supervisor = supervisor(Duration::from_secs(10));
+        let workers = Arc::new(WorkerPool::new("test", 1));
+        let pl_name = PluginName("/slow".to_string());
+        let health = Arc::new(PluginHealth::default());
+        let deadline = Duration::from_millis(200);
+        let (sender, results) = mpsc::channel();
+        let call = |value: Option<u64>, made: Arc<AtomicBool>| {
+            let sender = sender.clone();
+            supervisor.call_async(
+                &workers,
+                &pl_name,
+                &health,
+                deadline,
+                move || {
+                    made.store(true, Ordering::SeqCst);
+                    value.unwrap_or_else(|| {
+                        thread::sleep(Duration::from_millis(1000));
+                        0
+                    })
+                },
+                move |result| sender.send(result).unwrap(),
+            );
+        };
+
+        call(Some(1), Arc::default());
+        assert_eq!(results.recv().unwrap(), Some(1));
+
+        // A stuck call gives up its caller on time, and has its worker stood in for
+        let started = std::time::Instant::now();
+        call(None, Arc::default());
+        assert_eq!(results.recv().unwrap(), None);
+        assert!(started.elapsed() < Duration::from_millis(800));
+        assert_eq!(health.failures.load(Ordering::SeqCst), 1);
+        call(Some(2), Arc::default());
+        assert_eq!(results.recv().unwrap(), Some(2));
+        assert!(started.elapsed() < Duration::from_millis(800));
+
+        // A call still queued behind a busy pool when it overruns is never made
+        let made = Arc::new(AtomicBool::new(false));
+        workers.execute(|| thread::sleep(Duration::from_millis(1000)));
+        call(Some(3), made.clone());
+        assert_eq!(results.recv().unwrap(), None);
+        thread::sleep(Duration::from_millis(1200));
+        assert!(!made.load(Ordering::SeqCst));
+    }
+}
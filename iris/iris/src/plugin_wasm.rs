@@ -0,0 +1,446 @@
+//! # WebAssembly plugins
+//! A plugin compiled to a `.wasm` module, run in a sandbox: each call may only run for so many
+//! instructions, the module's memory is capped, and it can reach nothing outside itself but the
+//! host methods it has been granted. There is no WASI, so it has no files, clock or network.
+//!
+//! The module exports its `memory`, `iris_alloc(len: i32) -> i32`, which hands back `len` bytes
+//! of that memory, and `iris_call(ptr: i32, len: i32) -> i64`, which is given the JSON
+//! `{"method": ..., "params": ...}` of a `plugin_protocol` call and answers with `{"result": ...}`
+//! or `{"error": {...}}`, packed as `ptr << 32 | len`. An answer of 0 is a `null` result.
+//! Hooked messages are calls too, whose answers are ignored.
+//!
+//! It may import `iris.host_call(ptr: i32, len: i32) -> i64`, which takes and answers a call of
+//! the host in the same way, writing the answer to memory from `iris_alloc`,
+//! and `iris.log(ptr: i32, len: i32)`, which writes text to the server's log.
+//! Memory handed to the module is never freed by the server.
+
+use crate::config::{Capability, ServerConfig};
+use crate::plugin_protocol::{self, CallError, Description, HOST_ERROR, INVALID_PARAMS};
+use crate::plugin_runtime::{PluginMetadata, PluginRuntime};
+use anyhow::anyhow;
+use common::plugin::{PluginHostRef, RVerdict};
+use common::types::{Invocation, Message, Nick, PluginMsg, PluginName, PluginReply};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+use wasmi::{
+    Caller, Config, Engine, Extern, Instance, Linker, Memory, Module, Store, StoreLimits,
+    StoreLimitsBuilder, TrapCode, TypedFunc,
+};
+
+/// The capability a host method needs, if it is one.
+fn capability_of(method: &str) -> Option<Capability> {
+    match method {
+        "send" | "join" | "part" | "schedule" | "cancel" => Some(Capability::Messages),
+        "channels" | "channel" | "members" | "user" => Some(Capability::Queries),
+        "store_get" | "store_set" | "store_remove" | "store_keys" => Some(Capability::Store),
+        _ => None,
+    }
+}
+
+/// What host functions see of the plugin.
+struct Sandbox {
+    pl_name: String,
+    /// The host of the call being made, which there is none of while the module describes itself
+    host: Option<PluginHostRef>,
+    grants: Vec<Capability>,
+    limits: StoreLimits,
+}
+
+struct Exports {
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    call: TypedFunc<(i32, i32), i64>,
+}
+
+struct Guest {
+    store: Store<Sandbox>,
+    exports: Exports,
+}
+
+/// A plugin run in a WebAssembly sandbox. Calls are made one at a time.
+pub struct WasmPlugin {
+    description: Description,
+    fuel: u64,
+    /// Handlers, hooks and interceptors all take turns on the one instance, so a call waits for
+    /// whichever is running, which its fuel keeps short. An interceptor which waits past the time limit
+    /// is given up on, letting the message through as it is.
+    guest: Mutex<Guest>,
+}
+
+/// Copies `bytes` into memory handed out by the module, returning where they went.
+fn write_guest(
+    mut store: impl wasmi::AsContextMut<Data = Sandbox>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    bytes: &[u8],
+) -> Result<i32, String> {
+    let len = i32::try_from(bytes.len()).map_err(|_| "The call is too large".to_string())?;
+    let ptr = alloc
+        .call(&mut store, len)
+        .map_err(|err| describe_error(&err))?;
+    memory
+        .write(&mut store, ptr as u32 as usize, bytes)
+        .map_err(|_| "iris_alloc handed back memory which is out of bounds".to_string())?;
+
+    Ok(ptr)
+}
+
+/// Reads the bytes at `ptr << 32 | len` from the module's memory.
+fn read_guest(
+    store: impl wasmi::AsContext,
+    memory: Memory,
+    packed: i64,
+) -> Result<Vec<u8>, String> {
+    let (ptr, len) = ((packed as u64 >> 32) as usize, packed as u32 as usize);
+    let mut bytes = vec![0; len];
+    memory
+        .read(store, ptr, &mut bytes)
+        .map_err(|_| "The module answered with memory which is out of bounds".to_string())?;
+
+    Ok(bytes)
+}
+
+fn describe_error(err: &wasmi::Error) -> String {
+    match err.as_trap_code() {
+        Some(TrapCode::OutOfFuel) => "The plugin ran out of fuel".to_string(),
+        Some(trap) => format!("The plugin trapped: {trap}"),
+        None => err.to_string(),
+    }
+}
+
+/// Answers the module's `iris.host_call`.
+fn host_call(mut caller: Caller<'_, Sandbox>, ptr: i32, len: i32) -> Result<i64, wasmi::Error> {
+    let Some(Extern::Memory(memory)) = caller.get_export("memory") else {
+        return Err(wasmi::Error::new("The module exports no memory"));
+    };
+    let Some(alloc) = caller
+        .get_export("iris_alloc")
+        .and_then(Extern::into_func)
+        .and_then(|alloc| alloc.typed::<i32, i32>(&caller).ok())
+    else {
+        return Err(wasmi::Error::new("The module exports no iris_alloc"));
+    };
+
+    let packed = (i64::from(ptr as u32) << 32) | i64::from(len as u32);
+    let answer = read_guest(&caller, memory, packed)
+        .map_err(|err| (INVALID_PARAMS, err))
+        .and_then(|call| {
+            serde_json::from_slice::<Value>(&call).map_err(|err| (INVALID_PARAMS, err.to_string()))
+        })
+        .and_then(|call| {
+            let method = call.get("method").and_then(Value::as_str).unwrap_or("");
+            serve(caller.data(), method, &call["params"])
+        });
+
+    let answer = plugin_protocol::answer_json(answer).to_string();
+    let ptr =
+        write_guest(&mut caller, memory, alloc, answer.as_bytes()).map_err(wasmi::Error::new)?;
+    Ok((i64::from(ptr as u32) << 32) | answer.len() as i64)
+}
+
+/// Carries out a call the module made of the host, if it has been granted what the call needs.
+fn serve(sandbox: &Sandbox, method: &str, params: &Value) -> Result<Value, CallError> {
+    let host = sandbox.host.as_ref().ok_or_else(|| {
+        (
+            HOST_ERROR,
+            "The plugin has not been initialised".to_string(),
+        )
+    })?;
+
+    if let Some(capability) = capability_of(method) {
+        if !sandbox.grants.contains(&capability) {
+            return Err((
+                HOST_ERROR,
+                format!("{method} needs the `{capability}` capability, which has not been granted"),
+            ));
+        }
+    }
+
+    plugin_protocol::serve(host, method, params)
+}
+
+fn log(caller: Caller<'_, Sandbox>, ptr: i32, len: i32) {
+    let Some(Extern::Memory(memory)) = caller.get_export("memory") else {
+        return;
+    };
+
+    let packed = (i64::from(ptr as u32) << 32) | i64::from(len as u32);
+    if let Ok(text) = read_guest(&caller, memory, packed) {
+        info!(
+            "{}: {}",
+            caller.data().pl_name,
+            String::from_utf8_lossy(&text)
+        );
+    }
+}
+
+impl Guest {
+    fn instantiate(wasm: &[u8], config: &ServerConfig) -> anyhow::Result<Guest> {
+        let mut engine_config = Config::default();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config);
+        let module = Module::new(&engine, wasm)?;
+
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(config.wasm_memory_limit)
+            .instances(1)
+            .memories(1)
+            .tables(1)
+            .build();
+        let sandbox = Sandbox {
+            pl_name: String::new(),
+            host: None,
+            grants: vec![],
+            limits,
+        };
+        let mut store = Store::new(&engine, sandbox);
+        store.limiter(|sandbox| &mut sandbox.limits);
+        // Starting the module is limited like any other call
+        store.set_fuel(config.wasm_fuel)?;
+
+        let mut linker = Linker::new(&engine);
+        linker.func_wrap("iris", "host_call", host_call)?;
+        linker.func_wrap("iris", "log", log)?;
+        let instance: Instance = linker
+            .instantiate_and_start(&mut store, &module)
+            .map_err(|err| anyhow!(describe_error(&err)))?;
+
+        let exports = Exports {
+            memory: instance
+                .get_memory(&store, "memory")
+                .ok_or_else(|| anyhow!("The module exports no memory"))?,
+            alloc: instance.get_typed_func(&store, "iris_alloc")?,
+            call: instance.get_typed_func(&store, "iris_call")?,
+        };
+
+        Ok(Guest { store, exports })
+    }
+
+    fn call(
+        &mut self,
+        host: Option<PluginHostRef>,
+        fuel: u64,
+        method: &str,
+        params: Value,
+    ) -> Result<Value, String> {
+        let Exports {
+            memory,
+            alloc,
+            call,
+        } = self.exports;
+        self.store.data_mut().host = host;
+        self.store.set_fuel(fuel).map_err(|err| err.to_string())?;
+
+        let request = json!({"method": method, "params": params}).to_string();
+        let ptr = write_guest(&mut self.store, memory, alloc, request.as_bytes())?;
+        let packed = call
+            .call(&mut self.store, (ptr, request.len() as i32))
+            .map_err(|err| describe_error(&err))?;
+        if packed == 0 {
+            return Ok(Value::Null);
+        }
+
+        let answer = read_guest(&self.store, memory, packed)?;
+        let answer = serde_json::from_slice::<Value>(&answer)
+            .map_err(|err| format!("The plugin's answer is not JSON: {err}"))?;
+        plugin_protocol::parse_answer(&answer)
+    }
+}
+
+impl WasmPlugin {
+    /// Instantiates the module at `path`, and asks it to describe itself.
+    pub fn load(path: &Path, config: &ServerConfig) -> anyhow::Result<WasmPlugin> {
+        let wasm = fs::read(path).map_err(|err| anyhow!("{}: {err}", path.display()))?;
+        let mut guest = Guest::instantiate(&wasm, config)
+            .map_err(|err| anyhow!("{}: {err}", path.display()))?;
+
+        let description = guest
+            .call(None, config.wasm_fuel, "describe", Value::Null)
+            .and_then(Description::parse)
+            .map_err(|err| anyhow!("{}: describe failed: {err}", path.display()))?;
+
+        let sandbox = guest.store.data_mut();
+        sandbox.pl_name = description.name.clone();
+        sandbox.grants = config.wasm_grants(&PluginName(description.name.clone()));
+
+        Ok(WasmPlugin {
+            description,
+            fuel: config.wasm_fuel,
+            guest: Mutex::new(guest),
+        })
+    }
+
+    fn call(&self, host: PluginHostRef, method: &str, params: Value) -> Result<Value, String> {
+        let mut guest = self.guest.lock().unwrap_or_else(PoisonError::into_inner);
+        guest.call(Some(host), self.fuel, method, params)
+    }
+}
+
+impl PluginRuntime for WasmPlugin {
+    fn name(&self) -> PluginName {
+        PluginName(self.description.name.clone())
+    }
+
+    fn metadata(&self) -> PluginMetadata {
+        self.description.metadata()
+    }
+
+    fn verbs(&self) -> Vec<String> {
+        self.description.verbs.clone()
+    }
+
+    fn triggers(&self) -> Vec<String> {
+        self.description.triggers.clone()
+    }
+
+    fn takes_settings(&self) -> bool {
+        true
+    }
+
+    fn init(&self, host: PluginHostRef, settings: &BTreeMap<String, String>) -> Result<(), String> {
+        self.call(host, "init", plugin_protocol::init_params(settings))
+            .map(|_| ())
+    }
+
+    fn handle(
+        &self,
+        host: PluginHostRef,
+        invocation: Invocation,
+        plugin_msg: PluginMsg,
+    ) -> Result<Option<PluginReply>, String> {
+        let reply = self.call(
+            host,
+            "handle",
+            plugin_protocol::handle_params(invocation, plugin_msg),
+        )?;
+        plugin_protocol::parse_reply(reply)
+    }
+
+    fn hooks(&self, message: &Message) -> bool {
+        self.description.hooks(message)
+    }
+
+    fn notify(&self, host: PluginHostRef, sender: Nick, message: Message) {
+        let Some((hook, params)) = plugin_protocol::notification(sender, message) else {
+            return;
+        };
+
+        if let Err(err) = self.call(host, hook, params) {
+            warn!("{} failed handling {hook}: {err}", self.description.name);
+        }
+    }
+
+    fn intercepts(&self) -> bool {
+        self.description.intercepts
+    }
+
+    fn intercept(&self, host: PluginHostRef, sender: Nick, message: Message) -> RVerdict {
+        let Some(params) = plugin_protocol::intercept_params(sender, message) else {
+            return RVerdict::Allow;
+        };
+
+        // An interceptor which cannot answer lets the message through as it is
+        match self.call(host, "intercept", params) {
+            Ok(verdict) => plugin_protocol::parse_verdict(&verdict),
+            Err(err) => {
+                warn!(
+                    "{} could not intercept a message: {err}",
+                    self.description.name
+                );
+                RVerdict::Allow
+            }
+        }
+    }
+
+    fn deinit(&self, host: PluginHostRef) {
+        if let Err(err) = self.call(host, "deinit", Value::Null) {
+            warn!(
+                "{} could not be deinitialised: {err}",
+                self.description.name
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin_host::Host;
+    use crate::timer_wheel::TimerWheel;
+    use crate::user_connections::UserConnections;
+    use std::sync::Arc;
+
+    /// A module with a page of memory, a bump allocator and `body` as its `iris_call`.
+    fn guest(memory_pages: u32, data: &str, body: &str) -> String {
+        let data = data.replace('"', "\\\"");
+        format!(
+            r#"(module
+                (import "iris" "host_call" (func $host_call (param i32 i32) (result i64)))
+                (memory (export "memory") {memory_pages})
+                (global $next (mut i32) (i32.const 1024))
+                (data (i32.const 0) "{data}")
+                (func (export "iris_alloc") (param $len i32) (result i32)
+                    (global.get $next)
+                    (global.set $next (i32.add (global.get $next) (local.get $len))))
+                (func (export "iris_call") (param i32 i32) (result i64) {body}))"#
+        )
+    }
+
+    fn host() -> PluginHostRef {
+        let pl_name = PluginName::try_from("/wat".to_string()).unwrap();
+        Host::for_plugin(
+            &pl_name,
+            TimerWheel::start(),
+            Arc::new(UserConnections::new()),
+            &std::env::temp_dir(),
+        )
+        .to_ref()
+    }
+
+    #[test]
+    fn test_calls_run_out_of_fuel() {
+        let wasm = guest(1, "", "(loop $forever (br $forever)) (i64.const 0)");
+        let mut guest = Guest::instantiate(wasm.as_bytes(), &ServerConfig::default()).unwrap();
+
+        let err = guest.call(None, 1000, "describe", Value::Null).unwrap_err();
+        assert_eq!(err, "The plugin ran out of fuel");
+        // Each call is given its fuel afresh, so this one runs out too, rather than finding none left
+        let err = guest.call(None, 1000, "describe", Value::Null).unwrap_err();
+        assert_eq!(err, "The plugin ran out of fuel");
+    }
+
+    #[test]
+    fn test_memory_is_capped() {
+        let answer = r#"{"result": {"name": "/wat"}}"#;
+        let body = format!("(i64.const {})", answer.len());
+        let config = ServerConfig {
+            wasm_memory_limit: 4 * 65536,
+            ..ServerConfig::default()
+        };
+
+        let mut small = Guest::instantiate(guest(4, answer, &body).as_bytes(), &config).unwrap();
+        let description = small.call(None, 1000, "describe", Value::Null).unwrap();
+        assert_eq!(description, json!({"name": "/wat"}));
+        assert!(Guest::instantiate(guest(5, answer, &body).as_bytes(), &config).is_err());
+    }
+
+    #[test]
+    fn test_host_calls_need_a_capability() {
+        let call = r##"{"method": "join", "params": {"channel": "#wasm"}}"##;
+        let body = format!("(call $host_call (i32.const 0) (i32.const {}))", call.len());
+        let wasm = guest(1, call, &body);
+        let mut guest = Guest::instantiate(wasm.as_bytes(), &ServerConfig::default()).unwrap();
+
+        let err = guest
+            .call(Some(host()), 100_000, "handle", Value::Null)
+            .unwrap_err();
+        assert!(err.contains("`messages` capability"), "{err}");
+
+        guest.store.data_mut().grants = vec![Capability::Messages];
+        let joined = guest.call(Some(host()), 100_000, "handle", Value::Null);
+        assert_eq!(joined, Ok(Value::Null));
+    }
+}
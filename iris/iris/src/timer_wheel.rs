@@ -0,0 +1,225 @@
+//! # Timer wheel
+//! One thread, owned by the server, which runs every delayed and recurring job.
+//! Timers are hashed into a ring of slots by the tick on which they fall due,
+//! so scheduling, cancelling and each tick only touch the timers which are due.
+//! Timers are only as precise as the tick, and may fire up to one tick early or late.
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TICK: Duration = Duration::from_millis(100);
+const SLOTS: usize = 256;
+
+pub type TimerId = u64;
+type Job = Arc<dyn Fn(TimerId) + Send + Sync>;
+
+pub struct TimerWheel {
+    state: Mutex<WheelState>,
+    next_id: AtomicU64,
+}
+
+struct WheelState {
+    slots: Vec<Vec<Timer>>,
+    cursor: usize,
+    pending: HashSet<TimerId>,
+}
+
+struct Timer {
+    id: TimerId,
+    /// The number of full turns of the wheel left before the timer is due
+    rounds: usize,
+    interval: Option<usize>,
+    job: Job,
+}
+
+/// The number of ticks in `duration`, rounded up.
+fn ticks(duration: Duration) -> usize {
+    let ticks = duration.as_millis().div_ceil(TICK.as_millis());
+    (ticks as usize).max(1)
+}
+
+impl WheelState {
+    fn insert(&mut self, ticks: usize, timer: Timer) {
+        let slot = (self.cursor + ticks) % SLOTS;
+        self.slots[slot].push(Timer {
+            rounds: (ticks - 1) / SLOTS,
+            ..timer
+        });
+    }
+}
+
+impl TimerWheel {
+    /// Creates the wheel, and the thread which turns it.
+    /// The thread stops once the wheel is dropped.
+    pub fn start() -> Arc<TimerWheel> {
+        let wheel = Arc::new(Self::new());
+        let weak = Arc::downgrade(&wheel);
+
+        thread::Builder::new()
+            .name("timer-wheel".to_string())
+            .spawn(move || Self::turn(weak))
+            .expect("failed to spawn timer thread");
+
+        wheel
+    }
+
+    fn new() -> TimerWheel {
+        TimerWheel {
+            state: Mutex::new(WheelState {
+                slots: (0..SLOTS).map(|_| vec![]).collect(),
+                cursor: 0,
+                pending: HashSet::new(),
+            }),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    fn turn(wheel: Weak<TimerWheel>) {
+        let mut next_tick = Instant::now() + TICK;
+        loop {
+            thread::sleep(next_tick.saturating_duration_since(Instant::now()));
+            next_tick += TICK;
+
+            match wheel.upgrade() {
+                Some(wheel) => wheel.tick(),
+                None => return,
+            }
+        }
+    }
+
+    /// Runs `job` once `delay` has passed, then every `interval` if one is given, until cancelled.
+    /// The job is handed the id of its timer.
+    pub fn schedule(
+        &self,
+        delay: Duration,
+        interval: Option<Duration>,
+        job: impl Fn(TimerId) + Send + Sync + 'static,
+    ) -> TimerId {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let timer = Timer {
+            id,
+            rounds: 0,
+            interval: interval.map(ticks),
+            job: Arc::new(job),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.pending.insert(id);
+        state.insert(ticks(delay), timer);
+
+        id
+    }
+
+    /// Stops a timer, returning whether it was still pending.
+    pub fn cancel(&self, id: TimerId) -> bool {
+        // The timer itself is dropped from its slot the next time that slot comes round
+        self.state.lock().unwrap().pending.remove(&id)
+    }
+
+    /// Whether a timer is still to fire, which a recurring timer is until it is cancelled.
+    pub fn is_pending(&self, id: TimerId) -> bool {
+        self.state.lock().unwrap().pending.contains(&id)
+    }
+
+    /// Advances the wheel by one slot, running every job which has fallen due.
+    fn tick(&self) {
+        let due = {
+            let mut state = self.state.lock().unwrap();
+            state.cursor = (state.cursor + 1) % SLOTS;
+            let cursor = state.cursor;
+
+            let mut due = vec![];
+            for mut timer in std::mem::take(&mut state.slots[cursor]) {
+                if !state.pending.contains(&timer.id) {
+                    continue;
+                }
+
+                if timer.rounds > 0 {
+                    timer.rounds -= 1;
+                    state.slots[cursor].push(timer);
+                    continue;
+                }
+
+                due.push((timer.id, timer.job.clone()));
+                match timer.interval {
+                    Some(interval) => state.insert(interval, timer),
+                    None => {
+                        state.pending.remove(&timer.id);
+                    }
+                }
+            }
+
+            due
+        };
+
+        // Jobs run without the lock held, so they are free to schedule or cancel timers
+        for (id, job) in due {
+            job(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    fn counter(
+        wheel: &TimerWheel,
+        delay: Duration,
+        interval: Option<Duration>,
+    ) -> (TimerId, Arc<AtomicUsize>) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let job_count = count.clone();
+        let id = wheel.schedule(delay, interval, move |_| {
+            job_count.fetch_add(1, Ordering::SeqCst);
+        });
+
+        (id, count)
+    }
+
+    fn tick_n(wheel: &TimerWheel, n: usize) {
+        for _ in 0..n {
+            wheel.tick();
+        }
+    }
+
+    #[test]
+    fn test_timers_fire_once_due() {
+        let wheel = TimerWheel::new();
+        let (_, soon) = counter(&wheel, TICK * 3, None);
+        // Longer than a full turn of the wheel
+        let (_, later) = counter(&wheel, TICK * (SLOTS as u32 + 5), None);
+
+        tick_n(&wheel, 2);
+        assert_eq!(soon.load(Ordering::SeqCst), 0);
+        tick_n(&wheel, 1);
+        assert_eq!(soon.load(Ordering::SeqCst), 1);
+
+        tick_n(&wheel, SLOTS + 1);
+        assert_eq!(later.load(Ordering::SeqCst), 0);
+        tick_n(&wheel, 1);
+        assert_eq!(later.load(Ordering::SeqCst), 1);
+
+        tick_n(&wheel, SLOTS * 2);
+        assert_eq!(soon.load(Ordering::SeqCst), 1);
+        assert_eq!(later.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_recurring_timers_repeat_until_cancelled() {
+        let wheel = TimerWheel::new();
+        let (id, count) = counter(&wheel, TICK, Some(TICK * 2));
+
+        tick_n(&wheel, 5);
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        assert!(wheel.cancel(id));
+        tick_n(&wheel, 10);
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        assert!(!wheel.cancel(id));
+    }
+}
@@ -1,132 +1,529 @@
+//! # User connections
+//! Keeps track of which users are connected, and which channels they belong to.
+//! The maps are sharded behind reader/writer locks, and every user and channel carries its own lock,
+//! so sessions touching different users and channels do not contend with each other.
+//! Locks are always taken user first, then channel.
+
 use anyhow::anyhow;
-use common::connect::ConnectionWrite;
-use common::types::{Channel, ErrorType, Nick, Target};
-use std::collections::{BTreeMap, BTreeSet};
-use std::sync::{Arc, Mutex};
+use common::connect::{ConnectionError, ConnectionSender, SharedMessage};
+use common::types::{Channel, ErrorType, Nick, PluginName, Target};
+use log::warn;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, RwLock};
+
+const SHARD_COUNT: usize = 16;
 
-pub struct UserConnections {
-    channels_per_user: BTreeMap<Nick, BTreeSet<Channel>>,
-    users_per_channel: BTreeMap<Channel, BTreeSet<Nick>>,
-    writers: BTreeMap<Nick, Arc<Mutex<ConnectionWrite>>>,
+/// Anything which can queue a serialized line for delivery to a connected client.
+pub trait ClientWriter: Clone {
+    fn write_shared(&self, message: &SharedMessage) -> Result<(), ConnectionError>;
 }
 
-impl UserConnections {
-    pub fn new() -> UserConnections {
-        UserConnections {
-            users_per_channel: BTreeMap::new(),
-            channels_per_user: BTreeMap::new(),
-            writers: BTreeMap::new(),
+impl ClientWriter for ConnectionSender {
+    fn write_shared(&self, message: &SharedMessage) -> Result<(), ConnectionError> {
+        ConnectionSender::write_shared(self, message)
+    }
+}
+
+/// Where a user's messages are delivered: a connected client, or a plugin acting as a service.
+#[derive(Clone)]
+pub enum Recipient {
+    Client(ConnectionSender),
+    /// The service of the named plugin. Services have no connection, so anything written to them is dropped,
+    /// and the plugin is called with the private messages it is sent instead
+    Service(PluginName),
+}
+
+impl ClientWriter for Recipient {
+    fn write_shared(&self, message: &SharedMessage) -> Result<(), ConnectionError> {
+        match self {
+            Recipient::Client(writer) => writer.write_shared(message),
+            Recipient::Service(_) => Ok(()),
         }
     }
+}
+
+/// Serializes a message once, ready to be handed to any number of writers.
+fn serialize(message: &str) -> SharedMessage {
+    SharedMessage::from(format!("{}\r\n", message.trim_end()).into_bytes())
+}
+
+pub struct UserConnections<W = Recipient> {
+    /// Every online user, each with the channels they are in
+    users: ShardedMap<Nick, Arc<UserEntry<W>>>,
+    /// Every channel which has been joined, each with its members
+    channels: ShardedMap<Channel, Arc<ChannelEntry<W>>>,
+}
+
+struct UserEntry<W> {
+    writer: W,
+    state: Mutex<UserState>,
+}
+
+struct UserState {
+    nick: Nick,
+    channels: BTreeSet<Channel>,
+    away_message: Option<String>,
+    is_operator: bool,
+    removed: bool,
+}
 
-    pub fn add_user(
-        &mut self,
-        nick: &Nick,
-        conn_write: Arc<Mutex<ConnectionWrite>>,
-    ) -> anyhow::Result<()> {
-        if self.writers.insert(nick.clone(), conn_write).is_some() {
-            Err(ErrorType::NickCollision)
+/// A copy of what is known about an online user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserSnapshot {
+    pub nick: Nick,
+    pub channels: Vec<Channel>,
+    pub away_message: Option<String>,
+}
+
+struct ChannelEntry<W> {
+    members: RwLock<BTreeMap<Nick, W>>,
+}
+
+struct ShardedMap<K, V> {
+    shards: Vec<RwLock<HashMap<K, V>>>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> ShardedMap<K, V> {
+    fn new() -> Self {
+        ShardedMap {
+            shards: (0..SHARD_COUNT)
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &RwLock<HashMap<K, V>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARD_COUNT]
+    }
+
+    fn get(&self, key: &K) -> Option<V> {
+        self.shard(key).read().unwrap().get(key).cloned()
+    }
+
+    fn get_or_insert_with(&self, key: &K, value: impl FnOnce() -> V) -> V {
+        if let Some(existing) = self.get(key) {
+            return existing;
+        }
+
+        self.shard(key)
+            .write()
+            .unwrap()
+            .entry(key.clone())
+            .or_insert_with(value)
+            .clone()
+    }
+
+    /// Inserts the value, unless the key is already taken.
+    fn insert_new(&self, key: &K, value: V) -> bool {
+        let mut shard = self.shard(key).write().unwrap();
+        if shard.contains_key(key) {
+            false
         } else {
+            shard.insert(key.clone(), value);
+            true
+        }
+    }
+
+    fn remove(&self, key: &K) -> Option<V> {
+        self.shard(key).write().unwrap().remove(key)
+    }
+
+    fn snapshot(&self) -> Vec<(K, V)> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+}
+
+impl<W: ClientWriter> Default for UserConnections<W> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<W: ClientWriter> UserConnections<W> {
+    pub fn new() -> UserConnections<W> {
+        UserConnections {
+            users: ShardedMap::new(),
+            channels: ShardedMap::new(),
+        }
+    }
+
+    pub fn add_user(&self, nick: &Nick, conn_write: W) -> anyhow::Result<()> {
+        let entry = Arc::new(UserEntry {
+            writer: conn_write,
+            state: Mutex::new(UserState {
+                nick: nick.clone(),
+                channels: BTreeSet::new(),
+                away_message: None,
+                is_operator: false,
+                removed: false,
+            }),
+        });
+
+        if self.users.insert_new(nick, entry) {
             Ok(())
+        } else {
+            Err(anyhow!(ErrorType::NickCollision))
         }
-        .map_err(|e| anyhow!(e))
     }
 
-    pub fn remove_user(&mut self, nick: &Nick) {
-        self.writers.remove(nick);
-        if let Some(channels) = self.channels_per_user.get(&nick.clone()) {
-            for channel in channels.iter() {
-                self.users_per_channel
-                    .get_mut(channel)
-                    .map(|nicks| nicks.remove(nick));
-            }
+    fn user(&self, nick: &Nick) -> anyhow::Result<Arc<UserEntry<W>>> {
+        self.users
+            .get(nick)
+            .ok_or_else(|| anyhow!(ErrorType::NoSuchNick))
+    }
+
+    fn channel(&self, channel: &Channel) -> Arc<ChannelEntry<W>> {
+        self.channels.get_or_insert_with(channel, || {
+            Arc::new(ChannelEntry {
+                members: RwLock::new(BTreeMap::new()),
+            })
+        })
+    }
+
+    pub fn rename_user(&self, nick: &Nick, new_nick: &Nick) -> anyhow::Result<()> {
+        let user = self.user(nick)?;
+        let mut state = user.state.lock().unwrap();
+        if state.removed {
+            return Err(anyhow!(ErrorType::NoSuchNick));
+        }
+
+        if !self.users.insert_new(new_nick, user.clone()) {
+            return Err(anyhow!(ErrorType::NickCollision));
+        }
+
+        for channel in state.channels.iter() {
+            let channel = self.channel(channel);
+            let mut members = channel.members.write().unwrap();
+            members.remove(&state.nick);
+            members.insert(new_nick.clone(), user.writer.clone());
+        }
+
+        self.users.remove(&state.nick);
+        state.nick = new_nick.clone();
+
+        Ok(())
+    }
+
+    pub fn set_away(&self, nick: &Nick, away_message: Option<String>) {
+        if let Ok(user) = self.user(nick) {
+            user.state.lock().unwrap().away_message = away_message;
         }
+    }
 
-        self.channels_per_user.remove(nick);
+    pub fn away_message(&self, nick: &Nick) -> Option<String> {
+        self.user(nick)
+            .ok()
+            .and_then(|user| user.state.lock().unwrap().away_message.clone())
     }
 
-    pub fn add_user_to_channel(&mut self, nick: &Nick, channel: &Channel) -> anyhow::Result<()> {
-        if !self.writers.contains_key(nick) {
-            panic!("User {nick} does not already exist before being added to channel {channel}");
+    pub fn set_operator(&self, nick: &Nick) {
+        if let Ok(user) = self.user(nick) {
+            user.state.lock().unwrap().is_operator = true;
         }
+    }
 
-        self.users_per_channel
-            .entry(channel.clone())
-            .or_default()
-            .insert(nick.clone());
-        self.channels_per_user
-            .entry(nick.clone())
-            .or_default()
-            .insert(channel.clone());
+    /// Every online operator.
+    pub fn operators(&self) -> Vec<Nick> {
+        self.users
+            .snapshot()
+            .into_iter()
+            .filter_map(|(_, user)| {
+                let state = user.state.lock().unwrap();
+                (state.is_operator && !state.removed).then(|| state.nick.clone())
+            })
+            .collect()
+    }
+
+    /// The user, if they are online.
+    pub fn user_snapshot(&self, nick: &Nick) -> Option<UserSnapshot> {
+        let user = self.user(nick).ok()?;
+        let state = user.state.lock().unwrap();
+        if state.removed {
+            return None;
+        }
+
+        Some(UserSnapshot {
+            nick: state.nick.clone(),
+            channels: state.channels.iter().cloned().collect(),
+            away_message: state.away_message.clone(),
+        })
+    }
+
+    /// Every channel with at least one member, along with how many members it has.
+    pub fn channels(&self) -> Vec<(Channel, usize)> {
+        let mut channels = self
+            .channels
+            .snapshot()
+            .into_iter()
+            .map(|(channel, entry)| (channel, entry.members.read().unwrap().len()))
+            .filter(|(_, member_count)| *member_count > 0)
+            .collect::<Vec<_>>();
+        channels.sort();
+
+        channels
+    }
+
+    /// The nicks of everyone in the channel.
+    pub fn channel_members(&self, channel: &Channel) -> Vec<Nick> {
+        self.channels
+            .get(channel)
+            .map(|entry| entry.members.read().unwrap().keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn remove_user(&self, nick: &Nick) {
+        let user = match self.user(nick) {
+            Ok(user) => user,
+            Err(_) => return,
+        };
+
+        let mut state = user.state.lock().unwrap();
+        if state.removed {
+            return;
+        }
+
+        for channel in std::mem::take(&mut state.channels).iter() {
+            self.channel(channel)
+                .members
+                .write()
+                .unwrap()
+                .remove(&state.nick);
+        }
+
+        state.removed = true;
+        self.users.remove(&state.nick);
+    }
+
+    pub fn add_user_to_channel(&self, nick: &Nick, channel: &Channel) -> anyhow::Result<()> {
+        let user = self.user(nick)?;
+        let mut state = user.state.lock().unwrap();
+        if state.removed {
+            return Err(anyhow!(ErrorType::NoSuchNick));
+        }
+
+        self.channel(channel)
+            .members
+            .write()
+            .unwrap()
+            .insert(state.nick.clone(), user.writer.clone());
+        state.channels.insert(channel.clone());
 
         Ok(())
     }
 
-    pub fn remove_user_from_channel(
-        &mut self,
-        nick: &Nick,
-        channel: &Channel,
-    ) -> anyhow::Result<()> {
-        if !self.writers.contains_key(nick) {
-            panic!(
-                "User {nick} does not already exist before being removed from channel {channel}"
-            );
+    pub fn remove_user_from_channel(&self, nick: &Nick, channel: &Channel) -> anyhow::Result<()> {
+        let user = self.user(nick)?;
+        let mut state = user.state.lock().unwrap();
+        if state.removed {
+            return Err(anyhow!(ErrorType::NoSuchNick));
         }
 
-        self.users_per_channel
-            .entry(channel.clone())
-            .or_default()
-            .remove(nick);
-        self.channels_per_user
-            .entry(nick.clone())
-            .or_default()
-            .remove(channel);
+        self.channel(channel)
+            .members
+            .write()
+            .unwrap()
+            .remove(&state.nick);
+        state.channels.remove(channel);
 
         Ok(())
     }
 
-    pub fn write(&mut self, target: &Target, message: &str) -> anyhow::Result<()> {
+    pub fn write(&self, target: &Target, message: &str) -> anyhow::Result<()> {
         match target {
             Target::User(nick) => self.write_to_user(nick, message),
             Target::Channel(channel) => self.write_to_channel(channel, message),
         }
     }
 
-    pub fn write_to_user(&mut self, target: &Nick, message: &str) -> anyhow::Result<()> {
-        match self.writers.get_mut(target) {
-            Some(writer) => {
-                writer
-                    .lock()
-                    .unwrap()
-                    .write_message(format!("{}\r\n", message.trim_end()).as_str())?;
-                Ok(())
+    pub fn write_to_user(&self, target: &Nick, message: &str) -> anyhow::Result<()> {
+        let user = self.user(target)?;
+        user.writer.write_shared(&serialize(message))?;
+
+        Ok(())
+    }
+
+    pub fn write_to_channel(&self, target: &Channel, message: &str) -> anyhow::Result<()> {
+        let channel = self
+            .channels
+            .get(target)
+            .ok_or_else(|| anyhow!(ErrorType::NoSuchChannel))?;
+
+        let message = serialize(message);
+        for (nick, writer) in channel.members.read().unwrap().iter() {
+            if let Err(err) = writer.write_shared(&message) {
+                warn!("Failed to deliver message to {nick}: {err}");
             }
-            None => Err(ErrorType::NoSuchNick),
         }
-        .map_err(|e| anyhow!(e))
+
+        Ok(())
     }
 
-    pub fn write_to_channel(&mut self, target: &Channel, message: &str) -> anyhow::Result<()> {
-        let nicks = match self.users_per_channel.get(target) {
-            Some(nicks) => Ok(nicks.clone()),
-            None => Err(ErrorType::NoSuchChannel),
+    /// Writes a message exactly once to every user (other than `target`) sharing at least one channel with `target`.
+    /// A recipient which cannot be written to is skipped, so it does not stop delivery to the rest.
+    pub fn write_to_peers(&self, target: &Nick, message: &str) {
+        let user = match self.user(target) {
+            Ok(user) => user,
+            Err(_) => return,
+        };
+
+        let mut recipients = BTreeMap::new();
+        {
+            let state = user.state.lock().unwrap();
+            for channel in state.channels.iter() {
+                let channel = self.channel(channel);
+                for (nick, writer) in channel.members.read().unwrap().iter() {
+                    if *nick != state.nick {
+                        recipients.insert(nick.clone(), writer.clone());
+                    }
+                }
+            }
+        }
+
+        let message = serialize(message);
+        for (nick, writer) in recipients {
+            if let Err(err) = writer.write_shared(&message) {
+                warn!("Failed to deliver message to {nick}: {err}");
+            }
         }
-        .map_err(|e| anyhow!(e))?;
+    }
+}
 
-        for nick in nicks {
-            self.write_to_user(&nick, message)?;
+impl UserConnections<Recipient> {
+    /// The plugin whose service has the nick, if it belongs to one.
+    pub fn service(&self, nick: &Nick) -> Option<PluginName> {
+        let user = self.user(nick).ok()?;
+        if user.state.lock().unwrap().removed {
+            return None;
         }
 
-        Ok(())
+        match &user.writer {
+            Recipient::Service(pl_name) => Some(pl_name.clone()),
+            Recipient::Client(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    #[derive(Clone)]
+    struct NullWriter;
+
+    impl ClientWriter for NullWriter {
+        fn write_shared(&self, _: &SharedMessage) -> Result<(), ConnectionError> {
+            Ok(())
+        }
+    }
+
+    /// Checks that users and channels agree on who is in which channel.
+    /// Each user is checked while their lock is held, as every change to their channels is,
+    /// so the check holds while other threads are changing the maps.
+    fn assert_invariants(user_connections: &UserConnections<NullWriter>) {
+        for (_, user) in user_connections.users.snapshot() {
+            let state = user.state.lock().unwrap();
+            if state.removed {
+                continue;
+            }
+
+            let current = user_connections.users.get(&state.nick).unwrap();
+            assert!(Arc::ptr_eq(&current, &user));
+            for channel in state.channels.iter() {
+                let channel = user_connections.channels.get(channel).unwrap();
+                assert!(channel.members.read().unwrap().contains_key(&state.nick));
+            }
+            for (channel, entry) in user_connections.channels.snapshot() {
+                if entry.members.read().unwrap().contains_key(&state.nick) {
+                    assert!(state.channels.contains(&channel));
+                }
+            }
+        }
     }
 
-    pub fn write_to_users_channel(&mut self, target: &Nick, message: &str) -> anyhow::Result<()> {
-        if let Some(channels) = self.channels_per_user.get(&target.clone()) {
-            for channel in channels.clone().iter() {
-                self.write_to_channel(channel, message)?;
+    /// Checks that every member of every channel is online, which only holds once nothing is changing.
+    fn assert_members_are_online(user_connections: &UserConnections<NullWriter>) {
+        for (channel, entry) in user_connections.channels.snapshot() {
+            for nick in entry.members.read().unwrap().keys() {
+                let user = user_connections.users.get(nick).unwrap();
+                assert!(user.state.lock().unwrap().channels.contains(&channel));
             }
         }
+    }
 
-        Ok(())
+    #[test]
+    fn test_parallel_join_part_quit_keeps_maps_consistent() {
+        let user_connections = Arc::new(UserConnections::<NullWriter>::new());
+        let channels = (0..8)
+            .map(|i| Channel(format!("#chan{i}")))
+            .collect::<Vec<_>>();
+
+        let running = AtomicBool::new(true);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                while running.load(Ordering::Relaxed) {
+                    assert_invariants(&user_connections);
+                }
+            });
+
+            let workers = (0..8)
+                .map(|worker| {
+                    let user_connections = user_connections.clone();
+                    let channels = channels.clone();
+                    s.spawn(move || {
+                        for round in 0..200 {
+                            let nick = Nick(format!("w{worker}r{round}"));
+                            user_connections.add_user(&nick, NullWriter).unwrap();
+
+                            for (i, channel) in channels.iter().enumerate() {
+                                if (i + round + worker) % 3 != 0 {
+                                    user_connections
+                                        .add_user_to_channel(&nick, channel)
+                                        .unwrap();
+                                }
+                            }
+                            for channel in channels.iter().step_by(2) {
+                                user_connections
+                                    .remove_user_from_channel(&nick, channel)
+                                    .unwrap();
+                            }
+                            user_connections.write_to_peers(&nick, "hello");
+
+                            if round % 4 == 0 {
+                                // Leave some users behind, so later rounds share channels with them
+                                let renamed = Nick(format!("x{worker}r{round}"));
+                                user_connections.rename_user(&nick, &renamed).unwrap();
+                            } else {
+                                user_connections.remove_user(&nick);
+                            }
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            for worker in workers {
+                worker.join().unwrap();
+            }
+            running.store(false, Ordering::Relaxed);
+        });
+
+        assert_invariants(&user_connections);
+        assert_members_are_online(&user_connections);
     }
 }
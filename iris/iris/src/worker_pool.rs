@@ -0,0 +1,230 @@
+//! # Worker pool
+//! A fixed set of threads which run jobs handed to them, so that work such as plugin calls
+//! does not need a fresh thread each time. `ConcurrencyLimit` caps how many jobs of one kind
+//! may be in flight at once, so a single kind of job cannot take over the pool.
+//! A job which is stuck can be stood in for, so the pool keeps its strength while it waits on it.
+
+use std::cell::Cell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+thread_local! {
+    /// Set on a worker which has been stood in for, so that it stops once its job returns.
+    static RETIRING: Cell<bool> = const { Cell::new(false) };
+}
+
+pub struct WorkerPool {
+    name: String,
+    sender: Sender<Job>,
+    receiver: Arc<Mutex<Receiver<Job>>>,
+    /// How many workers have been spawned, stand ins included, to give each its own name
+    spawned: AtomicUsize,
+}
+
+impl WorkerPool {
+    pub fn new(name: &str, size: usize) -> WorkerPool {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let pool = WorkerPool {
+            name: name.to_string(),
+            sender,
+            receiver: Arc::new(Mutex::new(receiver)),
+            spawned: AtomicUsize::new(0),
+        };
+
+        for _ in 0..size.max(1) {
+            pool.spawn();
+        }
+
+        pool
+    }
+
+    fn spawn(&self) {
+        let id = self.spawned.fetch_add(1, Ordering::Relaxed);
+        let receiver = self.receiver.clone();
+        thread::Builder::new()
+            .name(format!("{}-{id}", self.name))
+            .spawn(move || Self::work(&receiver))
+            .expect("failed to spawn worker thread");
+    }
+
+    fn work(receiver: &Mutex<Receiver<Job>>) {
+        loop {
+            let job = receiver.lock().unwrap().recv();
+            match job {
+                // A panicking job must not take its worker down with it
+                Ok(job) => {
+                    let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                    if RETIRING.get() {
+                        return;
+                    }
+                }
+                // The pool has been dropped
+                Err(_) => return,
+            }
+        }
+    }
+
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        // Workers only stop once the pool (and so this sender) is dropped
+        let _ = self.sender.send(Box::new(job));
+    }
+}
+
+pub struct ConcurrencyLimit {
+    limit: usize,
+    in_flight: Arc<AtomicUsize>,
+}
+
+/// Held while a job runs, releasing its slot when dropped.
+pub struct ConcurrencyPermit {
+    in_flight: Arc<AtomicUsize>,
+    released: AtomicBool,
+}
+
+/// Shared between a job and whoever finds that it has overrun,
+/// so that a worker stuck on the job is stood in for, and stops once the job returns.
+#[derive(Clone, Default)]
+pub struct StandIn {
+    /// Set by whichever of the two gets here first
+    settled: Arc<AtomicBool>,
+}
+
+impl StandIn {
+    /// Spawns a worker to take the place of the one running the job, unless the job has already returned.
+    /// Returns whether it did.
+    pub fn take_over(&self, pool: &WorkerPool) -> bool {
+        let stood_in = !self.settled.swap(true, Ordering::AcqRel);
+        if stood_in {
+            pool.spawn();
+        }
+
+        stood_in
+    }
+
+    /// Called by the job as it returns, from the worker running it.
+    /// If the job has been stood in for, its worker stops rather than taking another job.
+    pub fn finish(&self) {
+        if self.settled.swap(true, Ordering::AcqRel) {
+            RETIRING.set(true);
+        }
+    }
+}
+
+impl ConcurrencyLimit {
+    pub fn new(limit: usize) -> ConcurrencyLimit {
+        ConcurrencyLimit {
+            limit,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Takes a slot, unless `limit` jobs are already in flight.
+    pub fn try_acquire(&self) -> Option<ConcurrencyPermit> {
+        self.in_flight
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |in_flight| {
+                (in_flight < self.limit).then_some(in_flight + 1)
+            })
+            .ok()
+            .map(|_| ConcurrencyPermit {
+                in_flight: self.in_flight.clone(),
+                released: AtomicBool::new(false),
+            })
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+}
+
+impl ConcurrencyPermit {
+    /// Gives the slot back before the permit is dropped, such as for a job which has been given up on.
+    pub fn release(&self) {
+        if !self.released.swap(true, Ordering::AcqRel) {
+            self.in_flight.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    #[test]
+    fn test_limit_releases_on_drop() {
+        let limit = ConcurrencyLimit::new(2);
+        let first = limit.try_acquire().unwrap();
+        let _second = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+
+        drop(first);
+        assert!(limit.try_acquire().is_some());
+    }
+
+    #[test]
+    fn test_jobs_run_concurrently() {
+        let pool = WorkerPool::new("test", 2);
+        let (started, wait_started) = channel();
+        let (release, wait_release) = channel::<()>();
+        let wait_release = Arc::new(Mutex::new(wait_release));
+
+        // The first job blocks until released, so the second can only finish if it runs alongside
+        let blocked = wait_release.clone();
+        pool.execute(move || {
+            let _ = blocked.lock().unwrap().recv();
+        });
+        pool.execute(move || started.send(()).unwrap());
+
+        assert!(wait_started.recv_timeout(Duration::from_secs(5)).is_ok());
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn test_permits_are_only_released_once() {
+        let limit = ConcurrencyLimit::new(1);
+        let permit = limit.try_acquire().unwrap();
+
+        permit.release();
+        assert_eq!(limit.in_flight(), 0);
+        drop(permit);
+        assert_eq!(limit.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_stuck_jobs_are_stood_in_for() {
+        let pool = WorkerPool::new("test", 1);
+        let stand_in = StandIn::default();
+        let (release, wait_release) = channel::<()>();
+        let (finished, wait_finished) = channel();
+
+        let stuck = stand_in.clone();
+        pool.execute(move || {
+            let _ = wait_release.recv();
+            stuck.finish();
+        });
+        assert!(stand_in.take_over(&pool));
+
+        // The stand in picks up the next job while the only other worker is stuck
+        let first = finished.clone();
+        pool.execute(move || first.send(()).unwrap());
+        assert!(wait_finished.recv_timeout(Duration::from_secs(5)).is_ok());
+
+        // Once the stuck job returns its worker stops, leaving the stand in to carry on
+        release.send(()).unwrap();
+        pool.execute(move || finished.send(()).unwrap());
+        assert!(wait_finished.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert_eq!(pool.spawned.load(Ordering::Relaxed), 2);
+    }
+}
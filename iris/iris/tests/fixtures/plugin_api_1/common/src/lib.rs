@@ -0,0 +1,3 @@
+// `sabi_trait` expands to code which newer compilers warn about
+#[allow(non_local_definitions, repr_transparent_non_zst_fields)]
+pub mod plugin;
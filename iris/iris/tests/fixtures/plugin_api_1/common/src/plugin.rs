@@ -0,0 +1,94 @@
+//! `common::plugin` at plugin API 1, trimmed to what a plugin needs.
+
+use abi_stable::{
+    declare_root_module_statics,
+    library::{LibraryError, RootModule},
+    package_version_strings, sabi_trait,
+    sabi_types::VersionStrings,
+    std_types::{RArc, ROption, RResult, RString, RVec},
+    StableAbi,
+};
+
+#[repr(C)]
+#[derive(StableAbi)]
+#[sabi(kind(Prefix(prefix_ref = "PluginMod_Ref")))]
+#[sabi(missing_field(panic))]
+pub struct PluginMod {
+    pub init: extern "C" fn(host: PluginHostRef),
+    pub pl_name: extern "C" fn() -> RPluginName,
+    pub handler: extern "C" fn(
+        host: PluginHostRef,
+        sender: RNick,
+        real_name: RString,
+        message: RPluginMsg,
+    ) -> RResult<ROption<RPluginReply>, RString>,
+}
+
+#[sabi_trait]
+pub trait PluginHost: Send + Sync + Clone {
+    fn schedule(
+        &self,
+        delay_ms: u64,
+        interval_ms: ROption<u64>,
+        reply: RPluginReply,
+    ) -> RTimerHandle;
+
+    fn cancel(&self, handle: RTimerHandle) -> bool;
+
+    fn send(&self, target: RTarget, message: RString) -> RResult<(), RString>;
+
+    fn join(&self, channel: RChannel) -> RResult<(), RString>;
+
+    #[sabi(last_prefix_field)]
+    fn part(&self, channel: RChannel) -> RResult<(), RString>;
+}
+
+pub type PluginHostRef = PluginHost_TO<'static, RArc<()>>;
+
+#[repr(C)]
+#[derive(StableAbi, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RTimerHandle(pub u64);
+
+#[repr(C)]
+#[derive(StableAbi)]
+pub struct RNick(pub RString);
+
+#[repr(C)]
+#[derive(StableAbi)]
+pub struct RChannel(pub RString);
+
+#[repr(C)]
+#[derive(StableAbi)]
+pub enum RTarget {
+    RChannel(RChannel),
+    RUser(RNick),
+}
+
+#[repr(C)]
+#[derive(StableAbi)]
+pub struct RPluginName(pub RString);
+
+#[repr(C)]
+#[derive(StableAbi)]
+pub struct RPluginReply {
+    pub target: RTarget,
+    pub message: RString,
+}
+
+#[repr(C)]
+#[derive(StableAbi)]
+pub struct RPluginMsg {
+    pub plugin_name: RPluginName,
+    pub args: RVec<RString>,
+}
+
+impl RootModule for PluginMod_Ref {
+    declare_root_module_statics! {PluginMod_Ref}
+    const BASE_NAME: &'static str = "iris";
+    const NAME: &'static str = "iris";
+    const VERSION_STRINGS: VersionStrings = package_version_strings!();
+
+    fn initialization(self) -> Result<Self, LibraryError> {
+        Ok(self)
+    }
+}
@@ -0,0 +1,45 @@
+// `sabi_extern_fn` drops its arguments explicitly, which clippy flags for types without `Drop`
+#![allow(clippy::drop_non_drop)]
+
+use abi_stable::{
+    export_root_module,
+    prefix_type::PrefixTypeTrait,
+    sabi_extern_fn,
+    std_types::{ROption, RResult, RString},
+};
+use common::plugin::{
+    PluginHostRef, PluginMod, PluginMod_Ref, RNick, RPluginMsg, RPluginName, RPluginReply,
+    RTarget,
+};
+
+#[sabi_extern_fn]
+pub fn init(_: PluginHostRef) {}
+
+#[sabi_extern_fn]
+pub fn pl_name() -> RPluginName {
+    RPluginName(RString::from("/oldtimer"))
+}
+
+/// Answers with how many arguments it was given.
+#[sabi_extern_fn]
+pub fn handler(
+    _: PluginHostRef,
+    sender: RNick,
+    _: RString,
+    msg: RPluginMsg,
+) -> RResult<ROption<RPluginReply>, RString> {
+    RResult::ROk(ROption::RSome(RPluginReply {
+        target: RTarget::RUser(sender),
+        message: format!("{} arguments, the old way", msg.args.len()).into(),
+    }))
+}
+
+#[export_root_module]
+fn instantiate_root_module() -> PluginMod_Ref {
+    PluginMod {
+        init,
+        pl_name,
+        handler,
+    }
+    .leak_into_prefix()
+}
@@ -10,6 +10,9 @@
 //! To run IRIS with the plugin loaded, simply run:
 //! > cargo +nightly run -- --plugins '/path/to/the/plugin.so'
 
+// `sabi_extern_fn` drops its arguments explicitly, which clippy flags for types without `Drop`
+#![allow(clippy::drop_non_drop)]
+
 use abi_stable::{
     export_root_module,
     prefix_type::PrefixTypeTrait,
@@ -18,14 +21,18 @@ use abi_stable::{
 };
 
 use common::plugin::{
-    PluginMod, PluginMod_Ref, RNick, RPluginMsg, RPluginName, RPluginReply, RTarget,
+    catch_panics, plugin_api_version, PluginHostRef, PluginMod, PluginMod_Ref, RNick, RPluginMsg,
+    RPluginName, RPluginReply, RTarget,
 };
 
 /// # Plugin Initialisation
 /// This function is run on plugin startup.
 /// It can be used to start up any initial required state.
+/// It is handed the host, which a plugin can keep in order to use the server's services later,
+/// such as sending messages unprompted, joining channels, scheduling delayed deliveries,
+/// or keeping data in a store of its own which survives the server restarting.
 #[sabi_extern_fn]
-pub fn init() {
+pub fn init(_: PluginHostRef) {
     // We require no initialisation
     // So we leave it empty
 }
@@ -39,6 +46,24 @@ pub fn pl_name() -> RPluginName {
     RPluginName(RString::from("/example"))
 }
 
+/// # Plugin Metadata
+/// These functions describe the plugin to users, through `PLUGIN /help /example` and `VERSION`.
+/// Each is optional, and is only asked for once, when the plugin is loaded.
+#[sabi_extern_fn]
+pub fn version() -> RString {
+    env!("CARGO_PKG_VERSION").into()
+}
+
+#[sabi_extern_fn]
+pub fn description() -> RString {
+    "Echoes your message back to you".into()
+}
+
+#[sabi_extern_fn]
+pub fn usage() -> RString {
+    "PLUGIN /example :message".into()
+}
+
 /// # Plugin Handler
 /// This function will be run whenever the plugin command is typed.
 /// For example: `PLUGIN /example :hi`.
@@ -46,20 +71,25 @@ pub fn pl_name() -> RPluginName {
 /// You return a Result (errors as string can be raised in case input is invalid).
 /// The Result contains an Optional reply. This will be sent to the appropriate target.
 /// If you do not want your plugin to output anything, simply have this optional be None.
+/// To say more than one thing, or to say something to someone else, use the host's `send`.
+/// The body is wrapped in `catch_panics`, as a panic escaping the plugin would take the server down with it.
 #[sabi_extern_fn]
 pub fn handler(
+    _: PluginHostRef,
     sender: RNick,
     real_name: RString,
     msg: RPluginMsg,
 ) -> RResult<ROption<RPluginReply>, RString> {
-    if msg.args.len() != 1 {
-        RResult::RErr(RString::from("Expected 1 argument"))
-    } else {
-        RResult::ROk(ROption::RSome(RPluginReply {
-            target: RTarget::RUser(sender),
-            message: format!("Echo \"{}\" to \"{}\"", msg.args[0].clone(), real_name).into(),
-        }))
-    }
+    catch_panics(|| {
+        if msg.args.len() != 1 {
+            RResult::RErr(RString::from("Expected 1 argument"))
+        } else {
+            RResult::ROk(ROption::RSome(RPluginReply {
+                target: RTarget::RUser(sender),
+                message: format!("Echo \"{}\" to \"{}\"", msg.args[0].clone(), real_name).into(),
+            }))
+        }
+    })
 }
 
 #[export_root_module]
@@ -68,6 +98,30 @@ fn instantiate_root_module() -> PluginMod_Ref {
         init,
         pl_name,
         handler,
+        // Hooks let a plugin react to the rest of the server's traffic,
+        // such as `on_join: Some(on_join)` to hear about every user joining a channel.
+        // This plugin only answers its own command, so it has none
+        on_join: None,
+        on_part: None,
+        on_quit: None,
+        on_privmsg: None,
+        on_nick: None,
+        intercept: None,
+        // `verbs` and `triggers` would let users call it as `ECHO :hi` or by saying `!echo :hi` in a channel
+        verbs: None,
+        triggers: None,
+        // `handler_with_context` would be called instead of `handler`, with the channel it was called from and more
+        handler_with_context: None,
+        // `deinit` would be called when the plugin is unloaded, to undo anything `init` set up
+        deinit: None,
+        version: Some(version),
+        description: Some(description),
+        usage: Some(usage),
+        author: None,
+        // `init_with_config` would be called instead of `init`, with the plugin's `--plugin-setting`s
+        init_with_config: None,
+        // Tells the server which version of the plugin API the plugin was built against
+        api_version: Some(plugin_api_version),
     }
     .leak_into_prefix()
 }
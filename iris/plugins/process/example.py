@@ -0,0 +1,100 @@
+#!/usr/bin/env python3
+"""An example process plugin.
+
+IRIS starts any plugin which is not a native library as a program, and talks to it with
+JSON-RPC 2.0, one message per line, over its stdin and stdout. The messages are described
+in iris/src/plugin_process.rs. To run IRIS with this plugin loaded, make it executable and run:
+> cargo run -- --plugins 'plugins/process/example.py'
+
+It echoes your message back to you, counting how many times you have asked in its store,
+and says hello to everyone who joins a channel it has joined.
+"""
+
+import json
+import sys
+
+# Messages which arrived while waiting on a response from the host, to be handled afterwards
+backlog = []
+next_id = 0
+
+
+def write(message):
+    message["jsonrpc"] = "2.0"
+    sys.stdout.write(json.dumps(message) + "\n")
+    sys.stdout.flush()
+
+
+def host(method, **params):
+    """Makes a request of the host, waiting for its response."""
+    global next_id
+    next_id += 1
+    write({"id": next_id, "method": method, "params": params})
+
+    for line in sys.stdin:
+        message = json.loads(line)
+        if "method" in message:
+            backlog.append(message)
+        elif message.get("id") == next_id:
+            if "error" in message:
+                raise RuntimeError(message["error"]["message"])
+            return message.get("result")
+    sys.exit(0)
+
+
+def describe(_):
+    return {
+        "name": "/pyexample",
+        "version": "0.1.0",
+        "description": "Echoes your message back to you, from Python",
+        "usage": "PLUGIN /pyexample :message",
+        "hooks": ["join"],
+    }
+
+
+def init(params):
+    host("join", channel=params["settings"].get("channel", "#python"))
+
+
+def handle(invocation):
+    if len(invocation["args"]) != 1:
+        raise ValueError("Expected 1 argument")
+
+    key = "count:" + invocation["sender"]
+    count = int(host("store_get", key=key) or 0) + 1
+    host("store_set", key=key, value=str(count))
+
+    return {
+        "target": invocation["origin"],
+        "message": f'Echo "{invocation["args"][0]}" ({count} so far)',
+    }
+
+
+def join(params):
+    if params["sender"] != "pyexample":
+        host("send", target={"channel": params["channel"]}, message=f"Hello, {params['sender']}!")
+
+
+METHODS = {"describe": describe, "init": init, "handle": handle, "join": join, "deinit": lambda _: None}
+
+
+def dispatch(message):
+    method = METHODS.get(message["method"])
+    try:
+        if method is None:
+            raise ValueError(f"Unknown method {message['method']}")
+        result, error = method(message.get("params")), None
+    except Exception as err:
+        result, error = None, {"code": -32000, "message": str(err)}
+
+    # Notifications have no id, and are not answered
+    if "id" in message:
+        if error:
+            write({"id": message["id"], "error": error})
+        else:
+            write({"id": message["id"], "result": result})
+
+
+for line in sys.stdin:
+    backlog.append(json.loads(line))
+    while backlog:
+        dispatch(backlog.pop(0))
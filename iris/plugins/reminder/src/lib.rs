@@ -1,18 +1,81 @@
+// `sabi_extern_fn` drops its arguments explicitly, which clippy flags for types without `Drop`
+#![allow(clippy::drop_non_drop)]
+
 use abi_stable::{
     export_root_module,
     prefix_type::PrefixTypeTrait,
     sabi_extern_fn,
-    std_types::{ROption, RResult, RString},
+    std_types::{RHashMap, ROption, RResult, RString, RVec, Tuple2},
 };
-
-use std::{thread, time};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
 
 use common::plugin::{
-    PluginMod, PluginMod_Ref, RNick, RPluginMsg, RPluginName, RPluginReply, RTarget,
+    catch_panics, plugin_api_version, PluginHostRef, PluginMod, PluginMod_Ref, RChannel,
+    RInvocation, RNick, RNickMsg, RPluginMsg, RPluginName, RPluginReply, RQuitMsg, RTarget,
+    RTimerHandle,
 };
 
+const USAGE: &str = "REMIND {interval in seconds} :message, \
+    REMIND every {interval in seconds} :message, or REMIND cancel {id}. \
+    Your reminders are cancelled when you change nick or quit";
+
+/// The shortest interval, in seconds, a recurring reminder may be set for
+const MIN_RECURRING_INTERVAL: u64 = 60;
+
+/// The most reminders a single user may have pending at once
+const MAX_REMINDERS_PER_USER: usize = 10;
+
+/// The longest interval a reminder may be set for, in seconds, where 0 means there is no limit
+static MAX_INTERVAL: AtomicU64 = AtomicU64::new(0);
+
+/// Each user's pending reminders, by id, along with when the ones which only fire once are due.
+/// A user may only cancel their own.
+static REMINDERS: Mutex<BTreeMap<String, HashMap<u64, Option<Instant>>>> =
+    Mutex::new(BTreeMap::new());
+
+/// The pending reminders of the user with `nick`, forgetting any which have fired.
+fn reminders_of<'a>(
+    reminders: &'a mut BTreeMap<String, HashMap<u64, Option<Instant>>>,
+    nick: &RNick,
+) -> &'a mut HashMap<u64, Option<Instant>> {
+    let now = Instant::now();
+    let pending = reminders.entry(nick.0.to_string()).or_default();
+    pending.retain(|_, due| due.is_none_or(|due| due > now));
+    pending
+}
+
 #[sabi_extern_fn]
-pub fn init() {}
+pub fn init(_: PluginHostRef) {}
+
+/// Takes one setting, `max_interval`: the longest interval in seconds a reminder may be set for.
+/// For example: `--plugin-setting /remind:max_interval=86400`
+#[sabi_extern_fn]
+pub fn init_with_config(
+    _: PluginHostRef,
+    config: RHashMap<RString, RString>,
+) -> RResult<(), RString> {
+    catch_panics(|| {
+        for Tuple2(key, value) in config.iter() {
+            match key.as_str() {
+                "max_interval" => match value.parse::<u64>() {
+                    Ok(max_interval) => MAX_INTERVAL.store(max_interval, Ordering::Relaxed),
+                    Err(_) => {
+                        return RResult::RErr(
+                            format!("max_interval must be a number of seconds, not {}", value)
+                                .into(),
+                        )
+                    }
+                },
+                _ => return RResult::RErr(format!("Unknown setting {}", key).into()),
+            }
+        }
+
+        RResult::ROk(())
+    })
+}
 
 #[sabi_extern_fn]
 pub fn pl_name() -> RPluginName {
@@ -20,31 +83,198 @@ pub fn pl_name() -> RPluginName {
 }
 
 #[sabi_extern_fn]
-pub fn handler(
+pub fn version() -> RString {
+    env!("CARGO_PKG_VERSION").into()
+}
+
+#[sabi_extern_fn]
+pub fn description() -> RString {
+    "Sends you a message once some time has passed, or every so often".into()
+}
+
+#[sabi_extern_fn]
+pub fn usage() -> RString {
+    USAGE.into()
+}
+
+/// `REMIND 10 :message` works the same as `PLUGIN /remind 10 :message`
+#[sabi_extern_fn]
+pub fn verbs() -> RVec<RString> {
+    RVec::from(vec![RString::from("REMIND")])
+}
+
+/// So does saying `!remind 10 :message` in a channel
+#[sabi_extern_fn]
+pub fn triggers() -> RVec<RString> {
+    RVec::from(vec![RString::from("!remind")])
+}
+
+fn parse_interval(interval: &str) -> Result<u64, RString> {
+    interval
+        .parse::<u64>()
+        .map_err(|_| RString::from("Please provide a valid integer interval"))
+}
+
+/// Where a reminder is delivered: back where it was set, addressed to whoever set it
+fn reminder(sender: &RNick, origin: &RTarget, message: &str) -> RPluginReply {
+    match origin {
+        RTarget::RChannel(channel) => RPluginReply {
+            target: RTarget::RChannel(RChannel(channel.0.clone())),
+            message: format!("Reminder for {}: {}", sender.0, message).into(),
+        },
+        RTarget::RUser(_) => RPluginReply {
+            target: RTarget::RUser(RNick(sender.0.clone())),
+            message: format!("Reminder: {}", message).into(),
+        },
+    }
+}
+
+fn remind(
+    host: &PluginHostRef,
+    sender: &RNick,
+    reminder: RPluginReply,
+    interval: &str,
+    recurring: bool,
+) -> Result<RString, RString> {
+    let interval = parse_interval(interval)?;
+    let max_interval = MAX_INTERVAL.load(Ordering::Relaxed);
+    if max_interval > 0 && interval > max_interval {
+        return Err(format!("Reminders can be set for at most {} seconds", max_interval).into());
+    }
+    if recurring && interval < MIN_RECURRING_INTERVAL {
+        return Err(format!(
+            "Recurring reminders must be at least {} seconds apart",
+            MIN_RECURRING_INTERVAL
+        )
+        .into());
+    }
+
+    let mut reminders = REMINDERS.lock().unwrap_or_else(PoisonError::into_inner);
+    let pending = reminders_of(&mut reminders, sender);
+    if pending.len() >= MAX_REMINDERS_PER_USER {
+        return Err(format!(
+            "You already have {} reminders, cancel one to set another",
+            MAX_REMINDERS_PER_USER
+        )
+        .into());
+    }
+
+    let interval_ms = interval.saturating_mul(1000);
+    let handle = host
+        .try_schedule(
+            interval_ms,
+            if recurring {
+                ROption::RSome(interval_ms)
+            } else {
+                ROption::RNone
+            },
+            reminder,
+        )
+        .into_result()?;
+    let due = (!recurring).then(|| Instant::now() + Duration::from_millis(interval_ms));
+    pending.insert(handle.0, due);
+
+    Ok(format!(
+        "Reminder {} set, cancel it with REMIND cancel {}",
+        handle.0, handle.0
+    )
+    .into())
+}
+
+fn cancel(host: &PluginHostRef, sender: &RNick, id: &str) -> Result<RString, RString> {
+    let id = id
+        .parse::<u64>()
+        .map_err(|_| RString::from("Please provide a valid reminder id"))?;
+
+    let mut reminders = REMINDERS.lock().unwrap_or_else(PoisonError::into_inner);
+    if reminders_of(&mut reminders, sender).remove(&id).is_some() && host.cancel(RTimerHandle(id)) {
+        Ok(format!("Reminder {} cancelled", id).into())
+    } else {
+        Err(format!("You have no pending reminder {}", id).into())
+    }
+}
+
+/// Cancels every reminder the user with `nick` set, which would otherwise reach whoever takes the nick next.
+fn cancel_all(host: &PluginHostRef, nick: &RNick) {
+    let mut reminders = REMINDERS.lock().unwrap_or_else(PoisonError::into_inner);
+    for id in reminders
+        .remove(nick.0.as_str())
+        .unwrap_or_default()
+        .into_keys()
+    {
+        host.cancel(RTimerHandle(id));
+    }
+}
+
+#[sabi_extern_fn]
+pub fn on_quit(host: PluginHostRef, sender: RNick, _: RQuitMsg) {
+    cancel_all(&host, &sender);
+}
+
+#[sabi_extern_fn]
+pub fn on_nick(host: PluginHostRef, sender: RNick, _: RNickMsg) {
+    cancel_all(&host, &sender);
+}
+
+/// Confirmations are only ever sent to the sender, even when the reminder was set in a channel.
+/// A panic is handed back as an error, rather than taking the server down.
+fn run(
+    host: &PluginHostRef,
     sender: RNick,
-    _: RString,
+    origin: RTarget,
     msg: RPluginMsg,
 ) -> RResult<ROption<RPluginReply>, RString> {
-    if msg.args.len() != 2 {
-        RResult::RErr(RString::from(
-            "Expected 2 arguments. Ex: PLUGIN /remind {interval in seconds} :message",
-        ))
-    } else {
-        let interval = match msg.args[0].to_string().parse::<u64>() {
-            Ok(interval) => interval,
-            Err(_) => {
-                return RResult::RErr(RString::from("Please provide a valid integer interval"));
-            }
+    catch_panics(|| {
+        // The server's timer wheel delivers reminders, so no thread waits on them
+        let args = msg.args.iter().map(|arg| arg.as_str()).collect::<Vec<_>>();
+        let confirmation = match args.as_slice() {
+            ["cancel", id] => cancel(host, &sender, id),
+            ["every", interval, message] => remind(
+                host,
+                &sender,
+                reminder(&sender, &origin, message),
+                interval,
+                true,
+            ),
+            [interval, message] => remind(
+                host,
+                &sender,
+                reminder(&sender, &origin, message),
+                interval,
+                false,
+            ),
+            _ => Err(format!("Expected 2 or 3 arguments. Ex: {}", USAGE).into()),
         };
 
-        let message = msg.args[1].to_string();
-        thread::sleep(time::Duration::from_secs(interval));
+        match confirmation {
+            Ok(confirmation) => RResult::ROk(ROption::RSome(RPluginReply {
+                target: RTarget::RUser(sender),
+                message: confirmation,
+            })),
+            Err(err) => RResult::RErr(err),
+        }
+    })
+}
 
-        RResult::ROk(ROption::RSome(RPluginReply {
-            target: RTarget::RUser(sender),
-            message: format!("Reminder: {}", &message).into(),
-        }))
-    }
+/// Only called by servers which cannot say where the reminder was set, so it is sent to the sender
+#[sabi_extern_fn]
+pub fn handler(
+    host: PluginHostRef,
+    sender: RNick,
+    _: RString,
+    msg: RPluginMsg,
+) -> RResult<ROption<RPluginReply>, RString> {
+    let origin = RTarget::RUser(RNick(sender.0.clone()));
+    run(&host, sender, origin, msg)
+}
+
+#[sabi_extern_fn]
+pub fn handler_with_context(
+    host: PluginHostRef,
+    context: RInvocation,
+    msg: RPluginMsg,
+) -> RResult<ROption<RPluginReply>, RString> {
+    run(&host, context.sender, context.origin, msg)
 }
 
 #[export_root_module]
@@ -53,6 +283,23 @@ fn instantiate_root_module() -> PluginMod_Ref {
         init,
         pl_name,
         handler,
+        // A user's reminders go when they do
+        on_join: None,
+        on_part: None,
+        on_quit: Some(on_quit),
+        on_privmsg: None,
+        on_nick: Some(on_nick),
+        intercept: None,
+        verbs: Some(verbs),
+        triggers: Some(triggers),
+        handler_with_context: Some(handler_with_context),
+        deinit: None,
+        version: Some(version),
+        description: Some(description),
+        usage: Some(usage),
+        author: None,
+        init_with_config: Some(init_with_config),
+        api_version: Some(plugin_api_version),
     }
     .leak_into_prefix()
 }
@@ -0,0 +1,126 @@
+//! # An example WebAssembly plugin
+//!
+//! ## Introduction
+//! IRIS runs `.wasm` modules in a sandbox, calling them with the JSON messages described in
+//! iris/src/plugin_wasm.rs. A module can only reach the host methods it has been granted.
+//!
+//! ## Loading
+//! Build the plugin for WebAssembly, then grant it the store it counts calls in:
+//! > cargo build --release --target wasm32-unknown-unknown
+//! > cargo run -- --plugins 'plugins/wasm/target/wasm32-unknown-unknown/release/wasm_example.wasm' --wasm-grant '/wasmexample:store'
+//!
+//! It echoes your message back to you, along with how many times you have asked.
+
+use serde_json::{json, Value};
+use std::ptr;
+use std::sync::Mutex;
+
+#[link(wasm_import_module = "iris")]
+extern "C" {
+    fn host_call(ptr: *const u8, len: usize) -> u64;
+    fn log(ptr: *const u8, len: usize);
+}
+
+/// The last answer handed to the host, which is freed once the host calls again
+static ANSWER: Mutex<Option<Box<[u8]>>> = Mutex::new(None);
+
+/// Hands the host `len` bytes to write a call into.
+#[no_mangle]
+pub extern "C" fn iris_alloc(len: usize) -> *mut u8 {
+    Box::into_raw(vec![0u8; len].into_boxed_slice()) as *mut u8
+}
+
+/// Takes back memory handed out by `iris_alloc`.
+///
+/// # Safety
+/// `ptr` and `len` must be those of memory from `iris_alloc`, which is not used again.
+unsafe fn take(ptr: *mut u8, len: usize) -> Box<[u8]> {
+    Box::from_raw(ptr::slice_from_raw_parts_mut(ptr, len))
+}
+
+fn unpack(packed: u64) -> (*mut u8, usize) {
+    ((packed >> 32) as usize as *mut u8, packed as u32 as usize)
+}
+
+fn pack(bytes: &[u8]) -> u64 {
+    ((bytes.as_ptr() as u64) << 32) | bytes.len() as u64
+}
+
+/// Calls a method of the host, waiting for its result.
+fn host(method: &str, params: Value) -> Result<Value, String> {
+    let call = json!({"method": method, "params": params}).to_string();
+    let (ptr, len) = unpack(unsafe { host_call(call.as_ptr(), call.len()) });
+    let answer: Value =
+        serde_json::from_slice(&unsafe { take(ptr, len) }).map_err(|err| err.to_string())?;
+
+    match answer.get("error") {
+        Some(error) => Err(error["message"].as_str().unwrap_or("").to_string()),
+        None => Ok(answer["result"].clone()),
+    }
+}
+
+fn info(text: &str) {
+    unsafe { log(text.as_ptr(), text.len()) }
+}
+
+fn describe() -> Result<Value, String> {
+    Ok(json!({
+        "name": "/wasmexample",
+        "version": env!("CARGO_PKG_VERSION"),
+        "description": "Echoes your message back to you, from a sandbox",
+        "usage": "PLUGIN /wasmexample :message",
+    }))
+}
+
+fn handle(invocation: &Value) -> Result<Value, String> {
+    let [Value::String(message)] = invocation["args"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or(&[])
+    else {
+        return Err("Expected 1 argument".to_string());
+    };
+
+    let key = format!("count:{}", invocation["sender"].as_str().unwrap_or(""));
+    let count = host("store_get", json!({"key": key}))?
+        .as_str()
+        .and_then(|count| count.parse::<u64>().ok())
+        .unwrap_or(0)
+        + 1;
+    host("store_set", json!({"key": key, "value": count.to_string()}))?;
+
+    Ok(json!({
+        "target": invocation["origin"],
+        "message": format!("Echo \"{message}\" ({count} so far)"),
+    }))
+}
+
+/// Answers a call from the host with `{"result": ...}` or `{"error": ...}`.
+///
+/// # Safety
+/// `ptr` and `len` must be those of memory from `iris_alloc`, as the host hands over.
+#[no_mangle]
+pub unsafe extern "C" fn iris_call(ptr: *mut u8, len: usize) -> u64 {
+    let call: Value = serde_json::from_slice(&take(ptr, len)).unwrap_or_default();
+
+    let result = match call["method"].as_str().unwrap_or("") {
+        "describe" => describe(),
+        "init" => {
+            info("Ready to echo");
+            Ok(Value::Null)
+        }
+        "handle" => handle(&call["params"]),
+        "deinit" => Ok(Value::Null),
+        method => Err(format!("Unknown method {method}")),
+    };
+
+    let answer = match result {
+        Ok(result) => json!({ "result": result }),
+        Err(message) => json!({"error": {"code": -32000, "message": message}}),
+    };
+    let answer = answer.to_string().into_bytes().into_boxed_slice();
+    let packed = pack(&answer);
+    *ANSWER.lock().unwrap() = Some(answer);
+
+    packed
+}